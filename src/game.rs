@@ -1,13 +1,29 @@
+use std::time::Duration;
 use std::time::Instant;
 
+// Rate of the fixed timestep loop in `sdl_main`, physics and animations advance once per tick.
+pub const TICKS_PER_SECOND: u64 = 30;
+
 pub struct Game {
     pub ticks: usize,
     pub render_ticks: usize,
     pub start_system_time: Instant,
     pub running: bool,
+    // When the last tick was due, used to interpolate between ticks for display.
     pub time: Instant,
 }
 
+impl Game {
+    pub fn tick_length(&self) -> Duration {
+        Duration::from_millis(1000 / TICKS_PER_SECOND)
+    }
+
+    // How far the display is between the last tick and the next one, in [0, 1].
+    pub fn interpolation(&self) -> f64 {
+        (self.time.elapsed().as_secs_f64() / self.tick_length().as_secs_f64()).min(1.0)
+    }
+}
+
 impl Default for Game {
     fn default() -> Self {
         Self {
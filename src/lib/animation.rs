@@ -0,0 +1,352 @@
+use crate::aseprite::Tags;
+use crate::systems::components::{InputHandler, Velocity};
+use crate::systems::input::InputState;
+use color_eyre::eyre::{bail, eyre};
+use serde::Deserialize;
+use specs::Entity;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/**
+ * A value a component exposes to animation state machines,
+ * ie. `velocity.y` or `input`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Param {
+    Number(f64),
+    Symbol(&'static str),
+}
+
+/**
+ * Implemented by anything that can drive an animation state machine.
+ * Returns None for variables the component does not know about.
+ */
+pub trait AnimationInput {
+    fn param(&self, name: &str) -> Option<Param>;
+}
+
+impl AnimationInput for InputHandler {
+    fn param(&self, name: &str) -> Option<Param> {
+        match name {
+            "input" => self.0.map(|state| {
+                Param::Symbol(match state {
+                    InputState::Idle => "idle",
+                    InputState::Running => "running",
+                })
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl AnimationInput for Velocity {
+    fn param(&self, name: &str) -> Option<Param> {
+        match name {
+            "velocity.x" => Some(Param::Number(self.0 as f64)),
+            "velocity.y" => Some(Param::Number(self.1 as f64)),
+            _ => None,
+        }
+    }
+}
+
+impl<T: AnimationInput + ?Sized> AnimationInput for &T {
+    fn param(&self, name: &str) -> Option<Param> {
+        (**self).param(name)
+    }
+}
+
+impl<T: AnimationInput> AnimationInput for Option<T> {
+    fn param(&self, name: &str) -> Option<Param> {
+        self.as_ref().and_then(|t| t.param(name))
+    }
+}
+
+impl<A: AnimationInput, B: AnimationInput> AnimationInput for (A, B) {
+    fn param(&self, name: &str) -> Option<Param> {
+        self.0.param(name).or_else(|| self.1.param(name))
+    }
+}
+
+/**
+ * Published on the `EventChannel<AnimationEvent>` resource
+ * when an animation enters a frame that declares events.
+ */
+#[derive(Debug, Clone)]
+pub struct AnimationEvent {
+    pub entity: Entity,
+    pub sprite: &'static str,
+    pub tag: Option<String>,
+    pub frame: usize,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    Symbol(String),
+}
+
+/**
+ * `<variable> <op> <value>`, ie. "velocity.y > 0" or "input == running".
+ * Conditions on variables the entity does not provide are false.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Condition {
+    pub var: String,
+    pub op: Op,
+    pub value: Operand,
+}
+
+impl FromStr for Condition {
+    type Err = color_eyre::Report;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        let (var, op, value) = match tokens.as_slice() {
+            [var, op, value] => (var, op, value),
+            _ => bail!(
+                "Condition \"{}\" is not of the form `<variable> <op> <value>`",
+                s
+            ),
+        };
+        let op = match *op {
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            op => bail!("Unknown operator \"{}\" in condition \"{}\"", op, s),
+        };
+        let value = match value.parse() {
+            Ok(n) => Operand::Number(n),
+            Err(_) => Operand::Symbol(value.to_string()),
+        };
+        if matches!(value, Operand::Symbol(_)) && !matches!(op, Op::Eq | Op::Ne) {
+            bail!(
+                "Condition \"{}\" orders a symbol, only == and != are allowed",
+                s
+            );
+        }
+        Ok(Condition {
+            var: var.to_string(),
+            op,
+            value,
+        })
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = color_eyre::Report;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Condition {
+    pub fn eval<T: AnimationInput + ?Sized>(&self, input: &T) -> bool {
+        match (input.param(&self.var), &self.value) {
+            (Some(Param::Number(a)), Operand::Number(b)) => match self.op {
+                Op::Eq => a == *b,
+                Op::Ne => a != *b,
+                Op::Lt => a < *b,
+                Op::Le => a <= *b,
+                Op::Gt => a > *b,
+                Op::Ge => a >= *b,
+            },
+            (Some(Param::Symbol(a)), Operand::Symbol(b)) => match self.op {
+                Op::Eq => a == b,
+                Op::Ne => a != b,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Transition {
+    pub to: String,
+    pub when: Condition,
+    // Let the current tag finish its loop before switching.
+    #[serde(default)]
+    pub wait_for_loop: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StateConfig {
+    pub name: String,
+    pub tag: String,
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+}
+
+/**
+ * Animation states of a sprite, loaded from a `.states.json` file next to it:
+ * ```json
+ * { "initial": "idle",
+ *   "states": [
+ *     { "name": "idle", "tag": "still",
+ *       "transitions": [{ "to": "run", "when": "input == running" }] },
+ *     { "name": "run", "tag": "run",
+ *       "transitions": [{ "to": "idle", "when": "input == idle", "wait_for_loop": true }] }
+ *   ] }
+ * ```
+ * Transitions are checked in order, the first one whose condition holds is taken.
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct StateMachineConfig {
+    pub initial: String,
+    pub states: Vec<StateConfig>,
+}
+
+impl StateMachineConfig {
+    pub fn open(path: &str) -> color_eyre::Result<Self> {
+        let file = File::open(path).map_err(|e| eyre!("Cannot open {}: {}", path, e))?;
+        let config: Self = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| eyre!("Cannot parse state machine {}: {}", path, e))?;
+        config
+            .validate()
+            .map_err(|e| eyre!("Invalid state machine {}: {}", path, e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> color_eyre::Result<()> {
+        self.index(&self.initial)
+            .ok_or_else(|| eyre!("Initial state \"{}\" does not exist", self.initial))?;
+        for state in &self.states {
+            for transition in &state.transitions {
+                self.index(&transition.to).ok_or_else(|| {
+                    eyre!(
+                        "State \"{}\" transitions to unknown state \"{}\"",
+                        state.name,
+                        transition.to
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that every state plays a tag of the sheet it is attached to.
+    pub fn check_tags(&self, tags: &[Tags]) -> color_eyre::Result<()> {
+        for state in &self.states {
+            if !tags.iter().any(|t| t.name == state.tag) {
+                bail!(
+                    "State \"{}\" plays tag \"{}\" which is not in the sheet",
+                    state.name,
+                    state.tag
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        self.states.iter().position(|s| s.name == name)
+    }
+}
+
+/**
+ * Per instance state of a StateMachineConfig.
+ */
+#[derive(Debug)]
+pub struct StateMachine {
+    config: Arc<StateMachineConfig>,
+    pub tags: Vec<Tags>,
+    pub current: usize,
+    // Waiting for the current tag to finish its loop before switching.
+    pub pending: Option<usize>,
+}
+
+impl StateMachine {
+    pub fn new(config: Arc<StateMachineConfig>, tags: Vec<Tags>) -> Self {
+        let current = config.index(&config.initial).unwrap_or(0);
+        Self {
+            config,
+            tags,
+            current,
+            pending: None,
+        }
+    }
+
+    pub fn state(&self) -> &StateConfig {
+        &self.config.states[self.current]
+    }
+
+    // Tag of the current state.
+    pub fn tag(&self) -> Result<&Tags, &'static str> {
+        let name = &self.state().tag;
+        self.tags
+            .iter()
+            .find(|t| &t.name == name)
+            .ok_or("Tag of the current animation state not found")
+    }
+
+    /**
+     * Checks the transitions of the current state against `input`.
+     * Returns true when the state changed right away,
+     * transitions that wait for the loop to end are kept in `pending` instead.
+     */
+    pub fn run<T: AnimationInput + ?Sized>(&mut self, input: &T) -> bool {
+        let taken = self
+            .state()
+            .transitions
+            .iter()
+            .find(|t| t.when.eval(input))
+            .map(|t| (self.config.index(&t.to), t.wait_for_loop));
+        self.pending = None;
+        match taken {
+            Some((Some(target), true)) if target != self.current => {
+                self.pending = Some(target);
+                false
+            }
+            Some((Some(target), false)) if target != self.current => {
+                self.current = target;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Switches to the pending state, if any. Called once the current loop is over.
+    pub fn finish_loop(&mut self) -> bool {
+        match self.pending.take() {
+            Some(target) => {
+                self.current = target;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aseprite::AsepriteJSON;
+
+    #[test]
+    fn states_play_tags_of_their_sheet() {
+        let states = StateMachineConfig::open("sprites/chicken_smear.states.json").unwrap();
+        let json = AsepriteJSON::open("sprites/chicken_smear.json").unwrap();
+        states.check_tags(&json.meta.frame_tags).unwrap();
+
+        let mushroom = AsepriteJSON::open("sprites/mushroom.json").unwrap();
+        let error = states.check_tags(&mushroom.meta.frame_tags).unwrap_err();
+        assert!(error.to_string().contains("not in the sheet"), "{}", error);
+    }
+}
@@ -0,0 +1,756 @@
+//! Loader for the native `.aseprite` / `.ase` binary format.
+//!
+//! Spec: https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md
+//!
+//! The file is parsed into the same `AsepriteJSON` structure the JSON exporter
+//! produces (frames laid out as a horizontal strip), plus one flattened RGBA
+//! image per frame, so the renderer does not care where a sprite came from.
+use super::{
+    AsepriteJSON, CelInfo, Direction, FrameInfo, LayerInfo, MetaInfo, Slice, SliceKey, SlicePoint,
+    SpriteRect, SpriteSheetSize, Tags,
+};
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use flate2::read::ZlibDecoder;
+use image::{Rgba, RgbaImage};
+use std::io::Read;
+use std::path::Path;
+
+const FILE_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_OLD_PALETTE: u16 = 0x0004;
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_PALETTE: u16 = 0x2019;
+const CHUNK_USER_DATA: u16 = 0x2020;
+const CHUNK_SLICE: u16 = 0x2022;
+
+const HEADER_FLAG_LAYER_OPACITY: u32 = 1;
+const LAYER_FLAG_VISIBLE: u16 = 1;
+const LAYER_FLAG_BACKGROUND: u16 = 8;
+const BLEND_NORMAL: u16 = 0;
+const SLICE_FLAG_NINE_PATCH: u32 = 1;
+const SLICE_FLAG_PIVOT: u32 = 2;
+const USER_DATA_FLAG_TEXT: u32 = 1;
+
+// The chunk a user data chunk describes, the one read right before it.
+#[derive(Debug, Clone, Copy)]
+enum UserDataTarget {
+    None,
+    Cel(u16),
+    Slice,
+    // Tag chunks are followed by one user data chunk per tag, these are skipped.
+    Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    Rgba,
+    Grayscale,
+    Indexed,
+}
+
+impl ColorDepth {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorDepth::Rgba => 4,
+            ColorDepth::Grayscale => 2,
+            ColorDepth::Indexed => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    Image,
+    Group,
+    Tilemap,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    pub flags: u16,
+    pub kind: LayerKind,
+    pub child_level: u16,
+    pub opacity: u8,
+}
+
+impl Layer {
+    pub fn is_visible(&self) -> bool {
+        self.flags & LAYER_FLAG_VISIBLE != 0
+    }
+    pub fn is_background(&self) -> bool {
+        self.flags & LAYER_FLAG_BACKGROUND != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CelContent {
+    Image { w: u16, h: u16, pixels: Vec<u8> },
+    Linked(u16),
+    // Tilemap cels are not supported, they are skipped during composition.
+    Unsupported,
+}
+
+#[derive(Debug, Clone)]
+struct Cel {
+    layer: u16,
+    x: i16,
+    y: i16,
+    opacity: u8,
+    content: CelContent,
+}
+
+#[derive(Debug)]
+pub struct AsepriteFile {
+    pub json: AsepriteJSON,
+    /// One flattened RGBA image per frame.
+    pub images: Vec<RgbaImage>,
+}
+
+/**
+ * Little-endian cursor over the raw file bytes.
+ */
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos + n;
+        if end > self.data.len() {
+            bail!("Unexpected end of file at offset {}", self.pos);
+        }
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+    fn skip(&mut self, n: usize) -> Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn word(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    fn short(&mut self) -> Result<i16> {
+        Ok(self.word()? as i16)
+    }
+    fn dword(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn long(&mut self) -> Result<i32> {
+        Ok(self.dword()? as i32)
+    }
+    fn string(&mut self) -> Result<String> {
+        let len = self.word()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+impl AsepriteFile {
+    pub fn open(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| eyre!("Cannot read {}: {}", path, e))?;
+        let name = Path::new(path)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(path);
+        Self::parse(name, &bytes).map_err(|e| eyre!("Cannot parse {}: {}", path, e))
+    }
+
+    pub fn parse(name: &str, bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader::new(bytes);
+
+        // Header (128 bytes)
+        let _file_size = r.dword()?;
+        let magic = r.word()?;
+        if magic != FILE_MAGIC {
+            bail!("Bad magic number {:#06x}, not an aseprite file", magic);
+        }
+        let frame_count = r.word()?;
+        let width = r.word()?;
+        let height = r.word()?;
+        let depth = match r.word()? {
+            32 => ColorDepth::Rgba,
+            16 => ColorDepth::Grayscale,
+            8 => ColorDepth::Indexed,
+            d => bail!("Unsupported color depth {}", d),
+        };
+        let flags = r.dword()?;
+        let _speed = r.word()?;
+        r.skip(8)?;
+        let transparent_index = r.byte()?;
+        r.skip(3)?;
+        let _color_count = r.word()?;
+        r.skip(2 + 8 + 84)?;
+
+        let mut layers = Vec::new();
+        let mut palette: Vec<Rgba<u8>> = Vec::new();
+        let mut old_palette: Vec<Rgba<u8>> = Vec::new();
+        let mut frame_tags = Vec::new();
+        let mut slices: Vec<Slice> = Vec::new();
+        let mut cel_data: Vec<(u16, usize, String)> = Vec::new();
+        let mut durations = Vec::with_capacity(frame_count as usize);
+        let mut cels: Vec<Vec<Cel>> = Vec::with_capacity(frame_count as usize);
+
+        for frame in 0..frame_count as usize {
+            let frame_start = r.pos;
+            let frame_len = r.dword()? as usize;
+            let magic = r.word()?;
+            if magic != FRAME_MAGIC {
+                bail!("Bad frame magic number {:#06x}", magic);
+            }
+            let old_chunks = r.word()?;
+            durations.push(r.word()? as u128);
+            r.skip(2)?;
+            let new_chunks = r.dword()?;
+            let chunk_count = if new_chunks == 0 {
+                old_chunks as u32
+            } else {
+                new_chunks
+            };
+
+            let mut frame_cels = Vec::new();
+            let mut target = UserDataTarget::None;
+            for _ in 0..chunk_count {
+                let chunk_start = r.pos;
+                let chunk_len = r.dword()? as usize;
+                let chunk_type = r.word()?;
+                let chunk_end = chunk_start + chunk_len;
+                if chunk_len < 6 || chunk_end > bytes.len() {
+                    bail!("Chunk {:#06x} has invalid size {}", chunk_type, chunk_len);
+                }
+                let mut c = Reader::new(&bytes[r.pos..chunk_end]);
+                match chunk_type {
+                    CHUNK_LAYER => {
+                        layers.push(read_layer(&mut c)?);
+                        target = UserDataTarget::None;
+                    }
+                    CHUNK_CEL => {
+                        let cel = read_cel(&mut c, depth)?;
+                        target = UserDataTarget::Cel(cel.layer);
+                        frame_cels.push(cel);
+                    }
+                    CHUNK_TAGS => {
+                        frame_tags = read_tags(&mut c)?;
+                        target = UserDataTarget::Tags;
+                    }
+                    CHUNK_PALETTE => read_palette(&mut c, &mut palette)?,
+                    CHUNK_OLD_PALETTE => old_palette = read_old_palette(&mut c)?,
+                    CHUNK_SLICE => {
+                        slices.push(read_slice(&mut c)?);
+                        target = UserDataTarget::Slice;
+                    }
+                    CHUNK_USER_DATA => {
+                        if let Some(text) = read_user_data(&mut c)? {
+                            match target {
+                                UserDataTarget::Cel(layer) => cel_data.push((layer, frame, text)),
+                                UserDataTarget::Slice => {
+                                    if let Some(slice) = slices.last_mut() {
+                                        slice.data = Some(text);
+                                    }
+                                }
+                                UserDataTarget::Tags | UserDataTarget::None => {}
+                            }
+                        }
+                    }
+                    // Color profiles, cel extras, tilesets, etc.
+                    _ => {}
+                }
+                r.pos = chunk_end;
+            }
+            cels.push(frame_cels);
+            r.pos = frame_start + frame_len;
+        }
+
+        if palette.is_empty() {
+            palette = old_palette;
+        }
+
+        let file = Decoded {
+            width,
+            height,
+            depth,
+            layer_opacity: flags & HEADER_FLAG_LAYER_OPACITY != 0,
+            transparent_index,
+            layers: &layers,
+            palette: &palette,
+            cels: &cels,
+        };
+        let images = (0..cels.len()).map(|frame| file.compose(frame)).collect();
+
+        let frames = durations
+            .iter()
+            .enumerate()
+            .map(|(i, &duration)| FrameInfo {
+                filename: format!("{} {}.aseprite", name, i),
+                frame: SpriteRect {
+                    x: i as i32 * width as i32,
+                    y: 0,
+                    w: width as u32,
+                    h: height as u32,
+                },
+                rotated: false,
+                trimmed: false,
+                sprite_source_size: SpriteRect {
+                    x: 0,
+                    y: 0,
+                    w: width as u32,
+                    h: height as u32,
+                },
+                duration,
+                source_size: SpriteSheetSize {
+                    w: width as usize,
+                    h: height as usize,
+                },
+            })
+            .collect();
+        let mut layer_info: Vec<LayerInfo> = layers
+            .iter()
+            .map(|layer| LayerInfo {
+                name: layer.name.clone(),
+                cels: Vec::new(),
+            })
+            .collect();
+        for (layer, frame, data) in cel_data {
+            if let Some(info) = layer_info.get_mut(layer as usize) {
+                info.cels.push(CelInfo {
+                    frame,
+                    data: Some(data),
+                });
+            }
+        }
+        let json = AsepriteJSON {
+            frames,
+            meta: MetaInfo {
+                size: SpriteSheetSize {
+                    w: width as usize * frame_count as usize,
+                    h: height as usize,
+                },
+                frame_tags,
+                layers: layer_info,
+                slices,
+            },
+        };
+
+        Ok(AsepriteFile { json, images })
+    }
+}
+
+fn read_layer(c: &mut Reader) -> Result<Layer> {
+    let flags = c.word()?;
+    let kind = match c.word()? {
+        0 => LayerKind::Image,
+        1 => LayerKind::Group,
+        2 => LayerKind::Tilemap,
+        k => bail!("Unknown layer type {}", k),
+    };
+    let child_level = c.word()?;
+    let _default_w = c.word()?;
+    let _default_h = c.word()?;
+    let blend_mode = c.word()?;
+    let opacity = c.byte()?;
+    c.skip(3)?;
+    let name = c.string()?;
+    // Frames are composed with blend_normal, other modes would not look as authored.
+    if blend_mode != BLEND_NORMAL {
+        bail!(
+            "Layer \"{}\" uses blend mode {}, only the normal blend mode is supported",
+            name,
+            blend_mode
+        );
+    }
+    Ok(Layer {
+        name,
+        flags,
+        kind,
+        child_level,
+        opacity,
+    })
+}
+
+fn read_cel(c: &mut Reader, depth: ColorDepth) -> Result<Cel> {
+    let layer = c.word()?;
+    let x = c.short()?;
+    let y = c.short()?;
+    let opacity = c.byte()?;
+    let cel_type = c.word()?;
+    c.skip(7)?;
+    let content = match cel_type {
+        0 => {
+            let w = c.word()?;
+            let h = c.word()?;
+            let len = w as usize * h as usize * depth.bytes_per_pixel();
+            let pixels = c.bytes(len)?.to_vec();
+            CelContent::Image { w, h, pixels }
+        }
+        1 => CelContent::Linked(c.word()?),
+        2 => {
+            let w = c.word()?;
+            let h = c.word()?;
+            let len = w as usize * h as usize * depth.bytes_per_pixel();
+            let mut pixels = Vec::with_capacity(len);
+            ZlibDecoder::new(&c.data[c.pos..]).read_to_end(&mut pixels)?;
+            if pixels.len() < len {
+                bail!("Compressed cel on layer {} is truncated", layer);
+            }
+            pixels.truncate(len);
+            CelContent::Image { w, h, pixels }
+        }
+        _ => CelContent::Unsupported,
+    };
+    Ok(Cel {
+        layer,
+        x,
+        y,
+        opacity,
+        content,
+    })
+}
+
+fn read_tags(c: &mut Reader) -> Result<Vec<Tags>> {
+    let count = c.word()?;
+    c.skip(8)?;
+    let mut tags = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let from = c.word()? as usize;
+        let to = c.word()? as usize;
+        let direction = match c.byte()? {
+            1 => Direction::Reverse,
+            2 => Direction::PingPong,
+            3 => Direction::PingPongReverse,
+            _ => Direction::Forward,
+        };
+        // repeat, reserved, deprecated tag color + extra byte
+        c.skip(2 + 6 + 3 + 1)?;
+        let name = c.string()?;
+        tags.push(Tags {
+            name,
+            from,
+            to,
+            direction,
+        });
+    }
+    Ok(tags)
+}
+
+fn read_slice(c: &mut Reader) -> Result<Slice> {
+    let key_count = c.dword()?;
+    let flags = c.dword()?;
+    c.skip(4)?;
+    let name = c.string()?;
+    let mut keys = Vec::with_capacity(key_count as usize);
+    for _ in 0..key_count {
+        let frame = c.dword()? as usize;
+        let bounds = SpriteRect {
+            x: c.long()?,
+            y: c.long()?,
+            w: c.dword()?,
+            h: c.dword()?,
+        };
+        let center = if flags & SLICE_FLAG_NINE_PATCH != 0 {
+            Some(SpriteRect {
+                x: c.long()?,
+                y: c.long()?,
+                w: c.dword()?,
+                h: c.dword()?,
+            })
+        } else {
+            None
+        };
+        let pivot = if flags & SLICE_FLAG_PIVOT != 0 {
+            Some(SlicePoint {
+                x: c.long()?,
+                y: c.long()?,
+            })
+        } else {
+            None
+        };
+        keys.push(SliceKey {
+            frame,
+            bounds,
+            center,
+            pivot,
+        });
+    }
+    Ok(Slice {
+        name,
+        data: None,
+        keys,
+    })
+}
+
+fn read_user_data(c: &mut Reader) -> Result<Option<String>> {
+    let flags = c.dword()?;
+    if flags & USER_DATA_FLAG_TEXT != 0 {
+        Ok(Some(c.string()?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn read_palette(c: &mut Reader, palette: &mut Vec<Rgba<u8>>) -> Result<()> {
+    let size = c.dword()? as usize;
+    let first = c.dword()? as usize;
+    let last = c.dword()? as usize;
+    c.skip(8)?;
+    if palette.len() < size {
+        palette.resize(size, Rgba([0, 0, 0, 0]));
+    }
+    for i in first..=last {
+        let flags = c.word()?;
+        let rgba = c.bytes(4)?;
+        if flags & 1 != 0 {
+            let _name = c.string()?;
+        }
+        if i >= palette.len() {
+            palette.resize(i + 1, Rgba([0, 0, 0, 0]));
+        }
+        palette[i] = Rgba([rgba[0], rgba[1], rgba[2], rgba[3]]);
+    }
+    Ok(())
+}
+
+fn read_old_palette(c: &mut Reader) -> Result<Vec<Rgba<u8>>> {
+    let mut palette = Vec::new();
+    let packets = c.word()?;
+    for _ in 0..packets {
+        let skip = c.byte()? as usize;
+        palette.resize(palette.len() + skip, Rgba([0, 0, 0, 255]));
+        let count = match c.byte()? {
+            0 => 256,
+            n => n as usize,
+        };
+        for _ in 0..count {
+            let rgb = c.bytes(3)?;
+            palette.push(Rgba([rgb[0], rgb[1], rgb[2], 255]));
+        }
+    }
+    Ok(palette)
+}
+
+/**
+ * Everything needed to flatten the layers of a frame into a single image.
+ */
+struct Decoded<'a> {
+    width: u16,
+    height: u16,
+    depth: ColorDepth,
+    layer_opacity: bool,
+    transparent_index: u8,
+    layers: &'a [Layer],
+    palette: &'a [Rgba<u8>],
+    cels: &'a [Vec<Cel>],
+}
+
+impl<'a> Decoded<'a> {
+    /// A layer is only drawn if it and every group above it is visible.
+    fn visible_layers(&self) -> Vec<bool> {
+        let mut visible = Vec::with_capacity(self.layers.len());
+        let mut parents: Vec<bool> = Vec::new();
+        for layer in self.layers {
+            parents.truncate(layer.child_level as usize);
+            let parent_visible = parents.last().copied().unwrap_or(true);
+            let is_visible = parent_visible && layer.is_visible();
+            visible.push(is_visible && layer.kind == LayerKind::Image);
+            if layer.kind == LayerKind::Group {
+                parents.push(is_visible);
+            }
+        }
+        visible
+    }
+
+    // Links only go back to earlier frames, anything else is a malformed file.
+    fn find_cel(&self, frame: usize, layer: u16) -> Option<&'a Cel> {
+        let cel = self.cels.get(frame)?.iter().find(|c| c.layer == layer)?;
+        match cel.content {
+            CelContent::Linked(target) if (target as usize) < frame => {
+                self.find_cel(target as usize, layer)
+            }
+            CelContent::Linked(_) => None,
+            _ => Some(cel),
+        }
+    }
+
+    fn pixel(&self, layer: &Layer, raw: &[u8]) -> Rgba<u8> {
+        match self.depth {
+            ColorDepth::Rgba => Rgba([raw[0], raw[1], raw[2], raw[3]]),
+            ColorDepth::Grayscale => Rgba([raw[0], raw[0], raw[0], raw[1]]),
+            ColorDepth::Indexed => {
+                let index = raw[0];
+                if index == self.transparent_index && !layer.is_background() {
+                    Rgba([0, 0, 0, 0])
+                } else {
+                    self.palette
+                        .get(index as usize)
+                        .copied()
+                        .unwrap_or(Rgba([0, 0, 0, 0]))
+                }
+            }
+        }
+    }
+
+    fn compose(&self, frame: usize) -> RgbaImage {
+        let mut canvas = RgbaImage::new(self.width as u32, self.height as u32);
+        let visible = self.visible_layers();
+        let bpp = self.depth.bytes_per_pixel();
+        for (index, layer) in self.layers.iter().enumerate() {
+            if !visible[index] {
+                continue;
+            }
+            let cel = match self.find_cel(frame, index as u16) {
+                Some(cel) => cel,
+                None => continue,
+            };
+            let (w, h, pixels) = match &cel.content {
+                CelContent::Image { w, h, pixels } => (*w, *h, pixels),
+                // Tilemaps have nothing to draw.
+                _ => continue,
+            };
+            let mut opacity = cel.opacity as f32 / 255.0;
+            if self.layer_opacity {
+                opacity *= layer.opacity as f32 / 255.0;
+            }
+            for py in 0..h as i32 {
+                let cy = cel.y as i32 + py;
+                if cy < 0 || cy >= self.height as i32 {
+                    continue;
+                }
+                for px in 0..w as i32 {
+                    let cx = cel.x as i32 + px;
+                    if cx < 0 || cx >= self.width as i32 {
+                        continue;
+                    }
+                    let offset = (py as usize * w as usize + px as usize) * bpp;
+                    let src = self.pixel(layer, &pixels[offset..offset + bpp]);
+                    let dst = canvas.get_pixel_mut(cx as u32, cy as u32);
+                    *dst = blend_normal(*dst, src, opacity);
+                }
+            }
+        }
+        canvas
+    }
+}
+
+/// Source-over compositing, the "Normal" blend mode.
+fn blend_normal(dst: Rgba<u8>, src: Rgba<u8>, opacity: f32) -> Rgba<u8> {
+    let sa = src[3] as f32 / 255.0 * opacity;
+    if sa <= 0.0 {
+        return dst;
+    }
+    let da = dst[3] as f32 / 255.0;
+    let out_a = sa + da * (1.0 - sa);
+    let channel = |i: usize| {
+        let c = (src[i] as f32 * sa + dst[i] as f32 * da * (1.0 - sa)) / out_a;
+        c.round().min(255.0) as u8
+    };
+    Rgba([
+        channel(0),
+        channel(1),
+        channel(2),
+        (out_a * 255.0).round() as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The committed sheets were exported from the .aseprite files next to them.
+    fn assert_matches_export(aseprite: &str, json: &str) {
+        let file = AsepriteFile::open(aseprite).unwrap();
+        let export = AsepriteJSON::open(json).unwrap();
+        assert_eq!(file.images.len(), export.frames.len());
+        let durations =
+            |json: &AsepriteJSON| -> Vec<u128> { json.frames.iter().map(|f| f.duration).collect() };
+        assert_eq!(durations(&file.json), durations(&export));
+        let tags = |json: &AsepriteJSON| -> Vec<(String, usize, usize)> {
+            let tags = json.meta.frame_tags.iter();
+            tags.map(|t| (t.name.clone(), t.from, t.to)).collect()
+        };
+        assert_eq!(tags(&file.json), tags(&export));
+        for image in &file.images {
+            assert_eq!(image.dimensions(), export.canvas_size());
+        }
+    }
+
+    #[test]
+    fn chicken_smear_matches_its_export() {
+        assert_matches_export(
+            "sprites/chicken_smear.aseprite",
+            "sprites/chicken_smear.json",
+        );
+    }
+
+    #[test]
+    fn tile_matches_its_export() {
+        assert_matches_export("sprites/tile.aseprite", "sprites/tile.json");
+    }
+
+    #[test]
+    fn links_only_follow_earlier_frames() {
+        let cel = |content| Cel {
+            layer: 0,
+            x: 0,
+            y: 0,
+            opacity: 255,
+            content,
+        };
+        let image = CelContent::Image {
+            w: 1,
+            h: 1,
+            pixels: vec![0; 4],
+        };
+        // Frames 1 and 2 link to each other, frame 3 to the image of frame 0.
+        let cels = vec![
+            vec![cel(image)],
+            vec![cel(CelContent::Linked(2))],
+            vec![cel(CelContent::Linked(1))],
+            vec![cel(CelContent::Linked(0))],
+        ];
+        let decoded = Decoded {
+            width: 1,
+            height: 1,
+            depth: ColorDepth::Rgba,
+            layer_opacity: true,
+            transparent_index: 0,
+            layers: &[],
+            palette: &[],
+            cels: &cels,
+        };
+        assert!(decoded.find_cel(1, 0).is_none());
+        assert!(decoded.find_cel(2, 0).is_none());
+        let linked = decoded.find_cel(3, 0).unwrap();
+        assert!(matches!(linked.content, CelContent::Image { .. }));
+    }
+
+    #[test]
+    fn only_reads_normal_layers() {
+        let chunk = |blend_mode: u16| {
+            // Flags, type, child level, default size, blend mode.
+            let mut bytes = vec![];
+            for word in &[1, 0, 0, 0, 0, blend_mode] {
+                bytes.extend_from_slice(&u16::to_le_bytes(*word));
+            }
+            bytes.extend_from_slice(&[255, 0, 0, 0]);
+            bytes.extend_from_slice(&u16::to_le_bytes(4));
+            bytes.extend_from_slice(b"body");
+            bytes
+        };
+        let layer = read_layer(&mut Reader::new(&chunk(BLEND_NORMAL))).unwrap();
+        assert_eq!((layer.name.as_str(), layer.opacity), ("body", 255));
+        // Multiply.
+        let error = read_layer(&mut Reader::new(&chunk(1))).unwrap_err();
+        assert!(error.to_string().contains("\"body\""), "{}", error);
+    }
+}
@@ -0,0 +1,562 @@
+pub mod binary;
+
+use color_eyre::eyre::eyre;
+use image::{imageops, GenericImageView, RgbaImage};
+use sdl2::rect::Rect;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufReader;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct SpriteRect {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FrameInfo {
+    // Absent in the "Hash" export layout, where it is the key of the frame instead.
+    #[serde(default)]
+    pub filename: String,
+    pub frame: SpriteRect,
+    #[serde(default)]
+    pub rotated: bool,
+    #[serde(default)]
+    pub trimmed: bool,
+    // Where the trimmed frame sits inside of the untrimmed `source_size` canvas.
+    #[serde(rename = "spriteSourceSize")]
+    pub sprite_source_size: SpriteRect,
+    pub duration: u128,
+    #[serde(rename = "sourceSize")]
+    pub source_size: SpriteSheetSize,
+}
+
+impl FrameInfo {
+    /**
+     * Cuts this frame out of the sheet and places it at its source offset
+     * on a transparent canvas, undoing any trimming or rotation done by the exporter.
+     * Rotated frames are stored 90 degrees clockwise in the sheet.
+     */
+    pub fn extract(&self, sheet: &RgbaImage, (width, height): (u32, u32)) -> RgbaImage {
+        let SpriteRect { x, y, w, h } = self.frame;
+        let sub = if self.rotated {
+            imageops::rotate270(&sheet.view(x as u32, y as u32, h, w))
+        } else {
+            sheet.view(x as u32, y as u32, w, h).to_image()
+        };
+        let mut canvas = RgbaImage::new(width, height);
+        let offset = self.sprite_source_size;
+        imageops::replace(&mut canvas, &sub, offset.x as u32, offset.y as u32);
+        canvas
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    #[default]
+    #[serde(rename = "forward")]
+    Forward,
+    #[serde(rename = "reverse")]
+    Reverse,
+    #[serde(rename = "pingpong")]
+    PingPong,
+    #[serde(rename = "pingpong_reverse")]
+    PingPongReverse,
+}
+
+impl Direction {
+    // Reverse and ping-pong reverse tags start playing from their last frame.
+    pub fn starts_reversed(self) -> bool {
+        matches!(self, Direction::Reverse | Direction::PingPongReverse)
+    }
+}
+
+/**
+ * An animation, `from` and `to` are both inclusive frame indexes.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tags {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+impl Tags {
+    pub fn first_frame(&self) -> usize {
+        if self.direction.starts_reversed() {
+            self.to
+        } else {
+            self.from
+        }
+    }
+
+    // Whether `frame` is the last frame of a full loop of this tag.
+    pub fn loop_ends(&self, frame: usize, reversed: bool) -> bool {
+        if self.from >= self.to {
+            return true;
+        }
+        match self.direction {
+            Direction::Forward => frame >= self.to,
+            Direction::Reverse => frame <= self.from,
+            Direction::PingPong => reversed && frame <= self.from,
+            Direction::PingPongReverse => !reversed && frame >= self.to,
+        }
+    }
+
+    /**
+     * The frame shown after `frame`.
+     * `reversed` holds which way a ping-pong animation is currently travelling.
+     */
+    pub fn next_frame(&self, frame: usize, reversed: &mut bool) -> usize {
+        let (from, to) = (self.from, self.to);
+        if from >= to {
+            return from;
+        }
+        if frame < from || frame > to {
+            *reversed = self.direction.starts_reversed();
+            return self.first_frame();
+        }
+        match self.direction {
+            Direction::Forward => {
+                if frame >= to {
+                    from
+                } else {
+                    frame + 1
+                }
+            }
+            Direction::Reverse => {
+                if frame <= from {
+                    to
+                } else {
+                    frame - 1
+                }
+            }
+            Direction::PingPong | Direction::PingPongReverse => {
+                if *reversed && frame <= from {
+                    *reversed = false;
+                } else if !*reversed && frame >= to {
+                    *reversed = true;
+                }
+                if *reversed {
+                    frame - 1
+                } else {
+                    frame + 1
+                }
+            }
+        }
+    }
+}
+impl Display for Tags {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "[{}] ({} -> {})", self.name, self.from, self.to)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpriteSheetSize {
+    pub w: usize,
+    pub h: usize,
+}
+
+impl Display for SpriteSheetSize {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}x{}", self.w, self.h)
+    }
+}
+
+impl Display for SpriteRect {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}x{}", self.w, self.h)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetaInfo {
+    pub size: SpriteSheetSize,
+    #[serde(rename = "frameTags")]
+    pub frame_tags: Vec<Tags>,
+    #[serde(default)]
+    pub layers: Vec<LayerInfo>,
+    #[serde(default)]
+    pub slices: Vec<Slice>,
+}
+
+// Cels are only exported when they carry user data.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CelInfo {
+    pub frame: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LayerInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cels: Vec<CelInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct SlicePoint {
+    pub x: i32,
+    pub y: i32,
+}
+
+/**
+ * The shape of a slice starting at `frame`, until the next key.
+ * `center` (9-slice) and `pivot` are relative to `bounds`.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SliceKey {
+    pub frame: usize,
+    pub bounds: SpriteRect,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub center: Option<SpriteRect>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pivot: Option<SlicePoint>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Slice {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    pub keys: Vec<SliceKey>,
+}
+
+impl Slice {
+    // Keys are sorted by frame, a slice does not exist before its first key.
+    pub fn key(&self, frame: usize) -> Option<&SliceKey> {
+        self.keys.iter().rev().find(|key| key.frame <= frame)
+    }
+}
+
+impl From<SpriteRect> for Rect {
+    fn from(SpriteRect { x, y, w, h }: SpriteRect) -> Self {
+        Self::new(x, y, w, h)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AsepriteJSON {
+    #[serde(deserialize_with = "deserialize_frames")]
+    pub frames: Vec<FrameInfo>,
+    pub meta: MetaInfo,
+}
+
+impl AsepriteJSON {
+    // Size every frame is drawn at, the largest untrimmed frame.
+    pub fn canvas_size(&self) -> (u32, u32) {
+        self.frames.iter().fold((0, 0), |(w, h), f| {
+            (w.max(f.source_size.w as u32), h.max(f.source_size.h as u32))
+        })
+    }
+
+    /**
+     * Events declared on `frame` through the user data of its cels,
+     * a comma separated list of names such as "footstep, dust".
+     */
+    pub fn frame_events(&self, frame: usize) -> impl Iterator<Item = &str> {
+        self.meta
+            .layers
+            .iter()
+            .flat_map(|layer| layer.cels.iter())
+            .filter(move |cel| cel.frame == frame)
+            .filter_map(|cel| cel.data.as_deref())
+            .flat_map(|data| data.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+    }
+
+    /**
+     * Frames of a sheet cut into a grid of `tile` sized cells, `columns` per row,
+     * such as a tileset or a font sheet. `margin` is the border around the sheet
+     * and `spacing` the gap between cells, in pixels.
+     */
+    pub fn grid(
+        name: &str,
+        (tw, th): (u32, u32),
+        columns: u32,
+        count: u32,
+        margin: u32,
+        spacing: u32,
+    ) -> Self {
+        let columns = columns.max(1);
+        let rows = count.div_ceil(columns);
+        let frames = (0..count)
+            .map(|i| FrameInfo {
+                filename: format!("{} {}", name, i),
+                frame: SpriteRect {
+                    x: (margin + (i % columns) * (tw + spacing)) as i32,
+                    y: (margin + (i / columns) * (th + spacing)) as i32,
+                    w: tw,
+                    h: th,
+                },
+                rotated: false,
+                trimmed: false,
+                sprite_source_size: SpriteRect {
+                    x: 0,
+                    y: 0,
+                    w: tw,
+                    h: th,
+                },
+                duration: 100,
+                source_size: SpriteSheetSize {
+                    w: tw as usize,
+                    h: th as usize,
+                },
+            })
+            .collect();
+        let span = |cells: u32, size: u32| {
+            (2 * margin + cells * size + cells.saturating_sub(1) * spacing) as usize
+        };
+        AsepriteJSON {
+            frames,
+            meta: MetaInfo {
+                size: SpriteSheetSize {
+                    w: span(columns.min(count), tw),
+                    h: span(rows, th),
+                },
+                frame_tags: Vec::new(),
+                layers: Vec::new(),
+                slices: Vec::new(),
+            },
+        }
+    }
+
+    pub fn open(path: &str) -> color_eyre::Result<Self> {
+        let file = File::open(path).map_err(|e| eyre!("Cannot open {}: {}", path, e))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| eyre!("Cannot parse aseprite json {}: {}", path, e))
+    }
+}
+
+// The last run of digits in a frame filename, ie. "chicken 12.aseprite" -> 12
+fn frame_number(filename: &str) -> Option<usize> {
+    let end = filename.rfind(|c: char| c.is_ascii_digit())? + 1;
+    let start = filename[..end]
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |i| i + 1);
+    filename[start..end].parse().ok()
+}
+
+/**
+ * Aseprite exports frames either as an array ("Array" layout)
+ * or as an object keyed by filename ("Hash" layout).
+ * Hash frames are put back in order using the number in their filename.
+ */
+fn deserialize_frames<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<FrameInfo>, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Array(frames) => frames
+            .into_iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                serde_json::from_value(frame)
+                    .map_err(|e| D::Error::custom(format!("frames[{}]: {}", i, e)))
+            })
+            .collect(),
+        Value::Object(frames) => {
+            let mut frames = frames
+                .into_iter()
+                .map(|(key, frame)| {
+                    let mut frame: FrameInfo = serde_json::from_value(frame)
+                        .map_err(|e| D::Error::custom(format!("frames[\"{}\"]: {}", key, e)))?;
+                    frame.filename = key;
+                    Ok(frame)
+                })
+                .collect::<Result<Vec<_>, D::Error>>()?;
+            // serde_json hands the keys over sorted, so frames without a number
+            // end up last in alphabetical order.
+            frames.sort_by_key(|f| frame_number(&f.filename).unwrap_or(usize::MAX));
+            Ok(frames)
+        }
+        other => {
+            let found = match other {
+                Value::Null => "null",
+                Value::Bool(_) => "a boolean",
+                Value::Number(_) => "a number",
+                _ => "a string",
+            };
+            Err(D::Error::custom(format!(
+                "\"frames\" must be an array or an object keyed by filename, found {}",
+                found
+            )))
+        }
+    }
+}
+
+use std::fmt;
+
+impl Debug for AsepriteJSON {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut table = table!(["filename", "frame", "duration", "sourceSize"]);
+        for f in &self.frames {
+            table.add_row(row![f.filename, f.frame, f.duration, f.source_size]);
+        }
+        let mut meta = table!();
+        meta.add_row(row!["meta"]);
+        meta.add_row(row!["size", self.meta.size]);
+        for t in &self.meta.frame_tags {
+            meta.add_row(row![t.name, t]);
+        }
+        table.add_row(row![meta]);
+
+        // meta.add_row(row!["tags", self.meta.frame_tags]);
+        write!(fmt, "\n{}", table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(x: i32) -> String {
+        format!(
+            r#"{{ "frame": {{ "x": {}, "y": 0, "w": 1, "h": 1 }},
+                 "spriteSourceSize": {{ "x": 0, "y": 0, "w": 1, "h": 1 }},
+                 "sourceSize": {{ "w": 1, "h": 1 }}, "duration": 100 }}"#,
+            x
+        )
+    }
+
+    fn sheet(frames: &str) -> AsepriteJSON {
+        let json = format!(
+            r#"{{ "frames": {}, "meta": {{ "size": {{ "w": 4, "h": 1 }}, "frameTags": [] }} }}"#,
+            frames
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn hash_frames_are_ordered_by_their_number() {
+        let json = sheet(&format!(
+            r#"{{ "b.aseprite": {}, "chicken 10.aseprite": {}, "chicken 2.aseprite": {},
+                 "a.aseprite": {} }}"#,
+            frame(3),
+            frame(1),
+            frame(0),
+            frame(2)
+        ));
+        let names: Vec<&str> = json.frames.iter().map(|f| f.filename.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "chicken 2.aseprite",
+                "chicken 10.aseprite",
+                "a.aseprite",
+                "b.aseprite"
+            ]
+        );
+        let x: Vec<i32> = json.frames.iter().map(|f| f.frame.x).collect();
+        assert_eq!(x, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn array_frames_keep_their_order() {
+        let json = sheet(&format!("[{}, {}]", frame(1), frame(0)));
+        let x: Vec<i32> = json.frames.iter().map(|f| f.frame.x).collect();
+        assert_eq!(x, [1, 0]);
+    }
+
+    #[test]
+    fn other_frames_are_rejected() {
+        let json = r#"{ "frames": 3, "meta": { "size": { "w": 1, "h": 1 }, "frameTags": [] } }"#;
+        let error = serde_json::from_str::<AsepriteJSON>(json).err().unwrap();
+        assert!(error.to_string().contains("found a number"));
+    }
+
+    // The frames a tag shows over `count` steps, true on those ending a loop.
+    fn play(tag: &Tags, count: usize) -> Vec<(usize, bool)> {
+        let mut reversed = tag.direction.starts_reversed();
+        let mut frame = tag.first_frame();
+        let mut frames = vec![];
+        for _ in 0..count {
+            frames.push((frame, tag.loop_ends(frame, reversed)));
+            frame = tag.next_frame(frame, &mut reversed);
+        }
+        frames
+    }
+
+    fn tag(from: usize, to: usize, direction: Direction) -> Tags {
+        Tags {
+            name: "walk".to_string(),
+            from,
+            to,
+            direction,
+        }
+    }
+
+    #[test]
+    fn tags_play_in_their_direction() {
+        let (o, x) = (false, true);
+        assert_eq!(
+            play(&tag(2, 4, Direction::Forward), 6),
+            [(2, o), (3, o), (4, x), (2, o), (3, o), (4, x)]
+        );
+        assert_eq!(
+            play(&tag(2, 4, Direction::Reverse), 6),
+            [(4, o), (3, o), (2, x), (4, o), (3, o), (2, x)]
+        );
+        // Ping-pong does not show the frame it turns around on twice.
+        assert_eq!(
+            play(&tag(2, 4, Direction::PingPong), 9),
+            [
+                (2, o),
+                (3, o),
+                (4, o),
+                (3, o),
+                (2, x),
+                (3, o),
+                (4, o),
+                (3, o),
+                (2, x)
+            ]
+        );
+        assert_eq!(
+            play(&tag(2, 4, Direction::PingPongReverse), 9),
+            [
+                (4, o),
+                (3, o),
+                (2, o),
+                (3, o),
+                (4, x),
+                (3, o),
+                (2, o),
+                (3, o),
+                (4, x)
+            ]
+        );
+    }
+
+    #[test]
+    fn single_frame_tags_end_every_loop() {
+        for direction in [
+            Direction::Forward,
+            Direction::Reverse,
+            Direction::PingPong,
+            Direction::PingPongReverse,
+        ] {
+            assert_eq!(play(&tag(1, 1, direction), 3), [(1, true); 3]);
+        }
+    }
+
+    #[test]
+    fn frames_outside_the_tag_restart_it() {
+        let mut reversed = false;
+        assert_eq!(
+            tag(2, 4, Direction::Reverse).next_frame(0, &mut reversed),
+            4
+        );
+        assert!(reversed);
+    }
+}
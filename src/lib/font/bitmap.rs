@@ -0,0 +1,377 @@
+use super::{align, wrap, Text};
+use crate::lib::aseprite::{AsepriteJSON, FrameInfo, MetaInfo, SpriteRect, SpriteSheetSize};
+use crate::lib::systems::renderer::TextureInfo;
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use std::collections::HashMap;
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BitmapGlyph {
+    // Frame of the font's sprite, None for glyphs without pixels, ie. spaces.
+    pub frame: Option<usize>,
+    // From the pen, on the top of the line, to the frame's top left corner.
+    pub offset: (i32, i32),
+    pub advance: i32,
+}
+
+/**
+ * A pixel font drawn through the sprite pipeline, every glyph being a frame of its sprite.
+ * Text sizes are rounded down to whole multiples of `size` so glyphs stay crisp.
+ */
+#[derive(Debug, Clone)]
+pub struct BitmapFont {
+    pub name: &'static str,
+    pub size: i32,
+    pub line_height: i32,
+    pub glyphs: HashMap<char, BitmapGlyph>,
+    // Added to the advance between two characters.
+    pub kerning: HashMap<(char, char), i32>,
+    // The sheet, loaded by the renderers as a sprite named after the font.
+    pub texture: TextureInfo,
+    // Size of every frame of the sprite.
+    pub canvas: (u32, u32),
+}
+
+// The tags of a .fnt file and their attributes, text and XML files alike.
+type Tags = Vec<(String, HashMap<String, String>)>;
+
+// `key=value` pairs after the tag of a line of a text .fnt, values may be quoted.
+fn text_tag(line: &str) -> Option<(String, HashMap<String, String>)> {
+    let line = line.trim();
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    if end == 0 {
+        return None;
+    }
+    let (tag, mut rest) = line.split_at(end);
+    let mut attributes = HashMap::new();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+        let value = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                rest = quoted.get(end + 1..).unwrap_or("");
+                &quoted[..end]
+            }
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let value = &rest[..end];
+                rest = &rest[end..];
+                value
+            }
+        };
+        attributes.insert(key, value.to_string());
+    }
+    Some((tag.to_string(), attributes))
+}
+
+fn xml_tags(source: &str, path: &str) -> Result<Tags> {
+    let mut tags = vec![];
+    for event in EventReader::new(source.as_bytes()) {
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = event.map_err(|e| eyre!("{}: {}", path, e))?
+        {
+            let attributes = attributes
+                .into_iter()
+                .map(|a| (a.name.local_name, a.value))
+                .collect();
+            tags.push((name.local_name, attributes));
+        }
+    }
+    Ok(tags)
+}
+
+impl BitmapFont {
+    /**
+     * Reads an AngelCode BMFont .fnt, in the text or XML format.
+     * The page image is found relative to the .fnt, only single page fonts are supported.
+     */
+    pub fn open(name: &'static str, path: &str) -> Result<Self> {
+        let source =
+            std::fs::read_to_string(path).map_err(|e| eyre!("Cannot open {}: {}", path, e))?;
+        let tags = if source.trim_start().starts_with('<') {
+            xml_tags(&source, path)?
+        } else {
+            source.lines().filter_map(text_tag).collect()
+        };
+
+        let mut size = 0;
+        let mut line_height = 0;
+        let mut sheet = (0, 0);
+        let mut pages = vec![];
+        let mut frames = vec![];
+        let mut glyphs = HashMap::new();
+        let mut kerning = HashMap::new();
+        for (tag, attributes) in &tags {
+            let get = |key: &str| -> Result<i32> {
+                match attributes.get(key) {
+                    Some(value) => value
+                        .parse()
+                        .map_err(|e| eyre!("{}: {} {}=\"{}\": {}", path, tag, key, value, e)),
+                    None => Ok(0),
+                }
+            };
+            let character = |key: &str| -> Result<char> {
+                let id = get(key)?;
+                std::char::from_u32(id as u32)
+                    .ok_or_else(|| eyre!("{}: {} {}={} is not a character", path, tag, key, id))
+            };
+            match tag.as_str() {
+                // Negative sizes are in pixels rather than points, either will do.
+                "info" => size = get("size")?.abs(),
+                "common" => {
+                    line_height = get("lineHeight")?;
+                    sheet = (
+                        get("scaleW")?.max(0) as usize,
+                        get("scaleH")?.max(0) as usize,
+                    );
+                }
+                "page" => pages.push(attributes.get("file").cloned().unwrap_or_default()),
+                "char" => {
+                    let (w, h) = (get("width")?.max(0) as u32, get("height")?.max(0) as u32);
+                    let frame = if w > 0 && h > 0 {
+                        frames.push(FrameInfo {
+                            filename: format!("{} {}", name, get("id")?),
+                            frame: SpriteRect {
+                                x: get("x")?,
+                                y: get("y")?,
+                                w,
+                                h,
+                            },
+                            rotated: false,
+                            trimmed: false,
+                            sprite_source_size: SpriteRect { x: 0, y: 0, w, h },
+                            duration: 100,
+                            source_size: SpriteSheetSize {
+                                w: w as usize,
+                                h: h as usize,
+                            },
+                        });
+                        Some(frames.len() - 1)
+                    } else {
+                        None
+                    };
+                    let glyph = BitmapGlyph {
+                        frame,
+                        offset: (get("xoffset")?, get("yoffset")?),
+                        advance: get("xadvance")?,
+                    };
+                    glyphs.insert(character("id")?, glyph);
+                }
+                "kerning" => {
+                    kerning.insert((character("first")?, character("second")?), get("amount")?);
+                }
+                _ => {}
+            }
+        }
+        let page = match pages.as_slice() {
+            [page] => page,
+            [] => bail!("{} has no page", path),
+            _ => bail!(
+                "{} has {} pages, only single page fonts are supported",
+                path,
+                pages.len()
+            ),
+        };
+        let image = match Path::new(path).parent() {
+            Some(dir) => dir.join(page).to_string_lossy().into_owned(),
+            None => page.clone(),
+        };
+        let json = AsepriteJSON {
+            frames,
+            meta: MetaInfo {
+                size: SpriteSheetSize {
+                    w: sheet.0,
+                    h: sheet.1,
+                },
+                frame_tags: vec![],
+                layers: vec![],
+                slices: vec![],
+            },
+        };
+        Ok(Self::new(
+            name,
+            Box::leak(image.into_boxed_str()),
+            json,
+            (size, line_height),
+            glyphs,
+            kerning,
+        ))
+    }
+
+    /**
+     * A font from a sheet whose frames are the glyphs of `chars`, in order, such as an
+     * Aseprite export or `AsepriteJSON::grid`. Every glyph advances by its frame width.
+     */
+    pub fn from_sheet(
+        name: &'static str,
+        path: &'static str,
+        json: AsepriteJSON,
+        chars: &str,
+    ) -> Self {
+        let glyphs = chars
+            .chars()
+            .zip(&json.frames)
+            .enumerate()
+            .map(|(i, (c, frame))| {
+                let glyph = BitmapGlyph {
+                    frame: Some(i),
+                    offset: (0, 0),
+                    advance: frame.source_size.w as i32,
+                };
+                (c, glyph)
+            })
+            .collect();
+        let height = json.canvas_size().1 as i32;
+        Self::new(name, path, json, (height, height), glyphs, HashMap::new())
+    }
+
+    fn new(
+        name: &'static str,
+        path: &'static str,
+        json: AsepriteJSON,
+        (size, line_height): (i32, i32),
+        glyphs: HashMap<char, BitmapGlyph>,
+        kerning: HashMap<(char, char), i32>,
+    ) -> Self {
+        let canvas = json.canvas_size();
+        Self {
+            name,
+            size: size.max(1),
+            line_height,
+            glyphs,
+            kerning,
+            texture: TextureInfo {
+                name,
+                path,
+                json: Some(json),
+                states: None,
+            },
+            canvas,
+        }
+    }
+
+    // How many times bigger than the sheet `size` is drawn, at least once.
+    pub fn scale(&self, size: u16) -> i32 {
+        (size as i32 / self.size).max(1)
+    }
+
+    // Width of a line at the sheet's size.
+    pub fn width(&self, line: &str) -> i32 {
+        let mut width = 0;
+        let mut previous = None;
+        for c in line.chars() {
+            if let Some(glyph) = self.glyphs.get(&c) {
+                if let Some(p) = previous {
+                    width += self.kerning.get(&(p, c)).copied().unwrap_or(0);
+                }
+                width += glyph.advance;
+                previous = Some(c);
+            }
+        }
+        width
+    }
+
+    // Width and height of `text` once laid out.
+    pub fn measure(&self, text: &Text) -> (u32, u32) {
+        let scale = self.scale(text.size);
+        let lines = wrap(text, |line| self.width(line) * scale);
+        let width = lines.iter().map(|(_, w)| *w).max().unwrap_or(0);
+        let height = lines.len() as i32 * self.line_height * scale;
+        (width.max(0) as u32, height.max(0) as u32)
+    }
+
+    /**
+     * The frame of every glyph of `text` and where its canvas goes relative to the
+     * Position, scaled for the text's size. Characters missing from the font are skipped.
+     */
+    pub fn layout(&self, text: &Text) -> Vec<(usize, (i32, i32))> {
+        let scale = self.scale(text.size);
+        let mut quads = vec![];
+        let lines = wrap(text, |line| self.width(line) * scale);
+        for (row, (line, width)) in lines.into_iter().enumerate() {
+            let mut pen = align(text.align, width);
+            let y = row as i32 * self.line_height * scale;
+            let mut previous = None;
+            for c in line.chars() {
+                let glyph = match self.glyphs.get(&c) {
+                    Some(glyph) => glyph,
+                    None => continue,
+                };
+                if let Some(p) = previous {
+                    pen += self.kerning.get(&(p, c)).copied().unwrap_or(0) * scale;
+                }
+                if let Some(frame) = glyph.frame {
+                    let (ox, oy) = glyph.offset;
+                    quads.push((frame, (pen + ox * scale, y + oy * scale)));
+                }
+                pen += glyph.advance * scale;
+                previous = Some(c);
+            }
+        }
+        quads
+    }
+}
+
+// The bitmap fonts Text can use, by name.
+#[derive(Debug, Clone, Default)]
+pub struct BitmapFonts(pub HashMap<&'static str, BitmapFont>);
+
+impl BitmapFonts {
+    pub fn add(&mut self, font: BitmapFont) {
+        self.0.insert(font.name, font);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BitmapFont> {
+        self.0.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_quoted_values() {
+        let (tag, attributes) = text_tag(r#"page id=0 file="mini sheet.png""#).unwrap();
+        assert_eq!(tag, "page");
+        assert_eq!(attributes["id"], "0");
+        assert_eq!(attributes["file"], "mini sheet.png");
+        assert!(text_tag("   ").is_none());
+    }
+
+    #[test]
+    fn reads_text_and_xml_fonts() {
+        for path in &["fixtures/fonts/mini.fnt", "fixtures/fonts/mini_xml.fnt"] {
+            let font = BitmapFont::open("mini", path).unwrap();
+            assert_eq!((font.size, font.line_height), (8, 10), "{}", path);
+            assert_eq!(font.texture.path, "fixtures/fonts/mini sheet.png");
+            assert_eq!(font.canvas, (8, 8));
+
+            let a = font.glyphs[&'A'];
+            assert_eq!((a.frame, a.offset, a.advance), (Some(0), (1, 2), 9));
+            assert_eq!(font.glyphs[&' '].frame, None);
+            assert_eq!(font.kerning[&('A', 'V')], -2);
+            // Kerning only applies between the pair, in order.
+            assert_eq!(font.width("AV"), 9 + 8 - 2);
+            assert_eq!(font.width("VA"), 8 + 9);
+        }
+    }
+
+    #[test]
+    fn sheet_fonts_advance_by_their_frames() {
+        let json = AsepriteJSON::grid("digits", (4, 6), 5, 10, 0, 0);
+        let font = BitmapFont::from_sheet("digits", "digits.png", json, "0123456789");
+        assert_eq!((font.size, font.line_height), (6, 6));
+        assert_eq!(font.glyphs[&'7'].frame, Some(7));
+        assert_eq!(font.width("2024"), 16);
+
+        let text = Text::new("12\n3", "digits", 12);
+        assert_eq!(font.scale(text.size), 2);
+        assert_eq!(font.layout(&text), [(1, (0, 0)), (2, (8, 0)), (3, (0, 12))]);
+    }
+}
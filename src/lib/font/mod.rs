@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use image::RgbaImage;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::ttf::{Font, Sdl2TtfContext};
+use specs::{Component, DenseVecStorage};
+
+pub mod bitmap;
+
+// Glyphs start in an atlas this wide and tall, doubled whenever it fills up.
+const ATLAS_SIZE: u32 = 256;
+const MAX_ATLAS_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/**
+ * A string drawn at the entity's Position, with a TTF font added to the FontManager
+ * or one of the BitmapFonts.
+ * Lines are aligned on the Position: Left starts there, Center is centered on it and
+ * Right ends there. Like sprites it is drawn in world space unless on the UI layer.
+ */
+#[derive(Component, Debug, Clone)]
+pub struct Text {
+    pub text: String,
+    pub font: &'static str,
+    // Point size TTF glyphs are rasterized at, in pixels of the virtual resolution.
+    // Bitmap fonts are drawn at whole multiples of their own size instead.
+    pub size: u16,
+    pub color: [f32; 4],
+    pub align: Align,
+    // Width in pixels lines are wrapped to at spaces, None to only break at newlines.
+    pub wrap: Option<u32>,
+}
+
+impl Text {
+    pub fn new(text: &str, font: &'static str, size: u16) -> Self {
+        Self {
+            text: text.to_string(),
+            font,
+            size,
+            color: [1.0; 4],
+            align: Align::Left,
+            wrap: None,
+        }
+    }
+}
+
+// The lines of `text` once wrapped, with their widths as measured by `width`.
+fn wrap(text: &Text, mut width: impl FnMut(&str) -> i32) -> Vec<(String, i32)> {
+    let space = width(" ");
+    let mut lines = vec![];
+    for paragraph in text.text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        for (i, word) in paragraph.split(' ').enumerate() {
+            let word_width = width(word);
+            match text.wrap {
+                // Words wider than the wrap get a line to themselves.
+                Some(wrap) if i > 0 && line_width + space + word_width > wrap as i32 => {
+                    lines.push((std::mem::take(&mut line), line_width));
+                    line_width = 0;
+                }
+                _ if i > 0 => {
+                    line.push(' ');
+                    line_width += space;
+                }
+                _ => {}
+            }
+            line.push_str(word);
+            line_width += word_width;
+        }
+        lines.push((line, line_width));
+    }
+    lines
+}
+
+// Where a line of `width` starts, relative to the Position.
+fn align(align: Align, width: i32) -> i32 {
+    match align {
+        Align::Left => 0,
+        Align::Center => -width / 2,
+        Align::Right => -width,
+    }
+}
+
+// A glyph to draw, relative to the text's Position, with its source rect in the atlas.
+#[derive(Debug, Clone, Copy)]
+pub struct GlyphQuad {
+    pub offset: (f32, f32),
+    pub size: (f32, f32),
+    pub source: Rect,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Glyph {
+    // None for glyphs without pixels, ie. spaces.
+    source: Option<Rect>,
+    advance: i32,
+}
+
+/**
+ * Rasterizes the glyphs of TTF fonts with SDL2_ttf as they are first used
+ * and packs them in rows of a single atlas, shared by every font and size.
+ */
+pub struct FontManager<'ttf> {
+    ttf: &'ttf Sdl2TtfContext,
+    paths: HashMap<&'static str, &'static str>,
+    // By name and point size, None when the font failed to load.
+    fonts: HashMap<(&'static str, u16), Option<Font<'ttf, 'static>>>,
+    glyphs: HashMap<(&'static str, u16, char), Glyph>,
+    atlas: RgbaImage,
+    // Bumped whenever glyphs are added to the atlas.
+    version: u32,
+    // Where the next glyph goes, and the height of the current row.
+    cursor: (u32, u32),
+    row_height: u32,
+}
+
+impl<'ttf> FontManager<'ttf> {
+    pub fn new(ttf: &'ttf Sdl2TtfContext) -> Self {
+        Self {
+            ttf,
+            paths: HashMap::new(),
+            fonts: HashMap::new(),
+            glyphs: HashMap::new(),
+            atlas: RgbaImage::new(ATLAS_SIZE, ATLAS_SIZE),
+            version: 0,
+            cursor: (0, 0),
+            row_height: 0,
+        }
+    }
+
+    // Makes the TTF file at `path` available to Text as `name`.
+    pub fn add(&mut self, name: &'static str, path: &'static str) {
+        self.paths.insert(name, path);
+    }
+
+    pub fn atlas(&self) -> &RgbaImage {
+        &self.atlas
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn font(&mut self, name: &'static str, size: u16) -> Option<&Font<'ttf, 'static>> {
+        let ttf = self.ttf;
+        let path = self.paths.get(name).copied();
+        self.fonts
+            .entry((name, size))
+            .or_insert_with(|| match path {
+                Some(path) => ttf
+                    .load_font(path, size)
+                    .map_err(|e| eprintln!("Cannot load font {}: {}", path, e))
+                    .ok(),
+                None => {
+                    eprintln!("Font \"{}\" was not added to the FontManager", name);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    // Space for a `w` x `h` glyph, growing the atlas when it is full.
+    fn allocate(&mut self, w: u32, h: u32) -> Option<Rect> {
+        loop {
+            let (mut x, mut y) = self.cursor;
+            let mut row_height = self.row_height;
+            if x + w > self.atlas.width() {
+                x = 0;
+                y += row_height;
+                row_height = 0;
+            }
+            if x + w <= self.atlas.width() && y + h <= self.atlas.height() {
+                self.cursor = (x + w + 1, y);
+                self.row_height = row_height.max(h + 1);
+                return Some(Rect::new(x as i32, y as i32, w, h));
+            }
+            let size = self.atlas.width() * 2;
+            if size > MAX_ATLAS_SIZE {
+                eprintln!("The glyph atlas is full");
+                return None;
+            }
+            // Glyphs keep their place, the shelves only get wider and more of them fit.
+            let mut atlas = RgbaImage::new(size, size);
+            image::imageops::replace(&mut atlas, &self.atlas, 0, 0);
+            self.atlas = atlas;
+        }
+    }
+
+    fn glyph(&mut self, name: &'static str, size: u16, c: char) -> Option<Glyph> {
+        if let Some(glyph) = self.glyphs.get(&(name, size, c)) {
+            return Some(*glyph);
+        }
+        let font = self.font(name, size)?;
+        let advance = font.find_glyph_metrics(c).map_or(0, |m| m.advance);
+        // Glyphs are white, Text colors them when drawn. The surface spans the
+        // font's height and the glyph's advance, so it sits right at the pen.
+        let surface = if c.is_whitespace() {
+            None
+        } else {
+            font.render_char(c)
+                .blended(Color::RGBA(255, 255, 255, 255))
+                .map_err(|e| e.to_string())
+                .and_then(|s| s.convert_format(PixelFormatEnum::RGBA32))
+                .map_err(|e| eprintln!("Cannot render '{}' of {}: {}", c, name, e))
+                .ok()
+        };
+        let mut glyph = Glyph {
+            source: None,
+            advance,
+        };
+        if let Some(surface) = surface {
+            let (w, h) = (surface.width(), surface.height());
+            if let Some(rect) = self.allocate(w, h) {
+                let pitch = surface.pitch() as usize;
+                let atlas = &mut self.atlas;
+                surface.with_lock(|pixels| {
+                    for y in 0..h {
+                        for x in 0..w {
+                            let i = y as usize * pitch + x as usize * 4;
+                            let pixel =
+                                atlas.get_pixel_mut(rect.x() as u32 + x, rect.y() as u32 + y);
+                            pixel.0.copy_from_slice(&pixels[i..i + 4]);
+                        }
+                    }
+                });
+                glyph.source = Some(rect);
+                self.version += 1;
+            }
+        }
+        self.glyphs.insert((name, size, c), glyph);
+        Some(glyph)
+    }
+
+    fn width(&mut self, text: &Text, line: &str) -> i32 {
+        line.chars()
+            .filter_map(|c| self.glyph(text.font, text.size, c))
+            .map(|g| g.advance)
+            .sum()
+    }
+
+    fn lines(&mut self, text: &Text) -> Vec<(String, i32)> {
+        wrap(text, |line| self.width(text, line))
+    }
+
+    fn line_height(&mut self, text: &Text) -> i32 {
+        self.font(text.font, text.size)
+            .map_or(0, |f| f.recommended_line_spacing())
+    }
+
+    // Width and height of `text` once laid out.
+    pub fn measure(&mut self, text: &Text) -> (u32, u32) {
+        let lines = self.lines(text);
+        let width = lines.iter().map(|(_, w)| *w).max().unwrap_or(0);
+        let height = lines.len() as i32 * self.line_height(text);
+        (width.max(0) as u32, height.max(0) as u32)
+    }
+
+    /**
+     * Quads of the glyphs of `text` relative to its Position, rasterizing new ones.
+     * Sources are in atlas pixels and stay put as it grows, upload the atlas
+     * once everything drawn in a frame is laid out.
+     */
+    pub fn layout(&mut self, text: &Text) -> Vec<GlyphQuad> {
+        let line_height = self.line_height(text);
+        let mut quads = vec![];
+        for (row, (line, width)) in self.lines(text).into_iter().enumerate() {
+            let mut pen = align(text.align, width);
+            let y = row as i32 * line_height;
+            for c in line.chars() {
+                let glyph = match self.glyph(text.font, text.size, c) {
+                    Some(glyph) => glyph,
+                    None => continue,
+                };
+                if let Some(source) = glyph.source {
+                    quads.push(GlyphQuad {
+                        offset: (pen as f32, y as f32),
+                        size: (source.width() as f32, source.height() as f32),
+                        source,
+                    });
+                }
+                pen += glyph.advance;
+            }
+        }
+        quads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lines of `text` with glyphs 4 pixels wide.
+    fn lines(text: &str, width: Option<u32>) -> Vec<(String, i32)> {
+        let text = Text {
+            wrap: width,
+            ..Text::new(text, "mono", 8)
+        };
+        wrap(&text, |line| line.chars().count() as i32 * 4)
+    }
+
+    #[test]
+    fn lines_wrap_at_spaces() {
+        let wrapped = lines("the quick brown fox", Some(40));
+        assert_eq!(
+            wrapped,
+            [("the quick".to_string(), 36), ("brown fox".to_string(), 36)]
+        );
+        // Newlines always break, even without a wrap.
+        let unwrapped = lines("one two\nthree", None);
+        assert_eq!(
+            unwrapped,
+            [("one two".to_string(), 28), ("three".to_string(), 20)]
+        );
+    }
+
+    #[test]
+    fn long_words_get_a_line_to_themselves() {
+        let wrapped = lines("a enormously b", Some(20));
+        let words: Vec<_> = wrapped
+            .iter()
+            .map(|(line, w)| (line.as_str(), *w))
+            .collect();
+        assert_eq!(words, [("a", 4), ("enormously", 40), ("b", 4)]);
+    }
+
+    #[test]
+    fn lines_align_on_the_position() {
+        assert_eq!(align(Align::Left, 36), 0);
+        assert_eq!(align(Align::Center, 36), -18);
+        assert_eq!(align(Align::Right, 36), -36);
+    }
+}
@@ -0,0 +1,102 @@
+//! What the map importers (tiled, ldtk) share: reading the files, the custom values
+//! of their objects, and the templates that add components to what they spawn.
+use crate::lib::sprite::SpriteManager;
+use crate::systems::components::SpriteHandle;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use specs::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// A custom value of a map object, ie. a Tiled property or an LDtk field.
+pub trait CustomValue {
+    fn as_bool(&self) -> Option<bool>;
+    fn as_str(&self) -> Option<&str>;
+}
+
+// Whether the value `name` is set to true, false when it is missing or not a bool.
+pub fn flag<V: CustomValue>(values: &HashMap<String, V>, name: &str) -> bool {
+    values
+        .get(name)
+        .and_then(CustomValue::as_bool)
+        .unwrap_or(false)
+}
+
+// Layers are visible unless the file says otherwise.
+pub fn visible() -> bool {
+    true
+}
+
+pub fn read_json<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
+    let file = File::open(path).map_err(|e| eyre!("Cannot open {}: {}", path, e))?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| eyre!("{}: {}", path, e))
+}
+
+// Path of `file` as referenced from a map, tileset or project at `from`.
+pub fn relative(from: &str, file: &str) -> String {
+    match Path::new(from).parent() {
+        Some(dir) => dir.join(file).to_string_lossy().into_owned(),
+        None => file.to_string(),
+    }
+}
+
+// A sprite of `image` paused on `tile`, instead of cycling through the whole tileset.
+pub fn tile_sprite(sprites: &mut SpriteManager, image: &'static str, tile: u32) -> SpriteHandle {
+    let handle = sprites.init(image);
+    if let Ok(state) = sprites.state_mut(&handle) {
+        state.frame_i = tile as usize;
+        state.paused = true;
+    }
+    handle
+}
+
+// A sprite of the loaded sprite `name`, None when there is no such sprite.
+pub fn named_sprite(sprites: &mut SpriteManager, name: &str) -> Option<SpriteHandle> {
+    let name = sprites.get(name)?.info.name;
+    Some(sprites.init(name))
+}
+
+/**
+ * Adds components to entities spawned from a `T` of a given kind,
+ * ie. `templates.insert("chicken", |_, sprites, e| e.with(sprites.init("chicken")))`
+ */
+pub type Template<T> =
+    Box<dyn for<'a> Fn(&T, &mut SpriteManager, EntityBuilder<'a>) -> EntityBuilder<'a>>;
+
+pub struct Templates<T>(HashMap<String, Template<T>>);
+
+impl<T> Default for Templates<T> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<T> Templates<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<F>(&mut self, kind: &str, template: F)
+    where
+        F: for<'a> Fn(&T, &mut SpriteManager, EntityBuilder<'a>) -> EntityBuilder<'a> + 'static,
+    {
+        self.0.insert(kind.to_string(), Box::new(template));
+    }
+
+    // Runs the template of `kind` on `builder`, if there is one.
+    pub fn apply<'a>(
+        &self,
+        kind: &str,
+        object: &T,
+        sprites: &mut SpriteManager,
+        builder: EntityBuilder<'a>,
+    ) -> EntityBuilder<'a> {
+        match self.0.get(kind) {
+            Some(template) => template(object, sprites, builder),
+            None => builder,
+        }
+    }
+}
@@ -0,0 +1,667 @@
+use crate::aseprite::AsepriteJSON;
+use crate::lib::importer::{self, flag, named_sprite, read_json, relative, tile_sprite};
+use crate::lib::importer::{visible, CustomValue};
+use crate::lib::sprite::SpriteManager;
+use crate::systems::components::CollisionType::Static;
+use crate::systems::components::{Collision, Layer, Position, RenderLayer, Size, ZIndex};
+use crate::systems::renderer::TextureInfo;
+use crate::tilemap::Tilemap;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use sdl2::rect::Rect;
+use serde::Deserialize;
+use serde_json::Value;
+use specs::prelude::*;
+use specs::Component;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    // Strings, multilines, colors, file paths and enum values.
+    String(String),
+    // In grid cells of the level.
+    Point(i32, i32),
+    // Iid of the referenced entity.
+    EntityRef(String),
+    Array(Vec<Field>),
+}
+
+impl CustomValue for Field {
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Field::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Field::String(s) | Field::EntityRef(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Field {
+    fn parse(kind: &str, value: Value) -> Field {
+        if let Some(inner) = kind
+            .strip_prefix("Array<")
+            .and_then(|k| k.strip_suffix('>'))
+        {
+            return match value {
+                Value::Array(values) => Field::Array(
+                    values
+                        .into_iter()
+                        .map(|value| Field::parse(inner, value))
+                        .collect(),
+                ),
+                _ => Field::Null,
+            };
+        }
+        match (kind, value) {
+            (_, Value::Null) => Field::Null,
+            (_, Value::Bool(b)) => Field::Bool(b),
+            ("Float", Value::Number(n)) => Field::Float(n.as_f64().unwrap_or(0.0)),
+            (_, Value::Number(n)) => match n.as_i64() {
+                Some(i) => Field::Int(i),
+                None => Field::Float(n.as_f64().unwrap_or(0.0)),
+            },
+            (_, Value::String(s)) => Field::String(s),
+            ("Point", value) => Field::Point(
+                value["cx"].as_i64().unwrap_or(0) as i32,
+                value["cy"].as_i64().unwrap_or(0) as i32,
+            ),
+            ("EntityRef", value) => match value["entityIid"].as_str() {
+                Some(iid) => Field::EntityRef(iid.to_string()),
+                None => Field::Null,
+            },
+            (_, other) => Field::String(other.to_string()),
+        }
+    }
+}
+
+pub type Fields = HashMap<String, Field>;
+
+// The field values of the LDtk entity instance an entity was spawned from.
+#[derive(Component, Debug, Clone, Default)]
+pub struct EntityFields(pub Fields);
+
+#[derive(Deserialize)]
+struct RawField {
+    #[serde(rename = "__identifier")]
+    identifier: String,
+    #[serde(rename = "__type")]
+    kind: String,
+    #[serde(rename = "__value")]
+    value: Value,
+}
+
+fn fields<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Fields, D::Error> {
+    let raw: Vec<RawField> = Deserialize::deserialize(deserializer)?;
+    Ok(raw
+        .into_iter()
+        .map(|f| {
+            let value = Field::parse(&f.kind, f.value);
+            (f.identifier, value)
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Tileset {
+    pub uid: i64,
+    pub identifier: String,
+    // Relative to the project, internal atlases (ie. LDtk's icons) have none.
+    pub rel_path: Option<String>,
+    pub tile_grid_size: u32,
+    #[serde(default)]
+    pub spacing: u32,
+    #[serde(default)]
+    pub padding: u32,
+    #[serde(rename = "__cWid")]
+    pub columns: u32,
+    #[serde(rename = "__cHei")]
+    pub rows: u32,
+    // Path of the tileset image, also the name its sprite is loaded under.
+    #[serde(skip)]
+    pub image: &'static str,
+}
+
+impl Tileset {
+    // The sprite whose frames are the tiles of this set, numbered like LDtk's tile ids.
+    pub fn texture(&self) -> TextureInfo {
+        TextureInfo {
+            name: self.image,
+            path: self.image,
+            json: Some(AsepriteJSON::grid(
+                &self.identifier,
+                (self.tile_grid_size, self.tile_grid_size),
+                self.columns,
+                self.columns * self.rows,
+                self.padding,
+                self.spacing,
+            )),
+            states: None,
+        }
+    }
+
+    // Id of the tile whose top left corner is at `(x, y)` in the image.
+    fn tile_at(&self, x: u32, y: u32) -> u32 {
+        let step = self.tile_grid_size + self.spacing;
+        let column = x.saturating_sub(self.padding) / step;
+        let row = y.saturating_sub(self.padding) / step;
+        row * self.columns + column
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TileInstance {
+    // Position in the layer, in pixels.
+    pub px: [i32; 2],
+    // Id in the layer's tileset. Flips (`f`) are not supported and ignored.
+    pub t: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TilesetRect {
+    pub tileset_uid: i64,
+    pub x: u32,
+    pub y: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EntityInstance {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    #[serde(default)]
+    pub iid: String,
+    // Position of the pivot in the level, in pixels.
+    pub px: [i32; 2],
+    #[serde(rename = "__pivot", default)]
+    pub pivot: [f32; 2],
+    pub width: u32,
+    pub height: u32,
+    #[serde(rename = "__tile")]
+    pub tile: Option<TilesetRect>,
+    #[serde(rename = "fieldInstances", deserialize_with = "fields", default)]
+    pub fields: Fields,
+}
+
+impl EntityInstance {
+    // Top left corner in the level.
+    pub fn top_left(&self) -> (i32, i32) {
+        (
+            self.px[0] - (self.pivot[0] * self.width as f32) as i32,
+            self.px[1] - (self.pivot[1] * self.height as f32) as i32,
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerInstance {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    // IntGrid, Entities, Tiles or AutoLayer.
+    #[serde(rename = "__type")]
+    pub kind: String,
+    #[serde(rename = "__cWid")]
+    pub width: usize,
+    #[serde(rename = "__cHei")]
+    pub height: usize,
+    #[serde(rename = "__gridSize")]
+    pub grid_size: u32,
+    #[serde(rename = "__pxTotalOffsetX", default)]
+    pub offset_x: i32,
+    #[serde(rename = "__pxTotalOffsetY", default)]
+    pub offset_y: i32,
+    #[serde(rename = "__tilesetDefUid")]
+    pub tileset: Option<i64>,
+    #[serde(default = "visible")]
+    pub visible: bool,
+    // Row major, 0 is an empty cell.
+    #[serde(default)]
+    pub int_grid_csv: Vec<i64>,
+    #[serde(default)]
+    pub auto_layer_tiles: Vec<TileInstance>,
+    #[serde(default)]
+    pub grid_tiles: Vec<TileInstance>,
+    #[serde(default)]
+    pub entity_instances: Vec<EntityInstance>,
+}
+
+impl LayerInstance {
+    // IntGrid value of a cell, 0 when empty or out of the layer.
+    pub fn int_grid(&self, x: usize, y: usize) -> i64 {
+        if x < self.width && y < self.height {
+            self.int_grid_csv
+                .get(y * self.width + x)
+                .copied()
+                .unwrap_or(0)
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    #[serde(rename = "n")]
+    North,
+    #[serde(rename = "s")]
+    South,
+    #[serde(rename = "e")]
+    East,
+    #[serde(rename = "w")]
+    West,
+    // Diagonals, and levels above, below or overlapping in depth.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Neighbour {
+    pub level_iid: String,
+    pub dir: Direction,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Level {
+    pub identifier: String,
+    pub iid: String,
+    pub world_x: i32,
+    pub world_y: i32,
+    pub px_wid: u32,
+    pub px_hei: u32,
+    #[serde(rename = "__neighbours", default)]
+    pub neighbours: Vec<Neighbour>,
+    #[serde(rename = "fieldInstances", deserialize_with = "fields", default)]
+    pub fields: Fields,
+    // Top layer first. Missing for levels saved in separate .ldtkl files until loaded.
+    pub layer_instances: Option<Vec<LayerInstance>>,
+    external_rel_path: Option<String>,
+}
+
+impl Level {
+    // Area of the level in the world, ie. for Camera::bounds.
+    pub fn bounds(&self) -> Rect {
+        Rect::new(self.world_x, self.world_y, self.px_wid, self.px_hei)
+    }
+
+    pub fn layers(&self) -> &[LayerInstance] {
+        self.layer_instances.as_deref().unwrap_or(&[])
+    }
+
+    pub fn layer(&self, identifier: &str) -> Option<&LayerInstance> {
+        self.layers().iter().find(|l| l.identifier == identifier)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawDefs {
+    #[serde(default)]
+    tilesets: Vec<Tileset>,
+}
+
+#[derive(Deserialize)]
+struct RawWorld {
+    #[serde(default)]
+    levels: Vec<Level>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawProject {
+    defs: RawDefs,
+    #[serde(default)]
+    levels: Vec<Level>,
+    // Projects with the multi-worlds option keep their levels here instead.
+    #[serde(default)]
+    worlds: Vec<RawWorld>,
+}
+
+// Adds components to entities spawned from instances of a given LDtk entity,
+// see importer::Templates.
+pub type Templates = importer::Templates<EntityInstance>;
+
+/**
+ * A project made in LDtk (.ldtk), with every level of its world(s) loaded,
+ * including the ones saved as separate .ldtkl files.
+ * Levels are spawned one at a time at their world position, `leaving` finds the level to
+ * move to when the player leaves the current one.
+ */
+#[derive(Debug, Clone)]
+pub struct LdtkProject {
+    pub tilesets: Vec<Tileset>,
+    pub levels: Vec<Level>,
+}
+
+impl LdtkProject {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::read(path).map_err(|e| eyre!("Cannot load LDtk project {}: {}", path, e))
+    }
+
+    fn read(path: &str) -> Result<Self> {
+        let raw: RawProject = read_json(path)?;
+        let mut tilesets = raw.defs.tilesets;
+        for set in &mut tilesets {
+            if let Some(image) = &set.rel_path {
+                set.image = SpriteManager::intern(relative(path, image));
+            }
+        }
+        let mut levels = raw.levels;
+        levels.extend(raw.worlds.into_iter().flat_map(|w| w.levels));
+        for level in &mut levels {
+            if level.layer_instances.is_some() {
+                continue;
+            }
+            if let Some(external) = &level.external_rel_path {
+                let loaded: Level = read_json(&relative(path, external))?;
+                level.layer_instances = loaded.layer_instances;
+            }
+        }
+        Ok(Self { tilesets, levels })
+    }
+
+    pub fn tileset(&self, uid: i64) -> Option<&Tileset> {
+        self.tilesets.iter().find(|set| set.uid == uid)
+    }
+
+    pub fn level(&self, identifier: &str) -> Option<&Level> {
+        self.levels.iter().find(|l| l.identifier == identifier)
+    }
+
+    pub fn level_by_iid(&self, iid: &str) -> Option<&Level> {
+        self.levels.iter().find(|l| l.iid == iid)
+    }
+
+    // The level linked to `level` on its `dir` side that contains `point`, if any.
+    pub fn neighbour(&self, level: &Level, dir: Direction, point: (i32, i32)) -> Option<&Level> {
+        level
+            .neighbours
+            .iter()
+            .filter(|n| n.dir == dir)
+            .filter_map(|n| self.level_by_iid(&n.level_iid))
+            .find(|l| l.bounds().contains_point(point))
+    }
+
+    /**
+     * The level to move to once `point` left `level`: the neighbour on the side it crossed,
+     * or whichever level contains it. None while the point is still in `level`.
+     */
+    pub fn leaving(&self, level: &Level, point: (i32, i32)) -> Option<&Level> {
+        let bounds = level.bounds();
+        if bounds.contains_point(point) {
+            return None;
+        }
+        let dir = if point.0 < bounds.left() {
+            Direction::West
+        } else if point.0 >= bounds.right() {
+            Direction::East
+        } else if point.1 < bounds.top() {
+            Direction::North
+        } else {
+            Direction::South
+        };
+        self.neighbour(level, dir, point)
+            .or_else(|| self.level_at(point))
+    }
+
+    // The level containing a point of the world.
+    pub fn level_at(&self, point: (i32, i32)) -> Option<&Level> {
+        self.levels
+            .iter()
+            .find(|l| l.bounds().contains_point(point))
+    }
+
+    // Sprites of every tileset with an image, to be loaded by the renderer before spawning.
+    pub fn textures(&self) -> Vec<TextureInfo> {
+        self.tilesets
+            .iter()
+            .filter(|set| set.rel_path.is_some())
+            .map(Tileset::texture)
+            .collect()
+    }
+
+    /**
+     * Creates the entities of `level` in `world`, at the level's world position.
+     * Tile, auto and IntGrid layers become Tilemaps, more than one when tiles are stacked.
+     * Non zero IntGrid cells are solid, an IntGrid layer without tiles becomes a
+     * Tilemap with no tileset that only collides.
+     * Each entity instance becomes an entity with Position, Size and its EntityFields,
+     * `Collision(Some(Static))` when its `solid` field is true, a sprite when it has a tile
+     * or a `sprite` field, and then whatever the template registered for it adds.
+     * Layers are ZIndex'ed in draw order, entities go on the Actors RenderLayer.
+     */
+    pub fn spawn_level(
+        &self,
+        world: &mut World,
+        sprites: &mut SpriteManager,
+        templates: &Templates,
+        level: &Level,
+    ) -> Vec<Entity> {
+        world.register::<EntityFields>();
+        let mut spawned = vec![];
+        let layers = level.layers();
+        for (i, layer) in layers.iter().enumerate() {
+            let z = (layers.len() - i) as i32;
+            let origin = (
+                level.world_x + layer.offset_x,
+                level.world_y + layer.offset_y,
+            );
+            if layer.kind == "Entities" {
+                for instance in &layer.entity_instances {
+                    let (x, y) = instance.top_left();
+                    let entity = self.spawn_entity(
+                        world,
+                        sprites,
+                        templates,
+                        instance,
+                        (origin.0 + x, origin.1 + y),
+                        z,
+                    );
+                    spawned.push(entity);
+                }
+                continue;
+            }
+            for map in self.tilemaps(layer) {
+                let entity = world
+                    .create_entity()
+                    .with(Position(origin.0, origin.1))
+                    .with(map)
+                    .with(RenderLayer(Layer::World))
+                    .with(ZIndex(z))
+                    .build();
+                spawned.push(entity);
+            }
+        }
+        spawned
+    }
+
+    // The first Tilemap holds the layer's collision, the others stacked tiles.
+    fn tilemaps(&self, layer: &LayerInstance) -> Vec<Tilemap> {
+        let size = (layer.width, layer.height);
+        let tile_size = (layer.grid_size, layer.grid_size);
+        let tileset = match layer.tileset.and_then(|uid| self.tileset(uid)) {
+            Some(set) if layer.visible => set.image,
+            _ => "",
+        };
+        let mut maps = vec![Tilemap::new(tileset, size, tile_size)];
+        let grid = layer.grid_size.max(1) as i32;
+        let mut tiles = layer
+            .grid_tiles
+            .iter()
+            .chain(&layer.auto_layer_tiles)
+            .peekable();
+        let solid = layer.int_grid_csv.iter().any(|&v| v != 0);
+        // Keep layers that draw or collide.
+        if !solid && (tileset.is_empty() || tiles.peek().is_none()) {
+            return vec![];
+        }
+        if !tileset.is_empty() {
+            for tile in tiles {
+                let (x, y) = ((tile.px[0] / grid) as usize, (tile.px[1] / grid) as usize);
+                let free = maps.iter().position(|map| map.get(x, y).is_none());
+                let map = match free {
+                    Some(i) => &mut maps[i],
+                    None => {
+                        maps.push(Tilemap::new(tileset, size, tile_size));
+                        maps.last_mut().unwrap()
+                    }
+                };
+                map.set(x, y, Some(tile.t));
+            }
+        }
+        for y in 0..layer.height {
+            for x in 0..layer.width {
+                if layer.int_grid(x, y) != 0 {
+                    maps[0].set_solid(x, y, true);
+                }
+            }
+        }
+        maps
+    }
+
+    fn spawn_entity(
+        &self,
+        world: &mut World,
+        sprites: &mut SpriteManager,
+        templates: &Templates,
+        instance: &EntityInstance,
+        (x, y): (i32, i32),
+        z: i32,
+    ) -> Entity {
+        let mut builder = world
+            .create_entity()
+            .with(Position(x, y))
+            .with(Size(instance.width as i32, instance.height as i32))
+            .with(EntityFields(instance.fields.clone()))
+            .with(RenderLayer(Layer::Actors))
+            .with(ZIndex(z));
+        if flag(&instance.fields, "solid") {
+            builder = builder.with(Collision(Some(Static)));
+        }
+        let tile = instance.tile.as_ref().and_then(|rect| {
+            let set = self.tileset(rect.tileset_uid)?;
+            set.rel_path.as_ref()?;
+            Some((set, set.tile_at(rect.x, rect.y)))
+        });
+        if let Some(name) = instance.fields.get("sprite").and_then(Field::as_str) {
+            match named_sprite(sprites, name) {
+                Some(handle) => builder = builder.with(handle),
+                None => eprintln!("Entity {} uses unknown sprite \"{}\"", instance.iid, name),
+            }
+        } else if let Some((set, tile)) = tile {
+            builder = builder.with(tile_sprite(sprites, set.image, tile));
+        }
+        templates
+            .apply(&instance.identifier, instance, sprites, builder)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> LdtkProject {
+        LdtkProject::open("fixtures/ldtk/world.ldtk").unwrap()
+    }
+
+    #[test]
+    fn loads_every_level() {
+        let project = project();
+        let first = project.level("Level_0").unwrap();
+        assert_eq!(first.fields["music"].as_str(), Some("field"));
+        // Level_1 is saved in its own file.
+        let second = project.level("Level_1").unwrap();
+        assert_eq!(second.layers().len(), 1);
+        assert_eq!(second.layer("Collisions").unwrap().int_grid(2, 1), 1);
+
+        let iid = |level: Option<&Level>| level.map(|l| l.iid.clone());
+        let east = project.neighbour(first, Direction::East, (50, 10));
+        assert_eq!(iid(east), Some(second.iid.clone()));
+        assert!(project
+            .neighbour(first, Direction::West, (50, 10))
+            .is_none());
+        assert_eq!(iid(project.level_at((47, 31))), Some(first.iid.clone()));
+        assert!(project.level_at((200, 0)).is_none());
+        assert_eq!(
+            iid(project.leaving(first, (50, 10))),
+            Some(second.iid.clone())
+        );
+        assert!(project.leaving(first, (10, 10)).is_none());
+        assert!(project.leaving(first, (200, 0)).is_none());
+
+        // LDtk's internal icons have no image to load.
+        let textures: Vec<_> = project.textures().iter().map(|t| t.name).collect();
+        assert_eq!(textures, [project.tileset(1).unwrap().image]);
+    }
+
+    #[test]
+    fn parses_fields() {
+        let project = project();
+        let layer = project.level("Level_0").unwrap().layer("Entities").unwrap();
+        let chicken = &layer.entity_instances[0];
+        assert_eq!(chicken.top_left(), (16, 16));
+        let fields = &chicken.fields;
+        assert!(flag(fields, "solid"));
+        assert_eq!(fields["speed"], Field::Float(1.5));
+        assert_eq!(fields["follows"], Field::EntityRef("chicken-1".to_string()));
+        assert_eq!(
+            fields["path"],
+            Field::Array(vec![Field::Point(1, 0), Field::Point(2, 1)])
+        );
+        assert_eq!(fields["mood"], Field::Null);
+    }
+
+    #[test]
+    fn spawns_a_level() {
+        let project = project();
+        let mut world = World::new();
+        world.register::<Position>();
+        world.register::<Size>();
+        world.register::<RenderLayer>();
+        world.register::<ZIndex>();
+        world.register::<Collision>();
+        world.register::<Tilemap>();
+        let mut sprites = SpriteManager::new();
+        let level = project.level("Level_0").unwrap();
+        let spawned = project.spawn_level(&mut world, &mut sprites, &Templates::new(), level);
+        // The chicken, the walls with a second map for the stacked tile, the collisions.
+        assert_eq!(spawned.len(), 4);
+
+        let chicken = spawned[0];
+        assert_eq!(world.read_storage::<Position>().get(chicken).unwrap().0, 16);
+        assert!(world.read_storage::<Collision>().get(chicken).is_some());
+        let fields = world.read_storage::<EntityFields>();
+        assert_eq!(
+            fields.get(chicken).unwrap().0,
+            level.layers()[0].entity_instances[0].fields
+        );
+
+        let maps = world.read_storage::<Tilemap>();
+        let walls: Vec<_> = spawned[1..3]
+            .iter()
+            .map(|&e| maps.get(e).unwrap())
+            .collect();
+        assert_eq!((walls[0].get(0, 1), walls[0].get(2, 1)), (Some(0), Some(0)));
+        assert_eq!((walls[1].get(0, 1), walls[1].get(2, 1)), (Some(0), None));
+        assert_eq!(walls[0].tileset, project.tileset(1).unwrap().image);
+
+        let collisions = maps.get(spawned[3]).unwrap();
+        assert!(collisions.tileset.is_empty());
+        assert!(!collisions.is_solid(0, 0) && collisions.is_solid(0, 1));
+        let z = world.read_storage::<ZIndex>();
+        assert!(z.get(spawned[0]).unwrap().0 > z.get(spawned[3]).unwrap().0);
+    }
+}
@@ -1,7 +1,9 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::sync::Mutex;
 
 #[derive(Debug)]
 pub struct DisplayError<T: Display + Debug> {
@@ -18,3 +20,14 @@ impl<T: Display + Debug> Display for DisplayError<T> {
     }
 }
 impl<T: Display + Debug> Error for DisplayError<T> {}
+
+lazy_static! {
+    static ref WARNED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// Prints `message` the first time only, for problems found again every frame.
+pub fn warn_once(message: String) {
+    if WARNED.lock().unwrap().insert(message.clone()) {
+        eprintln!("{}", message);
+    }
+}
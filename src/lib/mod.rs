@@ -1,6 +1,12 @@
+pub mod animation;
 pub mod aseprite;
+pub mod font;
+pub mod importer;
+pub mod ldtk;
 pub mod logging;
 pub mod opengl;
 // pub mod ecs; RIP.
 pub mod sprite;
 pub mod systems;
+pub mod tiled;
+pub mod tilemap;
@@ -25,6 +25,16 @@ impl SDL2Facade {
             self.backend.get_framebuffer_dimensions(),
         )
     }
+
+    // Size of the window in points.
+    pub fn window_size(&self) -> (u32, u32) {
+        self.backend.window.size()
+    }
+
+    // Size of the window in pixels.
+    pub fn drawable_size(&self) -> (u32, u32) {
+        self.backend.window.drawable_size()
+    }
 }
 pub struct SDL2Backend {
     window: sdl2_video::Window,
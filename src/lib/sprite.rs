@@ -1,100 +1,253 @@
+use crate::animation::AnimationEvent;
+use crate::animation::AnimationInput;
+use crate::animation::StateMachine;
+use crate::aseprite::FrameInfo;
+use crate::aseprite::SliceKey;
 use crate::aseprite::Tags;
 
+use crate::systems::components::Size;
 use crate::systems::components::SpriteHandle;
-use crate::systems::input::InputState;
 use crate::systems::renderer::TextureInfo;
-use std::marker::PhantomData;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Mutex;
 
+use sdl2::rect::Rect;
+use specs::prelude::*;
+use specs::shrev::EventChannel;
+use specs::world::Index;
+use std::fmt;
+use std::fmt::Display;
 use std::time::Duration;
 
 pub type SpriteIndex = usize;
 
-#[derive(Debug)]
-pub struct StateMachine<T> {
-    pub tags: Vec<Tags>,
-    _t: PhantomData<T>,
+// What a tag played once does after its last frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFinish {
+    // Stay on the last frame.
+    Hold,
+    // Go back to the tag that was playing before.
+    Return,
 }
 
-impl StateMachine<InputState> {
-    fn run(&mut self, object: InputState) -> Result<&Tags, &str> {
-        println!("You found my state machine!");
-        match object {
-            InputState::Idle => self
-                .tags
-                .iter()
-                .find(|t| t.name == "still")
-                .ok_or("Still tag not found"),
-            InputState::Running => self
-                .tags
-                .iter()
-                .find(|t| t.name == "run")
-                .ok_or("Run tag not found"),
-        }
-    }
+/**
+ * A tag to play, looping when `once` is None.
+ */
+#[derive(Debug, Clone)]
+pub struct Clip {
+    pub tag: Tags,
+    pub once: Option<OnFinish>,
+}
+
+// What to do once the current tag reached the end of its loop.
+enum LoopEnd {
+    Play(Clip),
+    Hold,
+    Step,
 }
 
 #[derive(Debug)]
 pub struct SpriteState {
-    pub state: InputState,
     pub tag: Option<Tags>,
     pub frame_i: usize,
     sprite: &'static str,
     time: Duration,
+    // Travelling backwards through a ping-pong tag.
+    reversed: bool,
+    // The events of the current frame have not been published yet.
+    entered: bool,
     pub texture: u32,
-    pub state_machine: Option<StateMachine<InputState>>,
+    pub state_machine: Option<StateMachine>,
+    // Playback rate multiplier, 1.0 is the speed authored in the sheet.
+    pub speed: f64,
+    pub paused: bool,
+    // Set while the current tag is played once.
+    once: Option<OnFinish>,
+    // Tag to go back to after a one-shot with OnFinish::Return.
+    previous: Option<Tags>,
+    // The last one-shot reached its final frame.
+    finished: bool,
+    queue: VecDeque<Clip>,
 }
 
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use glium::texture::Texture2dArray;
 use glium::uniforms::MagnifySamplerFilter;
 use glium::uniforms::MinifySamplerFilter;
 use glium::uniforms::Sampler;
 use glium::uniforms::SamplerWrapFunction;
+use image::RgbaImage;
 
 trait Ack<T> {
     fn ack(&mut self, object: T) -> Result<(), &str>;
 }
 
-impl Ack<InputState> for SpriteState {
-    fn ack(&mut self, object: InputState) -> Result<(), &str> {
-        if self.state == object {
-            // Force idempotence on input state object
-            // Ignore states that do not cause change in internal state.
+impl<'a, T: AnimationInput + ?Sized> Ack<&'a T> for SpriteState {
+    fn ack(&mut self, object: &'a T) -> Result<(), &str> {
+        let state_machine = self
+            .state_machine
+            .as_mut()
+            .ok_or("The current object does not have an associated state machine with it..")?;
+        // One-shots are not interrupted by the state machine.
+        if self.once.is_some() && !self.finished {
             return Ok(());
-        } else {
-            self.state = object;
-        }
-        if self.state_machine.is_none() {
-            return Err("The current object does not have an associated state machine with it..");
-        }
-        match self.state_machine.as_mut().unwrap().run(object) {
-            Ok(tag) => {
-                println!("{:?}", tag);
-                self.tag.replace(tag.clone());
-                self.frame_i = tag.from;
-                Ok(())
+        }
+        // Only transitions cause a change in internal state.
+        if state_machine.run(object) {
+            let tag = state_machine.tag()?.clone();
+            self.start(Clip { tag, once: None });
+        }
+        Ok(())
+    }
+}
+
+impl SpriteState {
+    // Starts `tag` from its first frame.
+    fn play(&mut self, tag: Tags) {
+        self.frame_i = tag.first_frame();
+        self.reversed = tag.direction.starts_reversed();
+        self.time = Duration::ZERO;
+        self.entered = true;
+        self.tag.replace(tag);
+    }
+
+    fn start(&mut self, clip: Clip) {
+        match clip.once {
+            // Chained one-shots return to what played before the first of them.
+            Some(OnFinish::Return) => {
+                if self.previous.is_none() {
+                    self.previous = self.tag.clone();
+                }
+            }
+            // Any other clip replacing a one-shot has nothing to return to.
+            _ => self.previous = None,
+        }
+        if clip.once.is_some() {
+            self.finished = false;
+        }
+        self.once = clip.once;
+        self.play(clip.tag);
+    }
+
+    // Called when the current tag played its last frame.
+    fn end_of_loop(&mut self) -> LoopEnd {
+        if self.once.is_some() {
+            self.finished = true;
+        }
+        if let Some(clip) = self.queue.pop_front() {
+            return LoopEnd::Play(clip);
+        }
+        match self.once {
+            Some(OnFinish::Hold) => LoopEnd::Hold,
+            Some(OnFinish::Return) => match self.previous.take() {
+                Some(tag) => LoopEnd::Play(Clip { tag, once: None }),
+                None => LoopEnd::Hold,
+            },
+            None => {
+                // Switch to a state that was waiting for this loop to end.
+                if let Some(sm) = self.state_machine.as_mut() {
+                    if sm.finish_loop() {
+                        if let Ok(tag) = sm.tag() {
+                            return LoopEnd::Play(Clip {
+                                tag: tag.clone(),
+                                once: None,
+                            });
+                        }
+                    }
+                }
+                LoopEnd::Step
             }
-            Err(e) => Err(e),
         }
     }
+
+    // Moves `elapsed` further through `frames`, the frames of the instance's sheet.
+    fn advance(&mut self, frames: &[FrameInfo], elapsed: Duration) {
+        let frame = self.frame_i;
+        if !self.paused {
+            self.time += elapsed.mul_f64(self.speed.max(0.0));
+        }
+        // A held one-shot stays on its last frame until something is queued.
+        let holding = self.once == Some(OnFinish::Hold) && self.finished && self.queue.is_empty();
+        let duration = Duration::from_millis(frames[frame].duration as u64);
+        if !holding && self.time >= duration {
+            let loop_ends = match &self.tag {
+                Some(tag) => tag.loop_ends(frame, self.reversed),
+                None => frame + 1 >= frames.len(),
+            };
+            let next = if loop_ends {
+                self.end_of_loop()
+            } else {
+                LoopEnd::Step
+            };
+            match next {
+                LoopEnd::Play(clip) => self.start(clip),
+                LoopEnd::Hold => self.time = Duration::ZERO,
+                LoopEnd::Step => {
+                    self.frame_i = match &self.tag {
+                        Some(tag) => tag.next_frame(frame, &mut self.reversed),
+                        None => frame + 1,
+                    };
+                    // Keep the remainder so frames last as long as authored
+                    // even when ticks do not divide their duration.
+                    self.time -= duration;
+                    self.entered = true;
+                }
+            }
+            self.frame_i %= frames.len();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Frames {
+    // One texture array layer per frame, drawn by the Renderer.
+    Gpu(Texture2dArray),
+    // Decoded in memory, drawn by the SoftwareRenderer.
+    Cpu(Vec<RgbaImage>),
 }
 
 #[derive(Debug)]
 pub struct Sprite {
-    pub texture: Texture2dArray,
+    pub texture: Frames,
+    // Normal map frames laid out like the texture's, only loaded by the Renderer.
+    pub normals: Option<Texture2dArray>,
     pub info: TextureInfo,
 }
 
 impl Sprite {
     pub fn sampler(&self) -> Sampler<Texture2dArray> {
-        self.texture
+        let texture = match &self.texture {
+            Frames::Gpu(texture) => texture,
+            Frames::Cpu(_) => panic!(
+                "Sprite \"{}\" was loaded without a GPU texture",
+                self.info.name
+            ),
+        };
+        texture
             .sampled()
             .minify_filter(MinifySamplerFilter::Nearest)
             .magnify_filter(MagnifySamplerFilter::Nearest)
             .wrap_function(SamplerWrapFunction::Clamp)
     }
+
+    // Width and height of every frame, in pixels.
+    pub fn frame_size(&self) -> (u32, u32) {
+        match &self.texture {
+            Frames::Gpu(texture) => texture.dimensions(),
+            Frames::Cpu(frames) => frames.first().map_or((0, 0), |f| f.dimensions()),
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        match &self.texture {
+            Frames::Gpu(texture) => texture.array_size() as usize,
+            Frames::Cpu(frames) => frames.len(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -111,20 +264,23 @@ impl<'a> From<&'a Sprite> for SpriteState {
             tag: None,
             frame_i: 0,
             time: Duration::ZERO,
-            state: InputState::Idle,
+            reversed: false,
+            entered: true,
             texture: 0,
             state_machine: None,
+            speed: 1.0,
+            paused: false,
+            once: None,
+            previous: None,
+            finished: false,
+            queue: VecDeque::new(),
         };
-        if let Some(tags) = sprite
-            .info
-            .json
-            .as_ref()
-            .map(|json| json.meta.frame_tags.clone())
+        if let (Some(json), Some(config)) = (sprite.info.json.as_ref(), sprite.info.states.as_ref())
         {
-            let sm = StateMachine::<InputState> {
-                tags: tags.to_vec(),
-                _t: PhantomData,
-            };
+            let sm = StateMachine::new(config.clone(), json.meta.frame_tags.clone());
+            if let Ok(tag) = sm.tag() {
+                state.play(tag.clone());
+            }
             state.state_machine.replace(sm);
         }
         state
@@ -138,17 +294,87 @@ impl<'a> From<&'static str> for SpriteState {
             tag: None,
             frame_i: 0,
             time: Duration::ZERO,
-            state: InputState::Idle,
+            reversed: false,
+            entered: true,
             texture: 0,
             state_machine: None,
+            speed: 1.0,
+            paused: false,
+            once: None,
+            previous: None,
+            finished: false,
+            queue: VecDeque::new(),
         }
     }
 }
 
+/**
+ * A SpriteHandle pointing at an instance that was released.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadHandle {
+    pub index: SpriteIndex,
+    pub generation: u32,
+}
+
+impl Display for DeadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Sprite handle {} (generation {}) was released",
+            self.index, self.generation
+        )
+    }
+}
+
+impl std::error::Error for DeadHandle {}
+
+// An instance slot, the generation is bumped every time the slot is released.
+#[derive(Debug)]
+struct Slot {
+    generation: u32,
+    state: Option<SpriteState>,
+}
+
+fn live<'s>(slots: &'s [Slot], handle: &SpriteHandle) -> Result<&'s SpriteState, DeadHandle> {
+    slots
+        .get(handle.index)
+        .filter(|slot| slot.generation == handle.generation)
+        .and_then(|slot| slot.state.as_ref())
+        .ok_or(DeadHandle {
+            index: handle.index,
+            generation: handle.generation,
+        })
+}
+
+fn live_mut<'s>(
+    slots: &'s mut [Slot],
+    handle: &SpriteHandle,
+) -> Result<&'s mut SpriteState, DeadHandle> {
+    slots
+        .get_mut(handle.index)
+        .filter(|slot| slot.generation == handle.generation)
+        .and_then(|slot| slot.state.as_mut())
+        .ok_or(DeadHandle {
+            index: handle.index,
+            generation: handle.generation,
+        })
+}
+
+lazy_static! {
+    // Sprite names only known at runtime, see SpriteManager::intern.
+    static ref NAMES: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
 pub struct SpriteManager {
     sprites: HashMap<String, Sprite>,
     pub loaded_textures: Vec<Sprite>,
-    pub instances: Vec<SpriteState>,
+    instances: Vec<Slot>,
+    // Released slots, reused by `init`.
+    free: Vec<SpriteIndex>,
+    // Handle owned by each entity, kept to release it once the entity is deleted.
+    owners: HashMap<Index, (SpriteIndex, u32)>,
+    handle_events: Option<ReaderId<ComponentEvent>>,
 }
 
 pub struct SpriteQuery {
@@ -163,8 +389,25 @@ impl SpriteManager {
             sprites: HashMap::new(),
             loaded_textures: Vec::new(),
             instances: Vec::new(),
+            free: Vec::new(),
+            owners: HashMap::new(),
+            handle_events: None,
+        }
+    }
+    /**
+     * A name for a sprite only known at runtime, ie. the image of a map's tileset.
+     * Each distinct name is leaked once, loading the same map again reuses it.
+     */
+    pub fn intern(name: String) -> &'static str {
+        let mut names = NAMES.lock().unwrap();
+        if let Some(name) = names.get(name.as_str()) {
+            return name;
         }
+        let name = Box::leak(name.into_boxed_str());
+        names.insert(name);
+        name
     }
+
     pub fn add(&mut self, sprite: Sprite) {
         self.sprites.insert(sprite.info.name.to_string(), sprite);
         // self.loaded_textures.push(sprite);
@@ -176,52 +419,288 @@ impl SpriteManager {
         }
     }
 
-    pub fn signal(&mut self, handle: &SpriteHandle, signal: Option<&InputState>) {
-        if let Some(input_state) = signal {
-            let state = &mut self.instances[handle.index];
-            state.ack(*input_state).unwrap();
+    // Feeds components of the entity to the instance's animation state machine, if it has one.
+    pub fn signal<T: AnimationInput + ?Sized>(
+        &mut self,
+        handle: &SpriteHandle,
+        signal: &T,
+    ) -> Result<()> {
+        let state = live_mut(&mut self.instances, handle)?;
+        if state.state_machine.is_some() {
+            // Tags of the states were checked against the sheet when it was loaded,
+            // a machine failing anyway is dropped so the error is only returned once.
+            let sprite = state.sprite;
+            if let Err(e) = state.ack(signal) {
+                let error = eyre!("Sprite \"{}\": {}", sprite, e);
+                state.state_machine = None;
+                return Err(error);
+            }
         }
+        Ok(())
+    }
+
+    pub fn state(&self, handle: &SpriteHandle) -> Result<&SpriteState, DeadHandle> {
+        live(&self.instances, handle)
     }
 
-    pub fn next_frame(&mut self, handle: &SpriteHandle, elapsed: Duration) -> (&Sprite, usize) {
-        let state = &mut self.instances[handle.index];
+    pub fn state_mut(&mut self, handle: &SpriteHandle) -> Result<&mut SpriteState, DeadHandle> {
+        live_mut(&mut self.instances, handle)
+    }
+
+    /**
+     * Advances the animation of `handle` by `elapsed`.
+     * Events declared on a newly entered frame are published to `events`.
+     */
+    pub fn next_frame(
+        &mut self,
+        entity: Entity,
+        handle: &SpriteHandle,
+        elapsed: Duration,
+        events: &mut EventChannel<AnimationEvent>,
+    ) -> Result<(&Sprite, usize), DeadHandle> {
+        let state = live_mut(&mut self.instances, handle)?;
         let sprite = self.sprites.get_mut(state.sprite).unwrap();
-        let frame = state.frame_i;
         let json = sprite.info.json.as_ref().unwrap();
-        let frame_info = &json.frames[frame];
-        let (from, to) = state
-            .tag
-            .as_ref()
-            .map(|t| (t.from, t.to))
-            .unwrap_or((0, json.frames.len()));
-        state.time += elapsed;
-        if state.time.as_millis() > frame_info.duration {
-            state.frame_i += 1;
-            if state.frame_i >= to {
-                state.frame_i = from;
+        state.advance(&json.frames, elapsed);
+        if state.entered {
+            state.entered = false;
+            let frame = state.frame_i;
+            let tag = state.tag.as_ref().map(|t| t.name.clone());
+            for name in json.frame_events(frame) {
+                events.single_write(AnimationEvent {
+                    entity,
+                    sprite: state.sprite,
+                    tag: tag.clone(),
+                    frame,
+                    name: name.to_string(),
+                });
             }
-            state.frame_i %= json.frames.len();
-            state.time = Duration::ZERO;
         }
-        (sprite, state.frame_i)
+        Ok((sprite, state.frame_i))
+    }
+
+    // Looks up the tag called `name` in the sheet of the instance.
+    fn clip(&self, handle: &SpriteHandle, name: &str, once: Option<OnFinish>) -> Result<Clip> {
+        let state = live(&self.instances, handle)?;
+        let tag = self
+            .sprites
+            .get(state.sprite)
+            .and_then(|sprite| sprite.info.json.as_ref())
+            .and_then(|json| json.meta.frame_tags.iter().find(|tag| tag.name == name))
+            .ok_or_else(|| eyre!("Sprite \"{}\" has no tag \"{}\"", state.sprite, name))?;
+        Ok(Clip {
+            tag: tag.clone(),
+            once,
+        })
     }
 
-    // Creates a sprite handle.
+    // Loops the tag `name` right away, dropping any queued clips.
+    pub fn play(&mut self, handle: &SpriteHandle, name: &str) -> Result<()> {
+        let clip = self.clip(handle, name, None)?;
+        let state = live_mut(&mut self.instances, handle)?;
+        state.queue.clear();
+        state.start(clip);
+        Ok(())
+    }
+
+    /**
+     * Plays the tag `name` once, then holds its last frame or returns
+     * to the tag that was playing before, depending on `then`.
+     * The state machine does not interrupt a one-shot.
+     */
+    pub fn play_once(&mut self, handle: &SpriteHandle, name: &str, then: OnFinish) -> Result<()> {
+        let clip = self.clip(handle, name, Some(then))?;
+        let state = live_mut(&mut self.instances, handle)?;
+        state.queue.clear();
+        state.start(clip);
+        Ok(())
+    }
+
+    // Plays the tag `name` once the current one ends its loop, looping it when `once` is None.
+    pub fn queue(
+        &mut self,
+        handle: &SpriteHandle,
+        name: &str,
+        once: Option<OnFinish>,
+    ) -> Result<()> {
+        let clip = self.clip(handle, name, once)?;
+        live_mut(&mut self.instances, handle)?.queue.push_back(clip);
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, handle: &SpriteHandle, speed: f64) -> Result<(), DeadHandle> {
+        live_mut(&mut self.instances, handle)?.speed = speed;
+        Ok(())
+    }
+
+    pub fn pause(&mut self, handle: &SpriteHandle) -> Result<(), DeadHandle> {
+        live_mut(&mut self.instances, handle)?.paused = true;
+        Ok(())
+    }
+
+    pub fn resume(&mut self, handle: &SpriteHandle) -> Result<(), DeadHandle> {
+        live_mut(&mut self.instances, handle)?.paused = false;
+        Ok(())
+    }
+
+    // True once the last one-shot played its final frame, even if it returned since.
+    pub fn is_finished(&self, handle: &SpriteHandle) -> Result<bool, DeadHandle> {
+        Ok(live(&self.instances, handle)?.finished)
+    }
+
+    // Loaded sprite called `name`.
+    pub fn get(&self, name: &str) -> Option<&Sprite> {
+        self.sprites.get(name)
+    }
+
+    pub fn sprite(&self, handle: &SpriteHandle) -> Result<&Sprite, DeadHandle> {
+        let state = live(&self.instances, handle)?;
+        Ok(self.sprites.get(state.sprite).unwrap())
+    }
+
+    // Key of the slice called `name` on the instance's current frame.
+    pub fn slice(&self, handle: &SpriteHandle, name: &str) -> Option<&SliceKey> {
+        let state = live(&self.instances, handle).ok()?;
+        let json = self.sprites.get(state.sprite)?.info.json.as_ref()?;
+        json.meta
+            .slices
+            .iter()
+            .find(|slice| slice.name == name)?
+            .key(state.frame_i)
+    }
+
+    /**
+     * Sprite origin in pixels, set by a slice named "pivot".
+     * Uses the slice's pivot point when it has one, otherwise its top left corner.
+     */
+    pub fn pivot(&self, handle: &SpriteHandle) -> (i32, i32) {
+        self.slice(handle, "pivot")
+            .map(|key| {
+                let (x, y) = (key.bounds.x, key.bounds.y);
+                key.pivot.map_or((x, y), |p| (x + p.x, y + p.y))
+            })
+            .unwrap_or((0, 0))
+    }
+
+    // Pixel to world scale of an instance drawn at `size`.
+    fn scale(handle: &SpriteHandle, size: &Size) -> (f32, f32) {
+        (
+            size.0 as f32 / handle.width as f32,
+            size.1 as f32 / handle.height as f32,
+        )
+    }
+
+    // Where the top left corner of the sprite is drawn, relative to the entity position.
+    pub fn origin(&self, handle: &SpriteHandle, size: &Size) -> (i32, i32) {
+        let (sx, sy) = Self::scale(handle, size);
+        let (px, py) = self.pivot(handle);
+        (-(px as f32 * sx) as i32, -(py as f32 * sy) as i32)
+    }
+
+    // Slice `name` on the current frame, in world units relative to the entity position.
+    pub fn slice_rect(&self, handle: &SpriteHandle, size: &Size, name: &str) -> Option<Rect> {
+        let bounds = self.slice(handle, name)?.bounds;
+        let (sx, sy) = Self::scale(handle, size);
+        let (ox, oy) = self.origin(handle, size);
+        Some(Rect::new(
+            ox + (bounds.x as f32 * sx) as i32,
+            oy + (bounds.y as f32 * sy) as i32,
+            (bounds.w as f32 * sx) as u32,
+            (bounds.h as f32 * sy) as u32,
+        ))
+    }
+
+    // Creates a sprite handle, reusing a released slot when there is one.
     pub fn init(&mut self, name: &'static str) -> SpriteHandle {
         let sprite = self.sprites.get(name).unwrap();
         let state = SpriteState::from(sprite);
         let SpriteQuery {
             size: (width, height),
         } = self.query(&state);
-        self.instances.push(state);
+        let (index, generation) = self.insert(state);
         SpriteHandle {
-            index: self.instances.len() - 1,
+            index,
+            generation,
             width,
             height,
             scale: 2,
         }
     }
 
+    // Puts `state` in a released slot, or a new one, and returns the slot's index and generation.
+    fn insert(&mut self, state: SpriteState) -> (SpriteIndex, u32) {
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.instances[index].state = Some(state);
+                index
+            }
+            None => {
+                self.instances.push(Slot {
+                    generation: 0,
+                    state: Some(state),
+                });
+                self.instances.len() - 1
+            }
+        };
+        (index, self.instances[index].generation)
+    }
+
+    // Frees the instance of `handle`, the handle and any copy of it is dead afterwards.
+    pub fn release(&mut self, handle: &SpriteHandle) -> Result<(), DeadHandle> {
+        live(&self.instances, handle)?;
+        self.release_slot(handle.index);
+        Ok(())
+    }
+
+    fn release_slot(&mut self, index: SpriteIndex) {
+        let slot = &mut self.instances[index];
+        slot.state = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+    }
+
+    // Starts listening to SpriteHandle storage events, see `release_deleted`.
+    pub fn track(&mut self, world: &mut World) {
+        let reader = world.write_storage::<SpriteHandle>().register_reader();
+        self.handle_events.replace(reader);
+    }
+
+    /**
+     * Releases the handles of deleted entities (or of removed/replaced SpriteHandle components).
+     * Should be called every frame once `track` was called, or storage events pile up.
+     */
+    pub fn release_deleted(&mut self, entities: &Entities, handles: &ReadStorage<SpriteHandle>) {
+        let events: Vec<ComponentEvent> = match self.handle_events.as_mut() {
+            Some(reader) => handles.channel().read(reader).copied().collect(),
+            None => return,
+        };
+        for event in events {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    if let Some(handle) = handles.get(entities.entity(id)) {
+                        let owned = (handle.index, handle.generation);
+                        match self.owners.insert(id, owned) {
+                            Some(old) if old != owned => self.release_owned(old),
+                            _ => {}
+                        }
+                    }
+                }
+                ComponentEvent::Removed(id) => {
+                    if let Some(old) = self.owners.remove(&id) {
+                        self.release_owned(old);
+                    }
+                }
+            }
+        }
+    }
+
+    // Handles may have been released by hand already.
+    fn release_owned(&mut self, (index, generation): (SpriteIndex, u32)) {
+        if self.instances[index].generation == generation {
+            self.release_slot(index);
+        }
+    }
+
     pub fn query(&mut self, state: &SpriteState) -> SpriteQuery {
         let sprite = &self.sprites.get(state.sprite).unwrap();
         let json = sprite.info.json.as_ref().unwrap();
@@ -231,3 +710,160 @@ impl SpriteManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aseprite::{SpriteRect, SpriteSheetSize};
+
+    // A handle to a new instance of `sprite`, without loading the sprite itself.
+    fn handle(manager: &mut SpriteManager, sprite: &'static str) -> SpriteHandle {
+        let (index, generation) = manager.insert(SpriteState::from(sprite));
+        SpriteHandle {
+            index,
+            generation,
+            width: 18,
+            height: 18,
+            scale: 2,
+        }
+    }
+
+    #[test]
+    fn deleting_an_entity_frees_its_slot() {
+        let mut world = World::new();
+        world.register::<SpriteHandle>();
+        let mut manager = SpriteManager::new();
+        manager.track(&mut world);
+
+        let first = handle(&mut manager, "chicken");
+        let stale = SpriteHandle { ..first };
+        let entity = world.create_entity().with(first).build();
+        manager.release_deleted(&world.entities(), &world.read_storage());
+        assert!(manager.state(&stale).is_ok());
+
+        world.delete_entity(entity).unwrap();
+        world.maintain();
+        manager.release_deleted(&world.entities(), &world.read_storage());
+        assert_eq!(manager.free, [stale.index]);
+
+        // The slot is reused, the old handle stays dead.
+        let second = handle(&mut manager, "chicken");
+        assert_eq!(second.index, stale.index);
+        assert!(manager.state(&second).is_ok());
+        let error = manager.state(&stale).unwrap_err();
+        assert_eq!(error.generation, stale.generation);
+        assert!(manager.release(&stale).is_err());
+    }
+
+    fn tag(name: &str, from: usize, to: usize) -> Tags {
+        Tags {
+            name: name.to_string(),
+            from,
+            to,
+            direction: Default::default(),
+        }
+    }
+
+    // Four frames of 100ms, walking on the first two and jumping on the last two.
+    fn frames() -> Vec<FrameInfo> {
+        let rect = SpriteRect {
+            x: 0,
+            y: 0,
+            w: 1,
+            h: 1,
+        };
+        let frame = FrameInfo {
+            filename: String::new(),
+            frame: rect,
+            rotated: false,
+            trimmed: false,
+            sprite_source_size: rect,
+            duration: 100,
+            source_size: SpriteSheetSize { w: 1, h: 1 },
+        };
+        vec![frame; 4]
+    }
+
+    fn walking() -> SpriteState {
+        let mut state = SpriteState::from("chicken");
+        state.start(Clip {
+            tag: tag("walk", 0, 1),
+            once: None,
+        });
+        state
+    }
+
+    // The frame shown after each of `count` steps a bit longer than a frame.
+    fn steps(state: &mut SpriteState, count: usize) -> Vec<usize> {
+        let frames = frames();
+        (0..count)
+            .map(|_| {
+                state.advance(&frames, Duration::from_millis(101));
+                state.frame_i
+            })
+            .collect()
+    }
+
+    #[test]
+    fn speed_scales_and_pause_stops_time() {
+        let mut state = walking();
+        state.speed = 0.5;
+        assert_eq!(steps(&mut state, 4), [0, 1, 1, 0]);
+        state.paused = true;
+        assert_eq!(steps(&mut state, 3), [0, 0, 0]);
+        state.paused = false;
+        assert_eq!(steps(&mut state, 2), [0, 1]);
+    }
+
+    #[test]
+    fn one_shots_hold_or_return() {
+        let mut state = walking();
+        state.start(Clip {
+            tag: tag("jump", 2, 3),
+            once: Some(OnFinish::Hold),
+        });
+        assert_eq!(steps(&mut state, 3), [3, 3, 3]);
+        assert!(state.finished);
+
+        let mut state = walking();
+        state.start(Clip {
+            tag: tag("jump", 2, 3),
+            once: Some(OnFinish::Return),
+        });
+        assert!(!state.finished);
+        assert_eq!(steps(&mut state, 3), [3, 0, 1]);
+        assert!(state.finished);
+        assert_eq!(state.tag.as_ref().unwrap().name, "walk");
+    }
+
+    #[test]
+    fn one_shots_return_to_what_replaced_a_returning_one() {
+        let mut state = walking();
+        state.start(Clip {
+            tag: tag("jump", 2, 3),
+            once: Some(OnFinish::Return),
+        });
+        state.start(Clip {
+            tag: tag("land", 3, 3),
+            once: Some(OnFinish::Hold),
+        });
+        assert_eq!(steps(&mut state, 2), [3, 3]);
+        state.start(Clip {
+            tag: tag("jump", 2, 3),
+            once: Some(OnFinish::Return),
+        });
+        assert_eq!(steps(&mut state, 2), [3, 3]);
+        assert_eq!(state.tag.as_ref().unwrap().name, "land");
+    }
+
+    #[test]
+    fn queued_clips_wait_for_the_end_of_the_loop() {
+        let mut state = walking();
+        state.queue.push_back(Clip {
+            tag: tag("jump", 2, 3),
+            once: None,
+        });
+        assert_eq!(steps(&mut state, 5), [1, 2, 3, 2, 3]);
+        assert!(state.queue.is_empty());
+    }
+}
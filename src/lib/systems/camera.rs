@@ -0,0 +1,213 @@
+use crate::lib::systems::components::{Position, Size};
+use crate::lib::systems::debug::{self, DebugDraw};
+use crate::lib::systems::screen::Screen;
+use sdl2::rect::Rect;
+use specs::prelude::*;
+use specs::{Entity, ReadStorage, System};
+
+/**
+ * The view into the world, inserted as a resource.
+ * `position` is the world point at the center of the screen,
+ * it only moves on ticks so it is extrapolated like sprites for display.
+ * The camera draws to the whole virtual resolution of the Screen.
+ */
+#[derive(Debug, Clone)]
+pub struct Camera {
+    pub position: (f32, f32),
+    // Where `position` was on the tick before, to extrapolate from.
+    pub previous: (f32, f32),
+    // Screen pixels per world unit.
+    pub zoom: f32,
+    pub target: Option<Entity>,
+    // Fraction of the distance to the target covered every tick, 1.0 snaps to it.
+    pub smoothing: f32,
+    // Half extents of the box around the center the target moves in freely, in world units.
+    pub dead_zone: (f32, f32),
+    // The view never shows anything outside of these, in world units.
+    pub bounds: Option<Rect>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: (400.0, 300.0),
+            previous: (400.0, 300.0),
+            zoom: 1.0,
+            target: None,
+            smoothing: 1.0,
+            dead_zone: (0.0, 0.0),
+            bounds: None,
+        }
+    }
+}
+
+impl Camera {
+    // Size of the visible world area.
+    pub fn view_size(&self, screen: &Screen) -> (f32, f32) {
+        let (w, h) = screen.resolution;
+        (w as f32 / self.zoom, h as f32 / self.zoom)
+    }
+
+    // Center of the view `alpha` of the way to the next tick.
+    pub fn interpolated(&self, alpha: f64) -> (f32, f32) {
+        let alpha = alpha as f32;
+        let (x, y) = self.position;
+        let (px, py) = self.previous;
+        (x + (x - px) * alpha, y + (y - py) * alpha)
+    }
+
+    pub fn view_projection(&self, screen: &Screen, alpha: f64) -> glm::Mat4x4 {
+        let (x, y) = self.interpolated(alpha);
+        let (w, h) = self.view_size(screen);
+        let (left, top) = (x - w / 2.0, y - h / 2.0);
+        glm::ortho(left, left + w, top + h, top, -1.0, 1.0)
+    }
+
+    // World area on screen `alpha` of the way to the next tick.
+    pub fn visible(&self, screen: &Screen, alpha: f64) -> Rect {
+        let (x, y) = self.interpolated(alpha);
+        let (w, h) = self.view_size(screen);
+        Rect::new(
+            (x - w / 2.0).floor() as i32,
+            (y - h / 2.0).floor() as i32,
+            w.ceil() as u32 + 1,
+            h.ceil() as u32 + 1,
+        )
+    }
+
+    // Maps a point at the virtual resolution into the world, as drawn `alpha` into the tick.
+    pub fn screen_to_world(&self, screen: &Screen, (sx, sy): (f32, f32), alpha: f64) -> (f32, f32) {
+        let (x, y) = self.interpolated(alpha);
+        let (w, h) = screen.resolution;
+        (
+            x + (sx - w as f32 / 2.0) / self.zoom,
+            y + (sy - h as f32 / 2.0) / self.zoom,
+        )
+    }
+
+    // Moves toward `point`, once it leaves the dead zone. Called once per tick.
+    pub fn follow(&mut self, (tx, ty): (f32, f32)) {
+        let (x, y) = self.position;
+        let outside = |d: f32, zone: f32| {
+            if d > zone {
+                d - zone
+            } else if d < -zone {
+                d + zone
+            } else {
+                0.0
+            }
+        };
+        let dx = outside(tx - x, self.dead_zone.0);
+        let dy = outside(ty - y, self.dead_zone.1);
+        let t = self.smoothing.clamp(0.0, 1.0);
+        self.position = (x + dx * t, y + dy * t);
+    }
+
+    // Keeps the view inside `bounds`, centering it on axes where the bounds are smaller.
+    pub fn clamp(&mut self, screen: &Screen) {
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let (w, h) = self.view_size(screen);
+        let axis = |center: f32, min: i32, len: u32, view: f32| {
+            let (min, max) = (min as f32, min as f32 + len as f32);
+            if view >= max - min {
+                (min + max) / 2.0
+            } else {
+                center.clamp(min + view / 2.0, max - view / 2.0)
+            }
+        };
+        self.position = (
+            axis(self.position.0, bounds.x(), bounds.width(), w),
+            axis(self.position.1, bounds.y(), bounds.height(), h),
+        );
+    }
+}
+
+/**
+ * Moves the Camera resource after its target, run on every tick.
+ * The DebugDraw shows its dead zone, the center of the view and the point it follows.
+ */
+#[derive(Default)]
+pub struct CameraSystem {}
+
+impl<'a> System<'a> for CameraSystem {
+    type SystemData = (
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+        Write<'a, Camera>,
+        Read<'a, Screen>,
+        Write<'a, DebugDraw>,
+    );
+    fn run(&mut self, (position, size, mut camera, screen, mut debug_draw): Self::SystemData) {
+        camera.previous = camera.position;
+        let mut followed = None;
+        if let Some(e) = camera.target {
+            if let Some(pos) = position.get(e) {
+                let (w, h) = size.get(e).map_or((0, 0), |s| (s.0, s.1));
+                let point = (pos.0 as f32 + w as f32 / 2.0, pos.1 as f32 + h as f32 / 2.0);
+                camera.follow(point);
+                followed = Some(point);
+            }
+        }
+        camera.clamp(&screen);
+
+        let (x, y) = camera.position;
+        let (zw, zh) = camera.dead_zone;
+        let zone = Rect::new(
+            (x - zw) as i32,
+            (y - zh) as i32,
+            (zw * 2.0) as u32,
+            (zh * 2.0) as u32,
+        );
+        debug_draw.rect(zone, debug::CAMERA);
+        let label = (zone.x() as f32, zone.y() as f32 - debug::TEXT_SIZE - 2.0);
+        debug_draw.text(label, "CAMERA", debug::CAMERA);
+        debug_draw.line((x - 4.0, y), (x + 4.0, y), debug::CAMERA);
+        debug_draw.line((x, y - 4.0), (x, y + 4.0), debug::CAMERA);
+        if let Some(point) = followed {
+            debug_draw.arrow((x, y), point, debug::CAMERA);
+            debug_draw.circle(point, 3.0, debug::CAMERA);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_what_leaves_the_dead_zone() {
+        let mut camera = Camera {
+            position: (100.0, 100.0),
+            smoothing: 0.5,
+            dead_zone: (20.0, 10.0),
+            ..Default::default()
+        };
+        camera.follow((115.0, 95.0));
+        assert_eq!(camera.position, (100.0, 100.0));
+        // 20 and 10 units out of the zone, half of the way is covered.
+        camera.follow((140.0, 80.0));
+        assert_eq!(camera.position, (110.0, 95.0));
+    }
+
+    #[test]
+    fn stays_in_its_bounds() {
+        let screen = Screen {
+            resolution: (200, 100),
+            ..Default::default()
+        };
+        let mut camera = Camera {
+            position: (0.0, 0.0),
+            bounds: Some(Rect::new(0, 0, 1000, 50)),
+            ..Default::default()
+        };
+        camera.clamp(&screen);
+        // Bounds shorter than the view are centered on.
+        assert_eq!(camera.position, (100.0, 25.0));
+        camera.position = (2000.0, 25.0);
+        camera.clamp(&screen);
+        assert_eq!(camera.position, (900.0, 25.0));
+    }
+}
@@ -0,0 +1,293 @@
+use crate::lib::opengl::SDL2Facade;
+
+use glium::backend::Facade;
+use glium::texture::RawImage2d;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Where captures go when no path is given.
+pub const CAPTURE_DIR: &str = "captures";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    // A looping animated GIF.
+    Gif,
+    // A directory of numbered PNGs, ie. frame-0001.png.
+    PngSequence,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub format: RecordFormat,
+    // The .gif file or the directory of the PNG sequence.
+    pub path: String,
+    pub seconds: f32,
+    pub fps: u32,
+}
+
+impl Recording {
+    // A recording to a timestamped path in CAPTURE_DIR.
+    pub fn new(format: RecordFormat, seconds: f32) -> Self {
+        let (path, fps) = match format {
+            // GIF delays are in hundredths of a second, 25 fps is exactly 4.
+            RecordFormat::Gif => (default_path("recording", ".gif"), 25),
+            RecordFormat::PngSequence => (default_path("recording", ""), 30),
+        };
+        Self {
+            format,
+            path,
+            seconds,
+            fps,
+        }
+    }
+}
+
+fn default_path(kind: &str, extension: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}/{}-{}{}", CAPTURE_DIR, kind, now.as_millis(), extension)
+}
+
+/**
+ * Captures of the window requested by the game, ie. from a hotkey.
+ * The Renderer takes them after presenting a frame, the images are then
+ * encoded and written on a separate thread so the game loop doesn't stall.
+ */
+#[derive(Debug, Default)]
+pub struct Capture {
+    screenshots: Vec<String>,
+    start: Option<Recording>,
+    stop: bool,
+    recording: bool,
+}
+
+impl Capture {
+    // Saves the next frame as a PNG in CAPTURE_DIR, returns its path.
+    pub fn screenshot(&mut self) -> String {
+        let path = default_path("screenshot", ".png");
+        self.screenshots.push(path.clone());
+        path
+    }
+
+    pub fn screenshot_to(&mut self, path: &str) {
+        self.screenshots.push(path.to_string());
+    }
+
+    // Starts recording from the next frame, replacing the current recording.
+    pub fn record(&mut self, recording: Recording) {
+        self.start = Some(recording);
+        self.stop = false;
+    }
+
+    // Ends the current recording early.
+    pub fn stop(&mut self) {
+        self.start = None;
+        self.stop = true;
+    }
+
+    pub fn toggle(&mut self, format: RecordFormat, seconds: f32) {
+        if self.is_recording() {
+            self.stop();
+        } else {
+            self.record(Recording::new(format, seconds));
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        (self.recording && !self.stop) || self.start.is_some()
+    }
+}
+
+enum Job {
+    Screenshot(RgbaImage, String),
+    Begin(Recording),
+    Frame(RgbaImage),
+    End,
+}
+
+enum Sink {
+    Gif(GifEncoder<BufWriter<File>>, Delay),
+    Png(String, u32),
+}
+
+fn create_dir_for(path: &str) {
+    if let Some(dir) = Path::new(path).parent() {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            eprintln!("Cannot create {}: {}", dir.display(), e);
+        }
+    }
+}
+
+fn begin(recording: &Recording) -> Option<Sink> {
+    match recording.format {
+        RecordFormat::Gif => {
+            create_dir_for(&recording.path);
+            let file = match File::create(&recording.path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Cannot record to {}: {}", recording.path, e);
+                    return None;
+                }
+            };
+            // Faster quantization than the default, at a small cost in colors.
+            let mut encoder = GifEncoder::new_with_speed(BufWriter::new(file), 10);
+            encoder.set_repeat(Repeat::Infinite).ok()?;
+            let delay = Delay::from_numer_denom_ms(1000, recording.fps.max(1));
+            Some(Sink::Gif(encoder, delay))
+        }
+        RecordFormat::PngSequence => match std::fs::create_dir_all(&recording.path) {
+            Ok(()) => Some(Sink::Png(recording.path.clone(), 0)),
+            Err(e) => {
+                eprintln!("Cannot record to {}: {}", recording.path, e);
+                None
+            }
+        },
+    }
+}
+
+// Runs on the encoder thread until the Recorder is dropped.
+fn encode(jobs: Receiver<Job>) {
+    let mut sink: Option<Sink> = None;
+    for job in jobs {
+        match job {
+            Job::Screenshot(image, path) => {
+                create_dir_for(&path);
+                match image.save(&path) {
+                    Ok(()) => println!("Saved {}", path),
+                    Err(e) => eprintln!("Cannot save {}: {}", path, e),
+                }
+            }
+            Job::Begin(recording) => {
+                sink = begin(&recording);
+                if sink.is_some() {
+                    println!("Recording to {}", recording.path);
+                }
+            }
+            Job::Frame(image) => match &mut sink {
+                Some(Sink::Gif(encoder, delay)) => {
+                    let frame = Frame::from_parts(image, 0, 0, *delay);
+                    if let Err(e) = encoder.encode_frame(frame) {
+                        eprintln!("Cannot encode GIF frame: {}", e);
+                        sink = None;
+                    }
+                }
+                Some(Sink::Png(dir, index)) => {
+                    *index += 1;
+                    let path = format!("{}/frame-{:04}.png", dir, index);
+                    if let Err(e) = image.save(&path) {
+                        eprintln!("Cannot save {}: {}", path, e);
+                    }
+                }
+                None => {}
+            },
+            // Dropping the encoder writes the GIF trailer.
+            Job::End => {
+                if sink.take().is_some() {
+                    println!("Recording done");
+                }
+            }
+        }
+    }
+}
+
+// The last presented frame, read back from the window.
+fn read_window(window: &SDL2Facade) -> Option<RgbaImage> {
+    let raw: RawImage2d<u8> = match window.get_context().read_front_buffer() {
+        Ok(raw) => raw,
+        Err(e) => {
+            eprintln!("Cannot read the window: {:?}", e);
+            return None;
+        }
+    };
+    let image = RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned())?;
+    // GL rows go bottom to top.
+    Some(image::imageops::flip_vertical(&image))
+}
+
+/**
+ * Renderer side of Capture: reads frames back from the window
+ * and hands them to the encoder thread, started on the first capture.
+ */
+#[derive(Default)]
+pub struct Recorder {
+    jobs: Option<Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+    // The recording in progress, when it started and the frames taken so far.
+    active: Option<(Recording, Instant, u64)>,
+}
+
+impl Recorder {
+    fn send(&mut self, job: Job) {
+        if self.jobs.is_none() {
+            let (sender, receiver) = channel();
+            self.worker = Some(std::thread::spawn(move || encode(receiver)));
+            self.jobs = Some(sender);
+        }
+        if self.jobs.as_ref().unwrap().send(job).is_err() {
+            eprintln!("The capture thread stopped");
+        }
+    }
+
+    // Takes the captures requested in `capture`, call after presenting a frame.
+    pub fn update(&mut self, window: &SDL2Facade, capture: &mut Capture) {
+        if capture.stop || capture.start.is_some() {
+            if self.active.take().is_some() {
+                self.send(Job::End);
+            }
+            capture.stop = false;
+        }
+        if let Some(recording) = capture.start.take() {
+            self.send(Job::Begin(recording.clone()));
+            self.active = Some((recording, Instant::now(), 0));
+        }
+
+        // Frames are taken at the recording's rate, skipping ahead when rendering is slower.
+        let mut record = false;
+        let mut finished = false;
+        if let Some((recording, start, taken)) = &mut self.active {
+            let elapsed = start.elapsed();
+            let due = (elapsed.as_secs_f64() * recording.fps as f64) as u64 + 1;
+            if *taken < due {
+                record = true;
+                *taken = due;
+            }
+            finished = elapsed >= Duration::from_secs_f32(recording.seconds.max(0.0));
+        }
+        if record || !capture.screenshots.is_empty() {
+            if let Some(image) = read_window(window) {
+                for path in capture.screenshots.drain(..) {
+                    self.send(Job::Screenshot(image.clone(), path));
+                }
+                if record {
+                    self.send(Job::Frame(image));
+                }
+            }
+        }
+        if finished {
+            self.active = None;
+            self.send(Job::End);
+        }
+        capture.recording = self.active.is_some();
+    }
+}
+
+impl Drop for Recorder {
+    // Lets the encoder thread finish writing before exiting.
+    fn drop(&mut self) {
+        if self.active.take().is_some() {
+            self.send(Job::End);
+        }
+        self.jobs = None;
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::systems::input::InputState;
-use specs::{Component, DenseVecStorage};
+use sdl2::rect::Rect;
+use specs::{Component, DenseVecStorage, FlaggedStorage};
 
 use crate::lib::sprite::SpriteIndex;
 
@@ -12,9 +13,13 @@ pub struct Position(pub i32, pub i32);
 #[derive(Component)]
 pub struct Size(pub i32, pub i32);
 
+// Flagged so the SpriteManager can release the instance when the entity is deleted.
 #[derive(Component)]
+#[storage(FlaggedStorage)]
 pub struct SpriteHandle {
     pub index: SpriteIndex,
+    // Must match the instance slot's generation, a released handle is dead.
+    pub generation: u32,
     pub width: usize,
     pub height: usize,
     pub scale: usize,
@@ -32,3 +37,35 @@ pub enum CollisionType {
 
 #[derive(Component)]
 pub struct Collision(pub Option<CollisionType>);
+
+// Boxes authored as "hitbox" / "hurtbox" slices for the current animation frame,
+// relative to the entity's Position. Updated on every tick as frames advance, before Physics.
+#[derive(Component, Default)]
+pub struct Hitbox(pub Option<Rect>);
+
+#[derive(Component, Default)]
+pub struct Hurtbox(pub Option<Rect>);
+
+// Color the sprite is multiplied by, white when absent.
+#[derive(Component)]
+pub struct Tint(pub [f32; 4]);
+
+// Named draw layers, drawn in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Layer {
+    Background,
+    #[default]
+    World,
+    Actors,
+    Foreground,
+    // Drawn in screen space, unaffected by the camera.
+    UI,
+}
+
+// Sprites without one are drawn on Layer::World.
+#[derive(Component)]
+pub struct RenderLayer(pub Layer);
+
+// Order within a layer, higher is drawn on top.
+#[derive(Component)]
+pub struct ZIndex(pub i32);
@@ -0,0 +1,378 @@
+use crate::lib::opengl::SDL2Facade;
+use crate::lib::sprite::Sprite;
+use crate::lib::systems::components::{Collision, CollisionType, Layer, Velocity};
+use crate::lib::systems::physics::collision_rect;
+use crate::lib::systems::renderer::{Draw, DrawKey, EntityData};
+use crate::lib::tilemap::Tilemap;
+
+use glium::implement_vertex;
+use glium::index::{NoIndices, PrimitiveType};
+use glium::uniform;
+use glium::{Blend, DrawParameters, Program, Surface, VertexBuffer};
+use sdl2::rect::Rect;
+use specs::prelude::*;
+
+pub type Color = [f32; 4];
+
+// Overlay colors.
+pub const DYNAMIC: Color = [0.2, 1.0, 0.3, 1.0];
+pub const STATIC: Color = [0.3, 0.6, 1.0, 1.0];
+pub const VELOCITY: Color = [1.0, 0.3, 0.9, 1.0];
+pub const BOUNDS: Color = [1.0, 0.9, 0.2, 0.6];
+pub const CAMERA: Color = [0.3, 0.9, 1.0, 0.8];
+
+// Velocities are in pixels per tick, arrows show how far an entity moves in this many ticks.
+const VELOCITY_SCALE: f32 = 4.0;
+// Height of a text label, glyphs are half as wide.
+pub const TEXT_SIZE: f32 = 8.0;
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Line((f32, f32), (f32, f32)),
+    Rect(Rect),
+    Circle((f32, f32), f32),
+    Arrow((f32, f32), (f32, f32)),
+    // Uppercase letters, digits and a little punctuation, from the top left corner.
+    Text((f32, f32), String),
+}
+
+/**
+ * Shapes drawn over the scene when debugging, in world coordinates.
+ * Any system can push shapes during a tick, they are cleared when the next tick starts
+ * and drawn as lines on top of every layer, after post processing.
+ * Nothing is kept while disabled.
+ */
+#[derive(Debug, Clone)]
+pub struct DebugDraw {
+    pub enabled: bool,
+    // Built in overlays: collision boxes colored by CollisionType, velocities
+    // and the quads sprites are drawn to.
+    pub collisions: bool,
+    pub velocities: bool,
+    pub sprite_bounds: bool,
+    shapes: Vec<(Shape, Color)>,
+}
+
+impl Default for DebugDraw {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            collisions: true,
+            velocities: true,
+            sprite_bounds: true,
+            shapes: vec![],
+        }
+    }
+}
+
+impl DebugDraw {
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.shapes.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.shapes.clear();
+    }
+
+    pub fn shapes(&self) -> &[(Shape, Color)] {
+        &self.shapes
+    }
+
+    pub fn push(&mut self, shape: Shape, color: Color) {
+        if self.enabled {
+            self.shapes.push((shape, color));
+        }
+    }
+
+    pub fn line(&mut self, from: (f32, f32), to: (f32, f32), color: Color) {
+        self.push(Shape::Line(from, to), color);
+    }
+
+    pub fn rect(&mut self, rect: Rect, color: Color) {
+        self.push(Shape::Rect(rect), color);
+    }
+
+    pub fn circle(&mut self, center: (f32, f32), radius: f32, color: Color) {
+        self.push(Shape::Circle(center, radius), color);
+    }
+
+    pub fn arrow(&mut self, from: (f32, f32), to: (f32, f32), color: Color) {
+        self.push(Shape::Arrow(from, to), color);
+    }
+
+    pub fn text(&mut self, position: (f32, f32), text: &str, color: Color) {
+        self.push(Shape::Text(position, text.to_string()), color);
+    }
+}
+
+fn collision_color(collision: &Collision) -> Color {
+    match collision.0 {
+        None => DYNAMIC,
+        Some(CollisionType::Static) => STATIC,
+    }
+}
+
+/**
+ * Collision boxes and velocities as Physics last saw them, at the tick's positions.
+ * Sprites are extrapolated between ticks so they can run slightly ahead of their box.
+ */
+pub fn overlays(
+    debug: &DebugDraw,
+    data: &EntityData,
+    (collision, tilemap): (&ReadStorage<Collision>, &ReadStorage<Tilemap>),
+    view: Rect,
+) -> Vec<(Shape, Color)> {
+    let (_, position, velocity, size, _, _, hitbox, _) = data;
+    let mut shapes = vec![];
+    for (pos, size, collision, velocity, hitbox) in (
+        position,
+        size,
+        collision.maybe(),
+        velocity.maybe(),
+        hitbox.maybe(),
+    )
+        .join()
+    {
+        let rect = collision_rect(pos, size, hitbox);
+        match collision {
+            Some(collision) if debug.collisions => {
+                shapes.push((Shape::Rect(rect), collision_color(collision)))
+            }
+            _ => {}
+        }
+        match velocity {
+            Some(Velocity(vx, vy)) if debug.velocities && (*vx, *vy) != (0, 0) => {
+                let center = rect.center();
+                let from = (center.x() as f32, center.y() as f32);
+                let to = (
+                    from.0 + *vx as f32 * VELOCITY_SCALE,
+                    from.1 + *vy as f32 * VELOCITY_SCALE,
+                );
+                shapes.push((Shape::Arrow(from, to), VELOCITY));
+            }
+            _ => {}
+        }
+    }
+    // Solid cells collide like Static entities, only those on camera are drawn.
+    if debug.collisions {
+        for (pos, map) in (position, tilemap).join() {
+            for cell in map.solid_cells((pos.0, pos.1), view) {
+                shapes.push((Shape::Rect(cell), STATIC));
+            }
+        }
+    }
+    shapes
+}
+
+// The quads of the sprites in `draws`, except on the UI layer which is not in world space.
+pub fn sprite_bounds(draws: &[(DrawKey, &Sprite, Draw)]) -> Vec<(Shape, Color)> {
+    let mut shapes = vec![];
+    for ((layer, ..), _, draw) in draws {
+        match draw {
+            Draw::Sprite(instance) if *layer != Layer::UI => {
+                let [x, y] = instance.offset;
+                let [w, h] = instance.scale;
+                let rect = Rect::new(x as i32, y as i32, w.max(1.0) as u32, h.max(1.0) as u32);
+                shapes.push((Shape::Rect(rect), BOUNDS));
+            }
+            _ => {}
+        }
+    }
+    shapes
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DebugVertex {
+    pub pos: [f32; 2],
+    pub color: [f32; 4],
+}
+implement_vertex!(DebugVertex, pos, color);
+
+/**
+ * Strokes of a glyph, as segments of a 16 segment display on a 2 x 2 grid:
+ * `a` / `A` top, `D` / `d` bottom and `g` / `G` middle, left and right halves,
+ * `f` / `e` left and `b` / `c` right, upper and lower,
+ * `i` / `l` center, upper and lower, `h` `j` / `k` `m` diagonals to the center.
+ */
+fn glyph(c: char) -> &'static str {
+    match c.to_ascii_uppercase() {
+        '0' => "aAbcdDefjk",
+        '1' => "bcj",
+        '2' => "aAbGgeDd",
+        '3' => "aAbcdDG",
+        '4' => "fgGbc",
+        '5' | 'S' => "aAfgGcdD",
+        '6' => "aAfegGcdD",
+        '7' => "aAbc",
+        '8' => "aAbcdDefgG",
+        '9' => "aAbcdDfgG",
+        'A' => "aAbcefgG",
+        'B' => "aAbcdDGil",
+        'C' => "aAfeDd",
+        'D' => "aAbcdDil",
+        'E' => "aAfeDdg",
+        'F' => "aAfeg",
+        'G' => "aAfeDdcG",
+        'H' => "fbecgG",
+        'I' => "aAdDil",
+        'J' => "bcdDe",
+        'K' => "fegjm",
+        'L' => "feDd",
+        'M' => "febchj",
+        'N' => "febchm",
+        'O' => "aAbcdDef",
+        'P' => "aAbfegG",
+        'Q' => "aAbcdDefm",
+        'R' => "aAbfegGm",
+        'T' => "aAil",
+        'U' => "febcdD",
+        'V' => "fekj",
+        'W' => "febckm",
+        'X' => "hjkm",
+        'Y' => "hjl",
+        'Z' => "aAjkDd",
+        '-' => "gG",
+        '+' => "gGil",
+        '=' => "gGDd",
+        '_' => "Dd",
+        '.' | ',' => "D",
+        '/' => "jk",
+        '|' => "il",
+        '<' | '(' | '[' => "jm",
+        '>' | ')' | ']' => "hk",
+        '\'' | '"' => "i",
+        _ => "",
+    }
+}
+
+fn segment(s: char) -> Option<((f32, f32), (f32, f32))> {
+    Some(match s {
+        'a' => ((0.0, 0.0), (1.0, 0.0)),
+        'A' => ((1.0, 0.0), (2.0, 0.0)),
+        'b' => ((2.0, 0.0), (2.0, 1.0)),
+        'c' => ((2.0, 1.0), (2.0, 2.0)),
+        'd' => ((1.0, 2.0), (2.0, 2.0)),
+        'D' => ((0.0, 2.0), (1.0, 2.0)),
+        'e' => ((0.0, 1.0), (0.0, 2.0)),
+        'f' => ((0.0, 0.0), (0.0, 1.0)),
+        'g' => ((0.0, 1.0), (1.0, 1.0)),
+        'G' => ((1.0, 1.0), (2.0, 1.0)),
+        'h' => ((0.0, 0.0), (1.0, 1.0)),
+        'i' => ((1.0, 0.0), (1.0, 1.0)),
+        'j' => ((2.0, 0.0), (1.0, 1.0)),
+        'k' => ((0.0, 2.0), (1.0, 1.0)),
+        'l' => ((1.0, 1.0), (1.0, 2.0)),
+        'm' => ((2.0, 2.0), (1.0, 1.0)),
+        _ => return None,
+    })
+}
+
+// Line segments of `shapes`, two vertices each.
+pub fn tessellate<'s>(shapes: impl IntoIterator<Item = &'s (Shape, Color)>) -> Vec<DebugVertex> {
+    let mut vertices = vec![];
+    for (shape, color) in shapes {
+        let mut line = |(x0, y0): (f32, f32), (x1, y1): (f32, f32)| {
+            vertices.push(DebugVertex {
+                pos: [x0, y0],
+                color: *color,
+            });
+            vertices.push(DebugVertex {
+                pos: [x1, y1],
+                color: *color,
+            });
+        };
+        match shape {
+            Shape::Line(from, to) => line(*from, *to),
+            Shape::Rect(rect) => {
+                // Through the centers of the edge pixels, so no side falls between two rows.
+                let (x0, y0) = (rect.left() as f32 + 0.5, rect.top() as f32 + 0.5);
+                let (x1, y1) = (rect.right() as f32 - 0.5, rect.bottom() as f32 - 0.5);
+                line((x0, y0), (x1, y0));
+                line((x1, y0), (x1, y1));
+                line((x1, y1), (x0, y1));
+                line((x0, y1), (x0, y0));
+            }
+            Shape::Circle((cx, cy), radius) => {
+                const SEGMENTS: usize = 24;
+                let point = |i: usize| {
+                    let angle = i as f32 / SEGMENTS as f32 * std::f32::consts::PI * 2.0;
+                    (cx + radius * angle.cos(), cy + radius * angle.sin())
+                };
+                for i in 0..SEGMENTS {
+                    line(point(i), point(i + 1));
+                }
+            }
+            Shape::Arrow((x0, y0), (x1, y1)) => {
+                line((*x0, *y0), (*x1, *y1));
+                let (dx, dy) = (x1 - x0, y1 - y0);
+                let length = (dx * dx + dy * dy).sqrt();
+                if length > 0.0 {
+                    // Head strokes at 30 degrees either side, shorter on short arrows.
+                    let head = 4.0f32.min(length / 2.0);
+                    let (ux, uy) = (dx / length * head, dy / length * head);
+                    let (cos, sin) = (0.866, 0.5);
+                    line(
+                        (*x1, *y1),
+                        (x1 - ux * cos + uy * sin, y1 - uy * cos - ux * sin),
+                    );
+                    line(
+                        (*x1, *y1),
+                        (x1 - ux * cos - uy * sin, y1 - uy * cos + ux * sin),
+                    );
+                }
+            }
+            Shape::Text((x, y), text) => {
+                let unit = TEXT_SIZE / 4.0;
+                let (mut gx, mut gy) = (*x, *y);
+                for c in text.chars() {
+                    if c == '\n' {
+                        gx = *x;
+                        gy += TEXT_SIZE + unit * 2.0;
+                        continue;
+                    }
+                    // Unknown characters leave a space.
+                    for s in glyph(c).chars().filter_map(segment) {
+                        let ((sx0, sy0), (sx1, sy1)) = s;
+                        line(
+                            (gx + sx0 * unit, gy + sy0 * unit * 2.0),
+                            (gx + sx1 * unit, gy + sy1 * unit * 2.0),
+                        );
+                    }
+                    gx += unit * 3.0;
+                }
+            }
+        }
+    }
+    vertices
+}
+
+// Draws `vertices` from `tessellate` as a list of lines.
+pub fn draw_lines(
+    window: &SDL2Facade,
+    target: &mut impl Surface,
+    program: &Program,
+    vertices: &[DebugVertex],
+    projection: &glm::Mat4x4,
+) {
+    if vertices.is_empty() {
+        return;
+    }
+    let vertex_buffer = VertexBuffer::new(window, vertices).unwrap();
+    let uniforms = uniform! {
+        projection: projection.data.0,
+    };
+    let params = DrawParameters {
+        blend: Blend::alpha_blending(),
+        ..Default::default()
+    };
+    target
+        .draw(
+            &vertex_buffer,
+            NoIndices(PrimitiveType::LinesList),
+            program,
+            &uniforms,
+            &params,
+        )
+        .unwrap();
+}
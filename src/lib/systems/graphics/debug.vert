@@ -0,0 +1,13 @@
+#version 330 core
+in vec2 pos;
+in vec4 color;
+
+out vec4 Color;
+
+uniform mat4 projection;
+
+void main()
+{
+    Color = color;
+    gl_Position = projection * vec4(pos, 0.0, 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,22 @@
+#version 330 core
+in vec2 WorldPos;
+flat in vec2 Center;
+flat in float Radius;
+flat in vec3 Color;
+flat in float Height;
+out vec4 FragColor;
+
+uniform sampler2D normals;
+uniform vec2 resolution;
+
+void main()
+{
+    // The light map and the normals are both at the virtual resolution.
+    vec3 normal = normalize(texture(normals, gl_FragCoord.xy / resolution).rgb * 2.0 - 1.0);
+    vec2 delta = Center - WorldPos;
+    // The world's y points down, normal maps are green up.
+    vec3 to_light = normalize(vec3(delta.x, -delta.y, Height));
+    float falloff = clamp(1.0 - length(delta) / Radius, 0.0, 1.0);
+    float diffuse = max(dot(normal, to_light), 0.0);
+    FragColor = vec4(Color * diffuse * falloff * falloff, 1.0);
+}
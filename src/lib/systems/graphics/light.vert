@@ -0,0 +1,26 @@
+#version 330 core
+in vec2 pos;
+// Per instance
+in vec2 center;
+in float radius;
+in vec3 color;
+in float height;
+
+out vec2 WorldPos;
+flat out vec2 Center;
+flat out float Radius;
+flat out vec3 Color;
+flat out float Height;
+
+uniform mat4 projection;
+
+void main()
+{
+    // A quad covering the light's radius.
+    WorldPos = center + (pos * 2.0 - 1.0) * radius;
+    Center = center;
+    Radius = radius;
+    Color = color;
+    Height = height;
+    gl_Position = projection * vec4(WorldPos, 0.0, 1.0);
+}
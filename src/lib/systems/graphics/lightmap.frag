@@ -0,0 +1,11 @@
+#version 330 core
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D light_map;
+
+void main()
+{
+    // Multiplied into the scene by the blending.
+    FragColor = vec4(texture(light_map, TexCoords).rgb, 1.0);
+}
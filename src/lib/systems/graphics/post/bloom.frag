@@ -0,0 +1,31 @@
+#version 330 core
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D scene;
+uniform vec2 resolution;
+uniform float threshold;
+uniform float intensity;
+uniform float radius;
+
+vec3 bright(vec2 uv)
+{
+    vec3 color = texture(scene, uv).rgb;
+    float luma = dot(color, vec3(0.2126, 0.7152, 0.0722));
+    return color * max(luma - threshold, 0.0) / max(1.0 - threshold, 0.0001);
+}
+
+void main()
+{
+    vec4 color = texture(scene, TexCoords);
+    vec2 texel = radius / resolution;
+    // Two rings of eight taps, the inner one weighted more.
+    vec3 glow = vec3(0.0);
+    for (int i = 0; i < 8; i++) {
+        float angle = float(i) * 0.78539816;
+        vec2 dir = vec2(cos(angle), sin(angle)) * texel;
+        glow += bright(TexCoords + dir) * 0.08;
+        glow += bright(TexCoords + dir * 2.0) * 0.04;
+    }
+    FragColor = vec4(color.rgb + glow * intensity, color.a);
+}
\ No newline at end of file
@@ -0,0 +1,36 @@
+#version 330 core
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D scene;
+uniform vec2 resolution;
+uniform float curvature;
+uniform float scanlines;
+uniform float aberration;
+
+// Bends the screen outwards from the center.
+vec2 warp(vec2 uv)
+{
+    vec2 c = uv * 2.0 - 1.0;
+    c *= 1.0 + curvature * dot(c.yx, c.yx);
+    return c * 0.5 + 0.5;
+}
+
+void main()
+{
+    vec2 uv = warp(TexCoords);
+    if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+        FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+    vec2 split = vec2(aberration / resolution.x, 0.0);
+    vec3 color = vec3(
+        texture(scene, uv + split).r,
+        texture(scene, uv).g,
+        texture(scene, uv - split).b
+    );
+    // One dark line per row of virtual pixels.
+    float line = sin(uv.y * resolution.y * 3.14159265);
+    color *= 1.0 - scanlines * (1.0 - line * line);
+    FragColor = vec4(color, 1.0);
+}
\ No newline at end of file
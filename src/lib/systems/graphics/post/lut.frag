@@ -0,0 +1,28 @@
+#version 330 core
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D scene;
+// size * size squares of size * size pixels laid out left to right,
+// blue picks the square, red and green the pixel within it.
+uniform sampler2D lut;
+uniform float size;
+uniform float strength;
+
+vec3 lookup(vec3 color)
+{
+    float blue = color.b * (size - 1.0);
+    float low = floor(blue);
+    float high = min(low + 1.0, size - 1.0);
+    vec2 cell = (color.rg * (size - 1.0) + 0.5) / vec2(size * size, size);
+    vec3 a = texture(lut, cell + vec2(low / size, 0.0)).rgb;
+    vec3 b = texture(lut, cell + vec2(high / size, 0.0)).rgb;
+    return mix(a, b, blue - low);
+}
+
+void main()
+{
+    vec4 color = texture(scene, TexCoords);
+    vec3 graded = lookup(clamp(color.rgb, 0.0, 1.0));
+    FragColor = vec4(mix(color.rgb, graded, strength), color.a);
+}
\ No newline at end of file
@@ -0,0 +1,10 @@
+#version 330 core
+in vec2 pos;
+
+out vec2 TexCoords;
+
+void main()
+{
+    TexCoords = pos;
+    gl_Position = vec4(pos * 2.0 - 1.0, 0.0, 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,18 @@
+#version 330 core
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D scene;
+uniform vec2 resolution;
+uniform float radius;
+uniform float softness;
+uniform float strength;
+
+void main()
+{
+    vec4 color = texture(scene, TexCoords);
+    // Distance from the center, corrected for the aspect ratio.
+    vec2 c = (TexCoords - 0.5) * vec2(resolution.x / resolution.y, 1.0);
+    float shade = 1.0 - smoothstep(radius - softness, radius, length(c));
+    FragColor = vec4(color.rgb * mix(1.0, shade, strength), color.a);
+}
\ No newline at end of file
@@ -0,0 +1,14 @@
+#version 330 core
+in vec2 TexCoords;
+in vec4 Color;
+layout(location = 0) out vec4 color;
+// Glyphs face the camera, only kept when the scene is lit like world.frag.
+layout(location = 1) out vec4 normal;
+
+uniform sampler2D atlas;
+
+void main()
+{
+    color = texture(atlas, TexCoords) * Color;
+    normal = vec4(0.5, 0.5, 1.0, color.a);
+}
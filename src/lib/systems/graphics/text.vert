@@ -0,0 +1,20 @@
+#version 330 core
+in vec2 pos;
+// Per instance
+in vec2 offset;
+in vec2 scale;
+in vec4 source; // <vec2 position, vec2 size> in atlas pixels
+in vec4 color;
+
+out vec2 TexCoords;
+out vec4 Color;
+
+uniform mat4 projection;
+uniform vec2 atlas_size;
+
+void main()
+{
+    TexCoords = (source.xy + pos * source.zw) / atlas_size;
+    Color = color;
+    gl_Position = projection * vec4(offset + pos * scale, 0.0, 1.0);
+}
\ No newline at end of file
@@ -1,14 +1,19 @@
 #version 330 core
 in vec2 TexCoords;
-out vec4 color;
+flat in int Frame;
+in vec4 Tint;
+layout(location = 0) out vec4 color;
+// Only kept when the scene is lit, see lighting.rs.
+layout(location = 1) out vec4 normal;
 
 uniform sampler2DArray image;
-uniform int index;
+uniform sampler2DArray normals;
 uniform vec2 sprite_dim;
 
 void main()
 {    
     // ivec2 pos = ivec2(coords.x, coords.y);
     // color = texelFetch(image, pos, 0);
-    color = texture(image, vec3(TexCoords, index));
-}  
\ No newline at end of file
+    color = texture(image, vec3(TexCoords, Frame)) * Tint;
+    normal = vec4(texture(normals, vec3(TexCoords, Frame)).rgb, color.a);
+}
@@ -1,12 +1,21 @@
 #version 330 core
 in vec2 pos; // <vec2 position, vec2 texCoords>
+// Per instance
+in vec2 offset;
+in vec2 scale;
+in int frame;
+in vec4 tint;
+
 out vec2 TexCoords;
+flat out int Frame;
+out vec4 Tint;
 
-uniform mat4 model;
 uniform mat4 projection;
 
 void main()
 {
     TexCoords = pos;
-    gl_Position = projection * model * vec4(pos, 0.0, 1.0);
+    Frame = frame;
+    Tint = tint;
+    gl_Position = projection * vec4(offset + pos * scale, 0.0, 1.0);
 }
\ No newline at end of file
@@ -15,7 +15,13 @@ use sdl2::EventPump;
 use specs::prelude::*;
 use specs::{Join, System, WriteStorage};
 
+use crate::game::Game;
+use crate::lib::systems::camera::Camera;
+use crate::lib::systems::capture::{Capture, RecordFormat};
 use crate::lib::systems::components::{InputHandler, Velocity};
+use crate::lib::systems::debug::DebugDraw;
+use crate::lib::systems::postprocess::PostProcess;
+use crate::lib::systems::screen::Screen;
 
 #[derive(Debug, Clone, Copy)]
 enum KeyState {
@@ -89,9 +95,26 @@ impl<'i> InputSystem<'i> {
         }
     }
 
-    fn key_map(&mut self, event: &Event) {
+    fn key_map(
+        &mut self,
+        event: &Event,
+        screen: &mut Screen,
+        (capture, debug_draw, post_process): (&mut Capture, &mut DebugDraw, &mut PostProcess),
+    ) {
         match event {
             key!(KeyDown on Escape) | Event::Quit { .. } => self.running = false,
+            key!(KeyDown on F11) => screen.mode = screen.mode.next(),
+            // Capture hotkeys, F9 / F10 start or stop a 5 second recording.
+            key!(KeyDown on F12) => {
+                capture.screenshot();
+            }
+            key!(KeyDown on F10) => capture.toggle(RecordFormat::Gif, 5.0),
+            key!(KeyDown on F9) => capture.toggle(RecordFormat::PngSequence, 5.0),
+            key!(KeyDown on F3) => debug_draw.toggle(),
+            key!(KeyDown on F5) => post_process.toggle("bloom"),
+            key!(KeyDown on F6) => post_process.toggle("crt"),
+            key!(KeyDown on F7) => post_process.toggle("vignette"),
+            key!(KeyDown on F8) => post_process.toggle("lut"),
             Event::KeyDown {
                 keycode: Some(code),
                 ..
@@ -111,7 +134,7 @@ impl<'i> InputSystem<'i> {
             _ => {}
         }
     }
-    fn egui_raw(&mut self, event: &Event, raw: &mut RawInput) {
+    fn egui_raw(&mut self, event: &Event, raw: &mut RawInput, screen: &mut Screen) {
         use sdl2::event::Event::*;
         use sdl2::event::*;
         let event = event.clone();
@@ -120,13 +143,20 @@ impl<'i> InputSystem<'i> {
         match event {
             //Only the window resize event is handled
             Window {
-                win_event: WindowEvent::Resized(_width, _height),
+                win_event: WindowEvent::Resized(width, height),
+                ..
+            }
+            | Window {
+                win_event: WindowEvent::SizeChanged(width, height),
                 ..
             } => {
-                // raw.screen_rect = Some(Rect::from_min_size(
-                //     Pos2::new(0f32, 0f32),
-                //     egui::vec2(width as f32, height as f32) / raw.pixels_per_point.unwrap(),
-                // ))
+                screen.window = (width.max(0) as u32, height.max(0) as u32);
+                // Window sizes are in points already.
+                raw.pixels_per_point = Some(screen.dpi_scale());
+                raw.screen_rect = Some(egui::Rect::from_min_size(
+                    Pos2::new(0f32, 0f32),
+                    egui::vec2(width as f32, height as f32),
+                ))
             }
 
             //MouseButonLeft pressed is the only one needed by egui
@@ -173,10 +203,15 @@ impl<'i> InputSystem<'i> {
         }
     }
 
-    fn read_input(&mut self, raw: &mut RawInput) {
+    fn read_input(
+        &mut self,
+        raw: &mut RawInput,
+        screen: &mut Screen,
+        (capture, debug_draw, post_process): (&mut Capture, &mut DebugDraw, &mut PostProcess),
+    ) {
         while let Some(event) = self.event_pump.poll_event() {
-            self.key_map(&event);
-            self.egui_raw(&event, raw);
+            self.key_map(&event, screen, (capture, debug_draw, post_process));
+            self.egui_raw(&event, raw, screen);
         }
         self.event_pump.pump_events();
     }
@@ -186,6 +221,13 @@ impl<'i> InputSystem<'i> {
     }
 }
 
+// Mouse position, updated by the InputSystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pointer {
+    pub screen: (f32, f32),
+    pub world: (f32, f32),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InputState {
     Idle,
@@ -197,14 +239,42 @@ impl<'a, 'i> System<'a> for InputSystem<'i> {
         WriteStorage<'a, InputHandler>,
         WriteStorage<'a, Velocity>,
         Write<'a, RawInput>,
+        Read<'a, Camera>,
+        Write<'a, Pointer>,
+        Write<'a, Screen>,
+        Write<'a, Capture>,
+        Write<'a, DebugDraw>,
+        Write<'a, PostProcess>,
+        Read<'a, Game>,
     );
-    fn run(&mut self, (mut input, mut velocity, mut raw): Self::SystemData) {
+    fn run(
+        &mut self,
+        (
+            mut input,
+            mut velocity,
+            mut raw,
+            camera,
+            mut pointer,
+            mut screen,
+            mut capture,
+            mut debug_draw,
+            mut post_process,
+            game,
+        ): Self::SystemData,
+    ) {
         let mut x_target_speed = 0;
         let mut y_target_speed = 10;
         let mut state: Option<InputState> = Some(InputState::Idle);
         let mut raw_input = raw.clone();
-        self.read_input(&mut raw_input);
+        self.read_input(
+            &mut raw_input,
+            &mut screen,
+            (&mut capture, &mut debug_draw, &mut post_process),
+        );
         *raw = raw_input;
+        // Mouse events are in window points, the camera works at the virtual resolution.
+        pointer.screen = screen.window_to_virtual((self.pointer_pos.x, self.pointer_pos.y));
+        pointer.world = camera.screen_to_world(&screen, pointer.screen, game.interpolation());
         match self.get_state(Control::Left) {
             Some(KeyState::Down) => {
                 x_target_speed = -5;
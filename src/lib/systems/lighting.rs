@@ -0,0 +1,243 @@
+use crate::lib::opengl::SDL2Facade;
+use crate::lib::systems::components::{Position, Velocity};
+use crate::lib::systems::renderer::{text_position, Renderer, Vertex};
+
+use glium::implement_vertex;
+use glium::texture::{
+    MipmapsOption, RawImage2d, Texture2d, Texture2dArray, UncompressedFloatFormat,
+};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
+use glium::{uniform, Blend, BlendingFunction, LinearBlendingFactor};
+use glium::{DrawParameters, IndexBuffer, Program, Surface, VertexBuffer};
+
+use specs::prelude::*;
+use specs::Component;
+
+/**
+ * Lights the scene around the entity's Position, falling off to nothing at `radius` pixels.
+ * `height` is how far above the sprites the light sits, lower lights graze their normals.
+ */
+#[derive(Component, Debug, Clone)]
+pub struct PointLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub radius: f32,
+    pub height: f32,
+    pub offset: (f32, f32),
+}
+
+impl PointLight {
+    pub fn new(color: [f32; 3], radius: f32) -> Self {
+        Self {
+            color,
+            intensity: 1.0,
+            radius,
+            height: radius / 4.0,
+            offset: (0.0, 0.0),
+        }
+    }
+}
+
+/**
+ * Light reaching everything, the scene's darkest it gets. Several add up.
+ * The scene is only lit once there is an AmbientLight or a PointLight,
+ * without any every layer is drawn as is.
+ */
+#[derive(Component, Debug, Clone, Copy)]
+pub struct AmbientLight(pub [f32; 3]);
+
+// Per instance attributes of a point light's quad, in world pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct LightInstance {
+    pub center: [f32; 2],
+    pub radius: f32,
+    pub color: [f32; 3],
+    pub height: f32,
+}
+implement_vertex!(LightInstance, center, radius, color, height);
+
+// The ambient color and the point lights of this frame, None when nothing is lit.
+pub fn lights(
+    (position, velocity): (&ReadStorage<Position>, &ReadStorage<Velocity>),
+    (point_light, ambient_light): (&ReadStorage<PointLight>, &ReadStorage<AmbientLight>),
+    alpha: f64,
+) -> Option<([f32; 3], Vec<LightInstance>)> {
+    let mut lit = false;
+    let mut ambient = [0.0; 3];
+    for AmbientLight(color) in ambient_light.join() {
+        lit = true;
+        for (a, c) in ambient.iter_mut().zip(color) {
+            *a += c;
+        }
+    }
+    let mut lights = vec![];
+    for (light, pos, velocity) in (point_light, position, velocity.maybe()).join() {
+        lit = true;
+        let (x, y) = text_position(pos, velocity, alpha);
+        let [r, g, b] = light.color;
+        lights.push(LightInstance {
+            center: [x as f32 + light.offset.0, y as f32 + light.offset.1],
+            radius: light.radius,
+            color: [
+                r * light.intensity,
+                g * light.intensity,
+                b * light.intensity,
+            ],
+            height: light.height,
+        });
+    }
+    if lit {
+        Some((ambient, lights))
+    } else {
+        None
+    }
+}
+
+/**
+ * GL side of the lighting. While the scene is lit the world and text shaders also write
+ * the normals of the layers below the UI, the lights are then accumulated into
+ * a light map at the virtual resolution and multiplied into the scene, so they
+ * are upscaled with the pixel art.
+ */
+pub struct Lighting {
+    light_program: Program,
+    composite_program: Program,
+    // One flat normal for sprites without a normal map.
+    pub flat: Texture2dArray,
+    pub normals: Option<Texture2d>,
+    light_map: Option<Texture2d>,
+    instances: Option<VertexBuffer<LightInstance>>,
+}
+
+impl Lighting {
+    pub fn new(window: &SDL2Facade) -> Self {
+        let light_program = Program::from_source(
+            window,
+            include_str!("graphics/light.vert"),
+            include_str!("graphics/light.frag"),
+            None,
+        )
+        .unwrap();
+        let composite_program = Program::from_source(
+            window,
+            include_str!("graphics/post/post.vert"),
+            include_str!("graphics/lightmap.frag"),
+            None,
+        )
+        .unwrap();
+        // Pointing straight out of the screen.
+        let flat = RawImage2d::from_raw_rgba(vec![128u8, 128, 255, 255], (1, 1));
+        Self {
+            light_program,
+            composite_program,
+            flat: Texture2dArray::new(window, vec![flat]).unwrap(),
+            normals: None,
+            light_map: None,
+            instances: None,
+        }
+    }
+
+    // Sizes the buffers to the scene, clears the normals and uploads the lights, before drawing.
+    pub fn prepare(&mut self, window: &SDL2Facade, (w, h): (u32, u32), lights: &[LightInstance]) {
+        if self.normals.as_ref().map(|t| t.dimensions()) != Some((w, h)) {
+            self.normals = Some(Texture2d::empty(window, w, h).unwrap());
+            // Half floats so dim lights add up without banding.
+            let light_map = Texture2d::empty_with_format(
+                window,
+                UncompressedFloatFormat::F16F16F16F16,
+                MipmapsOption::NoMipmap,
+                w,
+                h,
+            );
+            self.light_map = Some(light_map.unwrap());
+        }
+        let normals = self.normals.as_ref().unwrap();
+        normals.as_surface().clear_color(0.5, 0.5, 1.0, 1.0);
+        if !lights.is_empty() {
+            Renderer::upload(window, &mut self.instances, lights);
+        }
+    }
+
+    // Accumulates `ambient` and the prepared lights, then multiplies them into `scene`.
+    pub fn apply(
+        &self,
+        (vertex_buffer, index_buffer): (&VertexBuffer<Vertex>, &IndexBuffer<u16>),
+        scene: &Texture2d,
+        projection: &glm::Mat4x4,
+        (ambient, count): ([f32; 3], usize),
+    ) {
+        let (normals, light_map) = match (&self.normals, &self.light_map) {
+            (Some(normals), Some(light_map)) => (normals, light_map),
+            _ => return,
+        };
+        let (w, h) = light_map.dimensions();
+        let mut lights = light_map.as_surface();
+        lights.clear_color(ambient[0], ambient[1], ambient[2], 1.0);
+        if let Some(instances) = self.instances.as_ref().filter(|_| count > 0) {
+            let uniforms = uniform! {
+                projection: projection.data.0,
+                resolution: [w as f32, h as f32],
+                normals: normals
+                    .sampled()
+                    .magnify_filter(MagnifySamplerFilter::Nearest)
+                    .minify_filter(MinifySamplerFilter::Nearest),
+            };
+            let additive = BlendingFunction::Addition {
+                source: LinearBlendingFactor::One,
+                destination: LinearBlendingFactor::One,
+            };
+            let params = DrawParameters {
+                blend: Blend {
+                    color: additive,
+                    alpha: additive,
+                    constant_value: (0.0, 0.0, 0.0, 0.0),
+                },
+                ..Default::default()
+            };
+            lights
+                .draw(
+                    (
+                        vertex_buffer,
+                        instances.slice(0..count).unwrap().per_instance().unwrap(),
+                    ),
+                    index_buffer,
+                    &self.light_program,
+                    &uniforms,
+                    &params,
+                )
+                .unwrap();
+        }
+
+        // scene * light, keeping the scene's alpha.
+        let params = DrawParameters {
+            blend: Blend {
+                color: BlendingFunction::Addition {
+                    source: LinearBlendingFactor::DestinationColor,
+                    destination: LinearBlendingFactor::Zero,
+                },
+                alpha: BlendingFunction::Addition {
+                    source: LinearBlendingFactor::Zero,
+                    destination: LinearBlendingFactor::One,
+                },
+                constant_value: (0.0, 0.0, 0.0, 0.0),
+            },
+            ..Default::default()
+        };
+        let uniforms = uniform! {
+            light_map: light_map
+                .sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+        };
+        scene
+            .as_surface()
+            .draw(
+                vertex_buffer,
+                index_buffer,
+                &self.composite_program,
+                &uniforms,
+                &params,
+            )
+            .unwrap();
+    }
+}
@@ -2,10 +2,19 @@
 // use crate::Component;
 // use crate::System;
 
+pub mod camera;
+pub mod capture;
 pub mod components;
+pub mod debug;
 pub mod input;
+pub mod lighting;
+pub mod particles;
 pub mod physics;
+pub mod postprocess;
 pub mod renderer;
+pub mod screen;
+#[cfg(test)]
+pub mod software;
 // pub mod egui;
 
 #[macro_export]
@@ -0,0 +1,419 @@
+use crate::animation::AnimationEvent;
+use crate::game::TICKS_PER_SECOND;
+use crate::lib::logging::warn_once;
+use crate::lib::sprite::{Sprite, SpriteManager};
+use crate::lib::systems::components::{Layer, Position, RenderLayer, Velocity, ZIndex};
+use crate::lib::systems::renderer::{Draw, DrawKey, SpriteInstance};
+
+use specs::prelude::*;
+use specs::shrev::{EventChannel, ReaderId};
+use specs::Component;
+
+pub trait Lerp: Copy {
+    fn lerp(self, to: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, to: Self, t: f32) -> Self {
+        self + (to - self) * t
+    }
+}
+
+impl Lerp for [f32; 4] {
+    fn lerp(self, to: Self, t: f32) -> Self {
+        let mut out = self;
+        for (i, c) in out.iter_mut().enumerate() {
+            *c = c.lerp(to[i], t);
+        }
+        out
+    }
+}
+
+/**
+ * Keys over a particle's life, from 0 when it spawns to 1 when it dies,
+ * sorted by time and linearly interpolated. Before the first key and after
+ * the last one the value holds.
+ */
+#[derive(Debug, Clone)]
+pub struct Curve<T>(pub Vec<(f32, T)>);
+
+impl<T: Lerp> Curve<T> {
+    pub fn constant(value: T) -> Self {
+        Curve(vec![(0.0, value)])
+    }
+
+    pub fn sample(&self, t: f32) -> Option<T> {
+        let keys = &self.0;
+        match keys.iter().position(|(key, _)| *key > t) {
+            Some(0) => Some(keys[0].1),
+            Some(i) => {
+                let (t0, from) = keys[i - 1];
+                let (t1, to) = keys[i];
+                Some(from.lerp(to, (t - t0) / (t1 - t0)))
+            }
+            None => keys.last().map(|(_, value)| *value),
+        }
+    }
+}
+
+// Which frame of the sprite a particle shows.
+#[derive(Debug, Clone)]
+pub enum ParticleFrame {
+    Frame(usize),
+    // A random frame for each particle, ie. a sheet of debris.
+    Random,
+    // Plays an Aseprite tag, looping, from when the particle spawns.
+    Tag(String),
+}
+
+// What makes an emitter burst.
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    // An AnimationEvent of the entity with this name, ie. from Aseprite cel user data.
+    Event(&'static str),
+    // The entity stopping after falling, ie. hitting the ground.
+    Land,
+}
+
+#[derive(Debug, Clone)]
+pub struct Particle {
+    pub position: (f32, f32),
+    pub velocity: (f32, f32),
+    // In ticks.
+    pub age: u32,
+    pub lifetime: u32,
+    // Picks the frame of ParticleFrame::Random.
+    pub variant: u32,
+}
+
+impl Particle {
+    // How far through its life the particle is, in [0, 1).
+    pub fn life(&self) -> f32 {
+        self.age as f32 / self.lifetime as f32
+    }
+}
+
+/**
+ * Spawns particles around the entity's Position, continuously at `rate` while `emitting`
+ * and in bursts from `burst` or `bursts`. Particles are simulated on the fixed tick by the
+ * ParticleSystem and drawn as sprites on the entity's RenderLayer.
+ * Times are in seconds, velocities and gravity in pixels per tick like Velocity.
+ */
+#[derive(Component, Debug, Clone)]
+pub struct ParticleEmitter {
+    pub sprite: &'static str,
+    pub frame: ParticleFrame,
+    // Particles per second.
+    pub rate: f32,
+    pub emitting: bool,
+    pub bursts: Vec<(Trigger, u32)>,
+    // Particles spawn anywhere in a `spread` sized box centered `offset` from the Position.
+    pub offset: (f32, f32),
+    pub spread: (f32, f32),
+    // Ranges particles pick their lifetime and initial velocity from.
+    pub lifetime: (f32, f32),
+    pub velocity_x: (f32, f32),
+    pub velocity_y: (f32, f32),
+    pub gravity: f32,
+    pub color: Curve<[f32; 4]>,
+    // Multiplies the sprite's frame size.
+    pub size: Curve<f32>,
+    pub max_particles: usize,
+    // Deletes the entity once it has nothing left to emit or draw, for one-shot effects.
+    pub once: bool,
+    // Another entity whose Position and events drive the emitter, so one can have several.
+    pub follow: Option<Entity>,
+    particles: Vec<Particle>,
+    pending: u32,
+    accumulator: f32,
+    seed: u32,
+    falling: bool,
+}
+
+impl ParticleEmitter {
+    pub fn new(sprite: &'static str) -> Self {
+        Self {
+            sprite,
+            frame: ParticleFrame::Frame(0),
+            rate: 0.0,
+            emitting: true,
+            bursts: vec![],
+            offset: (0.0, 0.0),
+            spread: (0.0, 0.0),
+            lifetime: (1.0, 1.0),
+            velocity_x: (0.0, 0.0),
+            velocity_y: (0.0, 0.0),
+            gravity: 0.0,
+            color: Curve::constant([1.0; 4]),
+            size: Curve::constant(1.0),
+            max_particles: 256,
+            once: false,
+            follow: None,
+            particles: vec![],
+            pending: 0,
+            accumulator: 0.0,
+            seed: 0,
+            falling: false,
+        }
+    }
+
+    // Puffs kicked up sideways at the feet of a `(width, height)` entity when it lands.
+    pub fn dust(sprite: &'static str, (width, height): (f32, f32)) -> Self {
+        Self {
+            bursts: vec![(Trigger::Land, 8)],
+            offset: (width / 2.0, height),
+            spread: (width / 2.0, 2.0),
+            lifetime: (0.3, 0.6),
+            velocity_x: (-2.0, 2.0),
+            velocity_y: (-1.5, -0.5),
+            gravity: 0.1,
+            color: Curve(vec![
+                (0.0, [0.9, 0.85, 0.75, 0.8]),
+                (1.0, [0.9, 0.85, 0.75, 0.0]),
+            ]),
+            size: Curve(vec![(0.0, 0.75), (1.0, 1.5)]),
+            ..Self::new(sprite)
+        }
+    }
+
+    // Feathers thrown up from a `(width, height)` entity that drift down, on a "hit" event.
+    pub fn feathers(sprite: &'static str, (width, height): (f32, f32)) -> Self {
+        Self {
+            bursts: vec![(Trigger::Event("hit"), 6)],
+            offset: (width / 2.0, height / 2.0),
+            spread: (width / 2.0, height / 2.0),
+            lifetime: (0.8, 1.4),
+            velocity_x: (-2.5, 2.5),
+            velocity_y: (-5.0, -2.0),
+            gravity: 0.3,
+            color: Curve(vec![
+                (0.0, [1.0; 4]),
+                (0.7, [1.0; 4]),
+                (1.0, [1.0, 1.0, 1.0, 0.0]),
+            ]),
+            ..Self::new(sprite)
+        }
+    }
+
+    // Spawns `count` particles on the next tick.
+    pub fn burst(&mut self, count: u32) {
+        self.pending += count;
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.particles.is_empty() && self.pending == 0 && !(self.emitting && self.rate > 0.0)
+    }
+
+    // xorshift, in [0, 1).
+    fn random(&mut self) -> f32 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        (x >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn range(&mut self, (from, to): (f32, f32)) -> f32 {
+        from + (to - from) * self.random()
+    }
+
+    fn spawn(&mut self, (x, y): (f32, f32)) {
+        let (ox, oy) = self.offset;
+        let (sx, sy) = self.spread;
+        let position = (
+            x + ox + (self.random() - 0.5) * sx,
+            y + oy + (self.random() - 0.5) * sy,
+        );
+        let velocity = (self.range(self.velocity_x), self.range(self.velocity_y));
+        let lifetime = self.range(self.lifetime) * TICKS_PER_SECOND as f32;
+        let variant = (self.random() * u16::MAX as f32) as u32;
+        self.particles.push(Particle {
+            position,
+            velocity,
+            age: 0,
+            lifetime: (lifetime.round() as u32).max(1),
+            variant,
+        });
+    }
+
+    fn tick(&mut self, origin: (f32, f32)) {
+        let gravity = self.gravity;
+        for particle in &mut self.particles {
+            particle.age += 1;
+            particle.velocity.1 += gravity;
+            particle.position.0 += particle.velocity.0;
+            particle.position.1 += particle.velocity.1;
+        }
+        self.particles.retain(|p| p.age < p.lifetime);
+
+        if self.emitting {
+            self.accumulator += self.rate / TICKS_PER_SECOND as f32;
+        }
+        let continuous = self.accumulator.floor();
+        self.accumulator -= continuous;
+        let count = self.pending + continuous as u32;
+        self.pending = 0;
+        for _ in 0..count {
+            if self.particles.len() >= self.max_particles {
+                break;
+            }
+            self.spawn(origin);
+        }
+    }
+}
+
+// Steps every ParticleEmitter once per tick, run it after Physics.
+#[derive(Default)]
+pub struct ParticleSystem {
+    events: Option<ReaderId<AnimationEvent>>,
+}
+
+impl<'a> System<'a> for ParticleSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, ParticleEmitter>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        Read<'a, EventChannel<AnimationEvent>>,
+    );
+    fn run(&mut self, (entities, mut emitters, position, velocity, events): Self::SystemData) {
+        let events: Vec<&AnimationEvent> = events.read(self.events.as_mut().unwrap()).collect();
+        for (entity, emitter) in (&entities, &mut emitters).join() {
+            let source = emitter.follow.unwrap_or(entity);
+            let pos = match position.get(source) {
+                Some(pos) if entities.is_alive(source) => pos,
+                // Followed entities take their emitters with them, once the particles are gone.
+                _ => {
+                    emitter.emitting = false;
+                    emitter.pending = 0;
+                    emitter.tick((0.0, 0.0));
+                    if emitter.is_done() && (emitter.once || emitter.follow.is_some()) {
+                        entities.delete(entity).ok();
+                    }
+                    continue;
+                }
+            };
+            let mut count = 0;
+            for event in events.iter().filter(|e| e.entity == source) {
+                for (trigger, n) in &emitter.bursts {
+                    if matches!(trigger, Trigger::Event(name) if *name == event.name) {
+                        count += n;
+                    }
+                }
+            }
+            // Physics zeroes the vertical velocity of anything resting on something.
+            let vy = velocity.get(source).map_or(0, |v| v.1);
+            if emitter.falling && vy == 0 {
+                for (trigger, n) in &emitter.bursts {
+                    if matches!(trigger, Trigger::Land) {
+                        count += n;
+                    }
+                }
+            }
+            emitter.falling = vy > 1;
+            emitter.burst(count);
+            if emitter.seed == 0 {
+                emitter.seed = entity.id().wrapping_mul(2_654_435_761) | 1;
+            }
+            emitter.tick((pos.0 as f32, pos.1 as f32));
+            if emitter.once && emitter.is_done() {
+                entities.delete(entity).ok();
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.events = Some(
+            world
+                .fetch_mut::<EventChannel<AnimationEvent>>()
+                .register_reader(),
+        );
+    }
+}
+
+// The frame of `sprite` a particle shows for `frame`.
+fn particle_frame(sprite: &Sprite, frame: &ParticleFrame, particle: &Particle) -> usize {
+    match frame {
+        ParticleFrame::Frame(i) => *i,
+        ParticleFrame::Random => particle.variant as usize % sprite.frame_count().max(1),
+        ParticleFrame::Tag(name) => {
+            let json = match &sprite.info.json {
+                Some(json) => json,
+                None => return 0,
+            };
+            let tag = match json.meta.frame_tags.iter().find(|t| &t.name == name) {
+                Some(tag) => tag,
+                None => return 0,
+            };
+            let duration = |f: usize| json.frames.get(f).map_or(0, |f| f.duration);
+            let total: u128 = (tag.from..=tag.to).map(duration).sum();
+            if total == 0 {
+                return tag.from;
+            }
+            let mut time = particle.age as u128 * 1000 / TICKS_PER_SECOND as u128 % total;
+            for f in tag.from..=tag.to {
+                if time < duration(f) {
+                    return f;
+                }
+                time -= duration(f);
+            }
+            tag.to
+        }
+    }
+}
+
+/**
+ * A quad for every live particle, centered on it and extrapolated by its velocity.
+ * All the particles of an emitter share its draw key so they batch into one call.
+ */
+pub fn particle_draws<'m>(
+    sprite_manager: &'m SpriteManager,
+    emitters: &ReadStorage<ParticleEmitter>,
+    (render_layer, z_index): (&ReadStorage<RenderLayer>, &ReadStorage<ZIndex>),
+    alpha: f64,
+) -> Vec<(DrawKey, &'m Sprite, Draw)> {
+    let mut draws = Vec::new();
+    for (emitter, layer, z) in (emitters, render_layer.maybe(), z_index.maybe()).join() {
+        if emitter.particles.is_empty() {
+            continue;
+        }
+        let sprite = match sprite_manager.get(emitter.sprite) {
+            Some(sprite) => sprite,
+            None => {
+                warn_once(format!(
+                    "Particle sprite \"{}\" is not loaded",
+                    emitter.sprite
+                ));
+                continue;
+            }
+        };
+        let key = (
+            layer.map_or_else(Layer::default, |l| l.0),
+            z.map_or(0, |z| z.0),
+            0,
+        );
+        let (w, h) = sprite.frame_size();
+        for particle in &emitter.particles {
+            let life = particle.life();
+            let size = emitter.size.sample(life).unwrap_or(1.0);
+            let (w, h) = (w as f32 * size, h as f32 * size);
+            let (vx, vy) = particle.velocity;
+            let x = particle.position.0 + vx * alpha as f32;
+            let y = particle.position.1 + vy * alpha as f32;
+            let instance = SpriteInstance {
+                offset: [x - w / 2.0, y - h / 2.0],
+                scale: [w, h],
+                frame: particle_frame(sprite, &emitter.frame, particle) as i32,
+                tint: emitter.color.sample(life).unwrap_or([1.0; 4]),
+            };
+            draws.push((key, sprite, Draw::Sprite(instance)));
+        }
+    }
+    draws
+}
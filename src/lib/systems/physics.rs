@@ -1,8 +1,10 @@
 use crate::systems::components::Collision;
+use crate::systems::components::Hitbox;
 use crate::Size;
 use specs::prelude::*;
 
 use crate::systems::components::SpriteHandle;
+use crate::tilemap::Tilemap;
 use sdl2::rect::Rect;
 use specs::Entities;
 
@@ -15,6 +17,14 @@ use crate::lif;
 #[derive(Default)]
 pub struct Physics {}
 
+// Collide with the animation's hitbox when there is one, the whole sprite otherwise.
+pub fn collision_rect(pos: &Position, size: &Size, hitbox: Option<&Hitbox>) -> Rect {
+    match hitbox.and_then(|h| h.0) {
+        Some(b) => Rect::new(pos.0 + b.x(), pos.1 + b.y(), b.width(), b.height()),
+        None => Rect::new(pos.0, pos.1, size.0 as u32, size.1 as u32),
+    }
+}
+
 impl<'a> System<'a> for Physics {
     type SystemData = (
         Entities<'a>,
@@ -23,10 +33,21 @@ impl<'a> System<'a> for Physics {
         ReadStorage<'a, Collision>,
         ReadStorage<'a, SpriteHandle>,
         ReadStorage<'a, Size>,
+        ReadStorage<'a, Hitbox>,
+        ReadStorage<'a, Tilemap>,
     );
     fn run(
         &mut self,
-        (entities, mut pos, mut vel_storage, collision, _sprite_state, size): Self::SystemData,
+        (
+            entities,
+            mut pos,
+            mut vel_storage,
+            collision,
+            _sprite,
+            size,
+            hitbox,
+            tilemap,
+        ): Self::SystemData,
     ) {
         for (pos, vel) in (&mut pos, &mut vel_storage).join() {
             let Position(x, y) = pos;
@@ -43,9 +64,12 @@ impl<'a> System<'a> for Physics {
             collision: &'a Collision,
         }
         let mut ent_vec = vec![];
-        for (entity, pos, size, collision) in (&entities, &mut pos, &size, &collision).join() {
+        for (entity, pos, size, collision, hitbox) in
+            (&entities, &mut pos, &size, &collision, hitbox.maybe()).join()
+        {
+            let rect = collision_rect(pos, size, hitbox);
             ent_vec.push(EntityS {
-                rect: Rect::new(pos.0, pos.1, size.0 as u32, size.1 as u32),
+                rect,
                 entity,
                 collision,
             })
@@ -60,7 +84,7 @@ impl<'a> System<'a> for Physics {
                     Some(intersection) = entity_a.rect.intersection(entity_b.rect) => {
                         if let Some(Position(_, y)) = pos.get_mut(entity_a.entity) {
                             *y -= intersection.height() as i32;
-                            entity_a.rect.set_y(*y);
+                            entity_a.rect.offset(0, -(intersection.height() as i32));
                         }
                         if let Some(Velocity(_vx, vy)) = vel_storage.get_mut(entity_a.entity) {
                             *vy = 0;
@@ -69,6 +93,30 @@ impl<'a> System<'a> for Physics {
                 ];
             }
         }
+
+        // Solid tilemap cells behave like Static collisions.
+        let maps: Vec<((i32, i32), &Tilemap)> = (&pos, &tilemap)
+            .join()
+            .map(|(p, map)| ((p.0, p.1), map))
+            .collect();
+        for entity_a in ent_vec.iter_mut() {
+            lif! [Some(_) = entity_a.collision.0 => { continue }];
+            for (origin, map) in &maps {
+                for cell in map.solid_cells(*origin, entity_a.rect) {
+                    lif![
+                        Some(intersection) = entity_a.rect.intersection(cell) => {
+                            if let Some(Position(_, y)) = pos.get_mut(entity_a.entity) {
+                                *y -= intersection.height() as i32;
+                                entity_a.rect.offset(0, -(intersection.height() as i32));
+                            }
+                            if let Some(Velocity(_vx, vy)) = vel_storage.get_mut(entity_a.entity) {
+                                *vy = 0;
+                            }
+                        }
+                    ];
+                }
+            }
+        }
     }
 
     fn setup(&mut self, world: &mut World) {
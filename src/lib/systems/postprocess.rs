@@ -0,0 +1,249 @@
+use crate::lib::opengl::SDL2Facade;
+use crate::lib::systems::renderer::Vertex;
+
+use glium::texture::RawImage2d;
+use glium::texture::Texture2d;
+use glium::uniforms::{
+    MagnifySamplerFilter, MinifySamplerFilter, SamplerBehavior, SamplerWrapFunction, UniformValue,
+    Uniforms,
+};
+use glium::{IndexBuffer, Program, Surface, VertexBuffer};
+
+use std::collections::HashMap;
+
+// What a pass draws.
+#[derive(Debug, Clone)]
+pub enum Effect {
+    // Curvature, scanlines and a slight chromatic split.
+    Crt,
+    // Darkens the corners.
+    Vignette,
+    // Regrades colors through a lookup table strip, `size` x `size` squares of `size` pixels.
+    ColorLut(&'static str),
+    // Single pass glow around bright pixels.
+    Bloom,
+}
+
+/**
+ * A fullscreen pass run over the scene before it is shown, disabled until toggled on.
+ * Every pass samples the previous result as `scene`, with `resolution` (vec2) and
+ * `time` (float, seconds) set, plus one float uniform per entry of `params`.
+ */
+#[derive(Debug, Clone)]
+pub struct Pass {
+    pub name: &'static str,
+    pub effect: Effect,
+    pub enabled: bool,
+    pub params: Vec<(&'static str, f32)>,
+}
+
+impl Pass {
+    pub fn crt() -> Self {
+        Self::new(
+            "crt",
+            Effect::Crt,
+            &[
+                ("curvature", 0.08),
+                ("scanlines", 0.35),
+                ("aberration", 0.6),
+            ],
+        )
+    }
+
+    pub fn vignette() -> Self {
+        Self::new(
+            "vignette",
+            Effect::Vignette,
+            &[("radius", 0.75), ("softness", 0.45), ("strength", 0.6)],
+        )
+    }
+
+    pub fn color_lut(path: &'static str) -> Self {
+        Self::new(
+            "lut",
+            Effect::ColorLut(path),
+            &[("size", 16.0), ("strength", 1.0)],
+        )
+    }
+
+    pub fn bloom() -> Self {
+        Self::new(
+            "bloom",
+            Effect::Bloom,
+            &[("threshold", 0.7), ("intensity", 0.8), ("radius", 2.0)],
+        )
+    }
+
+    fn new(name: &'static str, effect: Effect, params: &[(&'static str, f32)]) -> Self {
+        Self {
+            name,
+            effect,
+            enabled: false,
+            params: params.to_vec(),
+        }
+    }
+
+    fn fragment(&self) -> &'static str {
+        match self.effect {
+            Effect::Crt => include_str!("graphics/post/crt.frag"),
+            Effect::Vignette => include_str!("graphics/post/vignette.frag"),
+            Effect::ColorLut(_) => include_str!("graphics/post/lut.frag"),
+            Effect::Bloom => include_str!("graphics/post/bloom.frag"),
+        }
+    }
+}
+
+/**
+ * The passes run over the scene each frame, in order.
+ * Change it at runtime to toggle passes or tweak their parameters, F5 to F8 toggle the
+ * passes of the game.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PostProcess {
+    pub passes: Vec<Pass>,
+}
+
+impl PostProcess {
+    pub fn pass_mut(&mut self, name: &str) -> Option<&mut Pass> {
+        self.passes.iter_mut().find(|p| p.name == name)
+    }
+
+    pub fn toggle(&mut self, name: &str) {
+        if let Some(pass) = self.pass_mut(name) {
+            pass.enabled = !pass.enabled;
+        }
+    }
+}
+
+struct PassUniforms<'a> {
+    scene: &'a Texture2d,
+    lut: Option<&'a Texture2d>,
+    resolution: [f32; 2],
+    time: f32,
+    params: &'a [(&'static str, f32)],
+}
+
+impl<'t> Uniforms for PassUniforms<'t> {
+    fn visit_values<'a, F: FnMut(&str, UniformValue<'a>)>(&'a self, mut output: F) {
+        let linear = Some(SamplerBehavior {
+            minify_filter: MinifySamplerFilter::Linear,
+            magnify_filter: MagnifySamplerFilter::Linear,
+            wrap_function: (
+                SamplerWrapFunction::Clamp,
+                SamplerWrapFunction::Clamp,
+                SamplerWrapFunction::Clamp,
+            ),
+            ..Default::default()
+        });
+        output("scene", UniformValue::Texture2d(self.scene, linear));
+        if let Some(lut) = self.lut {
+            output("lut", UniformValue::Texture2d(lut, linear));
+        }
+        output("resolution", UniformValue::Vec2(self.resolution));
+        output("time", UniformValue::Float(self.time));
+        for (name, value) in self.params {
+            output(name, UniformValue::Float(*value));
+        }
+    }
+}
+
+// GL side of the post processing: compiled passes, lookup tables and ping-pong targets.
+#[derive(Default)]
+pub struct PostChain {
+    // By fragment source, so passes with the same effect share a program and passes
+    // that only share a name don't. None when the shader failed to compile.
+    programs: HashMap<&'static str, Option<Program>>,
+    luts: HashMap<&'static str, Option<Texture2d>>,
+    targets: Vec<Texture2d>,
+}
+
+impl PostChain {
+    // Compiles new passes and sizes the targets to the scene.
+    fn prepare(&mut self, window: &SDL2Facade, passes: &[Pass], (w, h): (u32, u32)) {
+        for pass in passes.iter().filter(|p| p.enabled) {
+            self.programs.entry(pass.fragment()).or_insert_with(|| {
+                Program::from_source(
+                    window,
+                    include_str!("graphics/post/post.vert"),
+                    pass.fragment(),
+                    None,
+                )
+                .map_err(|e| eprintln!("Post process pass \"{}\": {}", pass.name, e))
+                .ok()
+            });
+            if let Effect::ColorLut(path) = pass.effect {
+                self.luts
+                    .entry(path)
+                    .or_insert_with(|| match image::open(path) {
+                        Ok(image) => {
+                            let image = image.to_rgba8();
+                            let dims = image.dimensions();
+                            let raw = RawImage2d::from_raw_rgba(image.into_raw(), dims);
+                            Texture2d::new(window, raw).ok()
+                        }
+                        Err(e) => {
+                            eprintln!("Cannot load color LUT {}: {}", path, e);
+                            None
+                        }
+                    });
+            }
+        }
+        if self.targets.first().map(|t| t.dimensions()) != Some((w, h)) {
+            self.targets = (0..2)
+                .map(|_| Texture2d::empty(window, w, h).unwrap())
+                .collect();
+        }
+    }
+
+    /**
+     * Runs the enabled passes over `scene`, alternating between two targets,
+     * and returns the texture holding the result. Passes that failed to load are skipped.
+     */
+    pub fn apply<'s>(
+        &'s mut self,
+        window: &SDL2Facade,
+        (vertex_buffer, index_buffer): (&VertexBuffer<Vertex>, &IndexBuffer<u16>),
+        scene: &'s Texture2d,
+        passes: &[Pass],
+        time: f32,
+    ) -> &'s Texture2d {
+        let (w, h) = scene.dimensions();
+        self.prepare(window, passes, (w, h));
+        let mut input = scene;
+        let mut next = 0;
+        for pass in passes.iter().filter(|p| p.enabled) {
+            let program = match &self.programs[pass.fragment()] {
+                Some(program) => program,
+                None => continue,
+            };
+            let lut = match pass.effect {
+                Effect::ColorLut(path) => match &self.luts[path] {
+                    Some(lut) => Some(lut),
+                    None => continue,
+                },
+                _ => None,
+            };
+            let target = &self.targets[next];
+            let uniforms = PassUniforms {
+                scene: input,
+                lut,
+                resolution: [w as f32, h as f32],
+                time,
+                params: &pass.params,
+            };
+            target
+                .as_surface()
+                .draw(
+                    vertex_buffer,
+                    index_buffer,
+                    program,
+                    &uniforms,
+                    &Default::default(),
+                )
+                .unwrap();
+            input = target;
+            next = 1 - next;
+        }
+        input
+    }
+}
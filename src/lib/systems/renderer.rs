@@ -1,17 +1,29 @@
+use crate::animation::AnimationEvent;
+use crate::animation::StateMachineConfig;
+use crate::aseprite::binary::AsepriteFile;
 use crate::aseprite::AsepriteJSON;
-use crate::aseprite::SpriteRect;
+use crate::game::Game;
+use crate::lib::logging::warn_once;
 
+use crate::lib::font::bitmap::BitmapFonts;
+use crate::lib::font::{FontManager, Text};
 use crate::lib::opengl::SDL2Facade;
-use crate::lib::sprite::Sprite;
+use crate::lib::sprite::{Frames, Sprite};
 
+use glium::framebuffer::MultiOutputFrameBuffer;
 use glium::implement_vertex;
 use glium::index::PrimitiveType;
 use glium::texture::MipmapsOption::NoMipmap;
 use glium::texture::RawImage2d;
+use glium::texture::Texture2d;
 use glium::texture::Texture2dArray;
+use glium::uniforms::MagnifySamplerFilter;
+use glium::uniforms::MinifySamplerFilter;
 use glium::Blend;
+use glium::BlitTarget;
 use glium::IndexBuffer;
 
+use glium::vertex::VertexBufferSlice;
 use glium::VertexBuffer;
 
 use glium::DrawParameters;
@@ -20,47 +32,67 @@ use glium::Surface;
 
 use glium::uniform;
 
-use image::GenericImageView;
+use image::RgbaImage;
 
+use sdl2::rect::Rect;
 use specs::prelude::*;
+use specs::shrev::EventChannel;
 
 use crate::InputHandler;
 use crate::Size;
 use crate::SpriteConfig;
 use crate::Velocity;
 
-use std::time::Duration;
-use std::time::Instant;
-
-use num_traits::One;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
 
 use specs::{Join, ReadStorage, System};
 
 use crate::lib::sprite::SpriteManager;
-use crate::lib::systems::components::{Position, SpriteHandle};
-
-use std::fs::File;
-use std::io::BufReader;
+use crate::lib::systems::camera::Camera;
+use crate::lib::systems::capture::{Capture, Recorder};
+use crate::lib::systems::components::{
+    Collision, Hitbox, Hurtbox, Layer, Position, RenderLayer, SpriteHandle, Tint, ZIndex,
+};
+use crate::lib::systems::debug::{self, DebugDraw};
+use crate::lib::systems::lighting::{self, AmbientLight, Lighting, PointLight};
+use crate::lib::systems::particles::{particle_draws, ParticleEmitter};
+use crate::lib::systems::postprocess::{PostChain, PostProcess};
+use crate::lib::systems::screen::Screen;
+use crate::lib::tilemap::Tilemap;
 
 #[derive(Debug, Clone)]
 pub struct TextureInfo {
     pub name: &'static str,
     pub path: &'static str,
     pub json: Option<AsepriteJSON>,
+    pub states: Option<Arc<StateMachineConfig>>,
 }
 
 type Str = &'static str;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 impl TextureInfo {
-    fn new(name: Str, path: Str, json_path: Option<Str>) -> Result<Self> {
+    fn new(name: Str, path: Str, json_path: Option<Str>, states_path: Option<Str>) -> Result<Self> {
         let mut info = TextureInfo {
             name,
             path,
             json: None,
+            states: None,
         };
         if let Some(path) = json_path {
-            let json_file = File::open(path)?;
-            info.json = serde_json::from_reader(BufReader::new(json_file))?;
+            info.json = Some(AsepriteJSON::open(path)?);
+        }
+        if let Some(path) = states_path {
+            let states = StateMachineConfig::open(path)?;
+            if let Some(json) = &info.json {
+                states
+                    .check_tags(&json.meta.frame_tags)
+                    .map_err(|e| eyre!("Invalid state machine {}: {}", path, e))?;
+            }
+            info.states = Some(Arc::new(states));
         }
 
         Ok(info)
@@ -74,32 +106,52 @@ impl From<SpriteConfig> for TextureInfo {
             name,
             path,
             json: None,
+            states: None,
         };
-        if let Ok(json_file) = File::open(json) {
-            info.json = serde_json::from_reader(BufReader::new(json_file)).unwrap();
+        if std::path::Path::new(json).exists() {
+            info.json = Some(AsepriteJSON::open(json).unwrap());
         }
 
         info
     }
 }
 
+// Layers whose sprites are drawn top to bottom, for top-down scenes.
+#[derive(Debug, Clone, Default)]
+pub struct YSort(pub HashSet<Layer>);
+
 lazy_static! {
     pub static ref TEXTURES: Vec<TextureInfo> = {
         let mut set = Vec::new();
         set.push(TextureInfo::new(
-            "chicken",                           // Name
-            "sprites/chicken_smear.png",         // Spritesheet path
-            Some("sprites/chicken_smear.json"),  // Json path (Only for animated sprite sheets)
+            "chicken",                                 // Name
+            "sprites/chicken_smear.png",               // Spritesheet path
+            Some("sprites/chicken_smear.json"),        // Json path (Only for animated sheets)
+            Some("sprites/chicken_smear.states.json"), // Animation state machine path
         ).unwrap());
         set.push(TextureInfo::new(
             "mushroom",
             "sprites/mushroom.png",
             Some("sprites/mushroom.json"),
+            None,
         ).unwrap());
         set.push(TextureInfo::new(
             "tile",
             "sprites/tile.png",
             Some("sprites/tile.json"),
+            None,
+        ).unwrap());
+        set.push(TextureInfo::new(
+            "tree",
+            "sprites/tree.aseprite", // Frame data is read from the .aseprite file itself
+            None,
+            None,
+        ).unwrap());
+        set.push(TextureInfo::new(
+            "particle",
+            "sprites/particle.png",
+            None,
+            None,
         ).unwrap());
         set
     };
@@ -110,6 +162,27 @@ pub struct Vertex {
     pos: [f32; 2],
 }
 implement_vertex!(Vertex, pos);
+
+// Per instance attributes of a sprite quad.
+#[derive(Clone, Copy, Debug)]
+pub struct SpriteInstance {
+    pub offset: [f32; 2],
+    pub scale: [f32; 2],
+    pub frame: i32,
+    pub tint: [f32; 4],
+}
+implement_vertex!(SpriteInstance, offset, scale, frame, tint);
+
+// Per instance attributes of a glyph quad, `source` being its rect in the glyph atlas.
+#[derive(Clone, Copy, Debug)]
+pub struct GlyphInstance {
+    pub offset: [f32; 2],
+    pub scale: [f32; 2],
+    pub source: [f32; 4],
+    pub color: [f32; 4],
+}
+implement_vertex!(GlyphInstance, offset, scale, source, color);
+
 // All relevant OpenGL objects needed for rendering.
 pub struct RenderSet<'a> {
     pub program: Program,
@@ -117,44 +190,181 @@ pub struct RenderSet<'a> {
     pub vertex_buffer: VertexBuffer<Vertex>,
     pub index_buffer: IndexBuffer<u16>,
     pub draw_params: DrawParameters<'a>,
+    // Instances of every sprite drawn in a frame, grown as needed and reused across frames.
+    pub instance_buffer: Option<VertexBuffer<SpriteInstance>>,
+    // Baked tilemap chunks and the chunk version they were baked from.
+    pub chunk_buffers: HashMap<(Entity, usize), (u32, VertexBuffer<SpriteInstance>)>,
+    pub post: PostChain,
+    // Draws the lines of the debug overlay.
+    pub debug_program: Program,
+    pub text_program: Program,
+    pub glyph_buffer: Option<VertexBuffer<GlyphInstance>>,
+    // The FontManager's atlas and the version it was uploaded from.
+    pub glyph_atlas: Option<(u32, Texture2d)>,
+    pub lighting: Lighting,
+}
+
+// Something to draw, in draw order.
+pub enum Draw {
+    Sprite(SpriteInstance),
+    Chunk(Entity, usize),
+}
+
+enum Batch<'a> {
+    // A run of instances of the frame's instance buffer.
+    Sprites(&'a Sprite, Layer, Range<usize>),
+    Chunk(&'a Sprite, Layer, Entity, usize),
+    // A run of glyphs of the frame's glyph buffer.
+    Text(Layer, Range<usize>),
+}
+
+impl<'a> Batch<'a> {
+    fn layer(&self) -> Layer {
+        match self {
+            Batch::Sprites(_, layer, _) | Batch::Chunk(_, layer, ..) | Batch::Text(layer, _) => {
+                *layer
+            }
+        }
+    }
 }
 
 pub struct Renderer<'a> {
     pub sprite_manager: &'a mut SpriteManager,
     pub window: SDL2Facade,
     pub render_set: Option<RenderSet<'a>>,
-    pub now: Instant,
+    // Game ticks the animations have been advanced through.
+    pub ticks: usize,
+    // The scene is drawn here at the virtual resolution, then scaled into the window.
+    pub offscreen: Option<Texture2d>,
+    pub recorder: Recorder,
+    pub fonts: FontManager<'a>,
 }
 
-fn load_sprite(display: &SDL2Facade, info: &TextureInfo) -> Sprite {
+fn is_aseprite(path: &str) -> bool {
+    path.ends_with(".aseprite") || path.ends_with(".ase")
+}
+
+fn load_aseprite(info: &TextureInfo) -> Sprite {
     let path = info.path;
+    let file = AsepriteFile::open(path).unwrap_or_else(|e| panic!("{}", e));
+    if let Some(states) = &info.states {
+        if let Err(e) = states.check_tags(&file.json.meta.frame_tags) {
+            panic!("Invalid state machine for {}: {}", path, e);
+        }
+    }
+    let mut info = info.clone();
+    info.json = Some(file.json);
+    Sprite {
+        texture: Frames::Cpu(file.images),
+        normals: None,
+        info,
+    }
+}
+
+// Cuts a sheet into its frames, the whole image being the only frame without JSON.
+fn sheet_frames(image: RgbaImage, json: Option<&AsepriteJSON>) -> Vec<RgbaImage> {
+    match json {
+        // Every layer of the array has the same size, so trimmed frames
+        // are padded back out to their untrimmed source size.
+        Some(json) => {
+            let canvas = json.canvas_size();
+            json.frames
+                .iter()
+                .map(|frame| frame.extract(&image, canvas))
+                .collect()
+        }
+        None => vec![image],
+    }
+}
+
+// Decodes the frames of a sprite into memory, as drawn by the SoftwareRenderer.
+pub fn load_frames(info: &TextureInfo) -> Sprite {
+    let path = info.path;
+    if is_aseprite(path) {
+        return load_aseprite(info);
+    }
     let image = image::open(path)
         .unwrap_or_else(|_| panic!("Cannot find {}", path))
         .to_rgba8();
-    let texture: Texture2dArray = {
-        if let Some(json) = &info.json {
-            let sub_images = json
-                .frames
-                .iter()
-                .map(|frame| {
-                    let SpriteRect { x, y, w, h } = frame.frame;
-                    let sub = image.view(x as u32, y as u32, w, h).to_image();
-                    let dims = sub.dimensions();
-                    let raw = sub.into_raw();
-                    RawImage2d::from_raw_rgba(raw, dims)
-                })
-                .collect();
-            Texture2dArray::with_mipmaps(display, sub_images, NoMipmap).unwrap()
-        } else {
-            let dims = image.dimensions();
-            let raw = image.into_raw();
-            let raw = RawImage2d::from_raw_rgba(raw, dims);
-            Texture2dArray::with_mipmaps(display, vec![raw], NoMipmap).unwrap()
+    Sprite {
+        texture: Frames::Cpu(sheet_frames(image, info.json.as_ref())),
+        normals: None,
+        info: info.clone(),
+    }
+}
+
+// Where the normal map of a sheet goes, ie. sprites/chicken.normal.png for sprites/chicken.png.
+pub fn normal_map_path(path: &str) -> String {
+    let path = std::path::Path::new(path);
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("png");
+    path.with_extension(format!("normal.{}", extension))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/**
+ * The frames of the sprite's normal map, if it has one, cut like its color frames.
+ * Maps whose frames don't match the colors are skipped, the sprite is then lit flat.
+ */
+fn load_normals(info: &TextureInfo, frames: &[RgbaImage]) -> Option<Vec<RgbaImage>> {
+    let path = normal_map_path(info.path);
+    if !std::path::Path::new(&path).exists() {
+        return None;
+    }
+    let normals = if is_aseprite(&path) {
+        AsepriteFile::open(&path)
+            .map(|file| file.images)
+            .map_err(|e| e.to_string())
+    } else {
+        image::open(&path)
+            .map(|image| sheet_frames(image.to_rgba8(), info.json.as_ref()))
+            .map_err(|e| e.to_string())
+    };
+    let normals = match normals {
+        Ok(normals) => normals,
+        Err(e) => {
+            eprintln!("Cannot load normal map {}: {}", path, e);
+            return None;
         }
     };
+    let size = |images: &[RgbaImage]| images.first().map(|i| i.dimensions());
+    if normals.len() != frames.len() || size(&normals) != size(frames) {
+        eprintln!(
+            "Normal map {} has {} frames of {:?}, \"{}\" has {} of {:?}",
+            path,
+            normals.len(),
+            size(&normals),
+            info.name,
+            frames.len(),
+            size(frames)
+        );
+        return None;
+    }
+    Some(normals)
+}
+
+fn texture_array(display: &SDL2Facade, frames: Vec<RgbaImage>) -> Texture2dArray {
+    let sub_images = frames
+        .into_iter()
+        .map(|image| {
+            let dims = image.dimensions();
+            RawImage2d::from_raw_rgba(image.into_raw(), dims)
+        })
+        .collect();
+    Texture2dArray::with_mipmaps(display, sub_images, NoMipmap).unwrap()
+}
+
+fn load_sprite(display: &SDL2Facade, info: &TextureInfo) -> Sprite {
+    let Sprite { texture, info, .. } = load_frames(info);
+    let frames = match texture {
+        Frames::Cpu(frames) => frames,
+        Frames::Gpu(_) => unreachable!(),
+    };
+    let normals = load_normals(&info, &frames).map(|normals| texture_array(display, normals));
     Sprite {
-        texture,
-        info: info.clone(),
+        texture: Frames::Gpu(texture_array(display, frames)),
+        normals,
+        info,
     }
 }
 
@@ -169,6 +379,20 @@ impl<'a> Renderer<'a> {
             None,
         )
         .unwrap();
+        let text_program = Program::from_source(
+            &self.window,
+            include_str!("graphics/text.vert"),
+            include_str!("graphics/text.frag"),
+            None,
+        )
+        .unwrap();
+        let debug_program = Program::from_source(
+            &self.window,
+            include_str!("graphics/debug.vert"),
+            include_str!("graphics/debug.frag"),
+            None,
+        )
+        .unwrap();
 
         let sprites = {
             let textures = &TEXTURES;
@@ -212,103 +436,757 @@ impl<'a> Renderer<'a> {
                 blend: Blend::alpha_blending(),
                 ..Default::default()
             },
+            instance_buffer: None,
+            chunk_buffers: HashMap::new(),
+            post: Default::default(),
+            debug_program,
+            text_program,
+            glyph_buffer: None,
+            glyph_atlas: None,
+            lighting: Lighting::new(&self.window),
         });
 
         println!("INIT DONE");
     }
 
-    fn draw_sprite(
-        frame: &mut glium::Frame,
-        texture: &Sprite,
+    // Uploads the instances of every batch drawn this frame, growing the buffer as needed.
+    pub fn upload<T: glium::Vertex + Send + 'static>(
+        window: &SDL2Facade,
+        buffer: &mut Option<VertexBuffer<T>>,
+        instances: &[T],
+    ) {
+        if buffer.as_ref().map_or(0, |b| b.len()) < instances.len() {
+            let capacity = instances.len().next_power_of_two();
+            *buffer = Some(VertexBuffer::empty_dynamic(window, capacity).unwrap());
+        }
+        if let Some(buffer) = buffer {
+            buffer.slice(0..instances.len()).unwrap().write(instances);
+        }
+    }
+
+    // Rebuilds a chunk of `map` if its tiles changed since it was last baked.
+    fn bake_chunk(
+        window: &SDL2Facade,
+        render_set: &mut RenderSet,
+        entity: Entity,
+        map: &Tilemap,
+        origin: (i32, i32),
+        chunk: usize,
+    ) {
+        let version = map.chunk_version(chunk);
+        match render_set.chunk_buffers.get(&(entity, chunk)) {
+            Some((baked, _)) if *baked == version => return,
+            _ => {}
+        }
+        let instances = chunk_instances(map, origin, chunk);
+        if instances.is_empty() {
+            render_set.chunk_buffers.remove(&(entity, chunk));
+        } else {
+            let buffer = VertexBuffer::new(window, &instances).unwrap();
+            render_set
+                .chunk_buffers
+                .insert((entity, chunk), (version, buffer));
+        }
+    }
+
+    // Draws `instances` of `sprite` with a single instanced call.
+    fn draw_batch(
+        frame: &mut impl Surface,
+        sprite: &Sprite,
         render_set: &RenderSet,
-        ((x, y), (w, h), frame_index): ((i32, i32), (f32, f32), usize),
+        instances: VertexBufferSlice<SpriteInstance>,
+        projection: &glm::Mat4x4,
     ) {
-        use nalgebra_glm::{scale, translate, vec3};
-        let mut model = nalgebra_glm::Mat4x4::one();
         let RenderSet {
             program,
-            projection,
             vertex_buffer,
             index_buffer,
             draw_params,
+            ..
         } = render_set;
-        model = translate(&model, &vec3(x as f32, y as f32, 0.0));
-        model = scale(&model, &vec3(w, h, 1.0));
+        let slice = instances;
+        let normals = sprite.normals.as_ref().unwrap_or(&render_set.lighting.flat);
+        let uniforms = uniform! {
+            projection: projection.data.0,
+            image: sprite.sampler(),
+            normals: normals
+                .sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+        };
+        frame
+            .draw(
+                (vertex_buffer, slice.per_instance().unwrap()),
+                index_buffer,
+                program,
+                &uniforms,
+                draw_params,
+            )
+            .unwrap()
+    }
+
+    // Draws a run of the frame's glyphs, sampling the atlas without filtering like sprites.
+    fn draw_glyphs(
+        frame: &mut impl Surface,
+        render_set: &RenderSet,
+        range: Range<usize>,
+        projection: &glm::Mat4x4,
+    ) {
+        let (atlas, buffer) = match (&render_set.glyph_atlas, &render_set.glyph_buffer) {
+            (Some((_, atlas)), Some(buffer)) => (atlas, buffer),
+            _ => return,
+        };
+        let (w, h) = atlas.dimensions();
         let uniforms = uniform! {
-            model: model.data.0,
             projection: projection.data.0,
-            image: texture.sampler(),
-            index: frame_index as i32
+            atlas_size: [w as f32, h as f32],
+            atlas: atlas
+                .sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
         };
         frame
-            .draw(vertex_buffer, index_buffer, program, &uniforms, draw_params)
+            .draw(
+                (
+                    &render_set.vertex_buffer,
+                    buffer.slice(range).unwrap().per_instance().unwrap(),
+                ),
+                &render_set.index_buffer,
+                &render_set.text_program,
+                &uniforms,
+                &render_set.draw_params,
+            )
             .unwrap()
     }
+
+    // Loads a sprite after setup, ie. the tilesets of a map. Loaded sprites are kept as is.
+    pub fn load(&mut self, info: &TextureInfo) {
+        if self.sprite_manager.get(info.name).is_none() {
+            let sprite = load_sprite(&self.window, info);
+            self.sprite_manager.add(sprite);
+        }
+    }
+
+    /**
+     * Advances the animations to the current game tick, hit and hurt boxes with them.
+     * Run it on every tick before Physics, so collisions never depend on when frames are drawn.
+     */
+    pub fn animate(&mut self, world: &World) {
+        let (mut data, game, mut events) = world.system_data::<AnimationData>();
+        animate(
+            self.sprite_manager,
+            &mut self.ticks,
+            &game,
+            &mut data,
+            &mut events,
+        );
+    }
     fn _debug_info(&mut self) {}
 }
 
-type EntityData<'s> = (
+pub type EntityData<'s> = (
+    Entities<'s>,
     ReadStorage<'s, Position>,
     ReadStorage<'s, Velocity>,
     ReadStorage<'s, Size>,
     ReadStorage<'s, SpriteHandle>,
     ReadStorage<'s, InputHandler>,
+    WriteStorage<'s, Hitbox>,
+    WriteStorage<'s, Hurtbox>,
 );
+// Layer, then z index, then the bottom edge on y-sorted layers.
+pub type DrawKey = (Layer, i32, i32);
+
+pub const CLEAR_COLOR: [f32; 4] = [0.1, 0.1, 0.2, 1.0];
+
+// One quad per tile of a tilemap chunk, placed with the map's top left corner at `origin`.
+pub fn chunk_instances(map: &Tilemap, (ox, oy): (i32, i32), chunk: usize) -> Vec<SpriteInstance> {
+    let (tw, th) = map.tile_size;
+    map.chunk_tiles(chunk)
+        .map(|(x, y, tile)| SpriteInstance {
+            offset: [
+                (ox + x as i32 * tw as i32) as f32,
+                (oy + y as i32 * th as i32) as f32,
+            ],
+            scale: [tw as f32, th as f32],
+            frame: tile as i32,
+            tint: [1.0; 4],
+        })
+        .collect()
+}
+
+// What animate needs, fetched on its own so it runs on the ticks rather than in a render system.
+pub type AnimationData<'s> = (
+    EntityData<'s>,
+    Read<'s, Game>,
+    Write<'s, EventChannel<AnimationEvent>>,
+);
+
+/**
+ * Steps every animation through the game ticks since `*ticks`, once per tick by the
+ * tick length, never by real frame time, so every run sees the same frame indices.
+ * Hit and hurt boxes follow the slices of the current frame.
+ */
+pub fn animate(
+    sprite_manager: &mut SpriteManager,
+    ticks: &mut usize,
+    game: &Game,
+    data: &mut EntityData,
+    animation_events: &mut EventChannel<AnimationEvent>,
+) {
+    let (entities, _, velocity, size, sprite_handle, input_handler, hitbox, hurtbox) = data;
+    let tick = game.tick_length();
+    let pending = game.ticks.saturating_sub(*ticks);
+    *ticks = game.ticks;
+    for (entity, velocity, input, size, handle, hitbox, hurtbox) in (
+        &*entities,
+        (&*velocity).maybe(),
+        (&*input_handler).maybe(),
+        &*size,
+        &*sprite_handle,
+        (&mut *hitbox).maybe(),
+        (&mut *hurtbox).maybe(),
+    )
+        .join()
+    {
+        for _ in 0..pending {
+            if let Err(e) = sprite_manager.signal(handle, &(input, velocity)) {
+                eprintln!("{}", e);
+                break;
+            }
+            sprite_manager
+                .next_frame(entity, handle, tick, animation_events)
+                .unwrap();
+        }
+        if let Some(Hitbox(rect)) = hitbox {
+            *rect = sprite_manager.slice_rect(handle, size, "hitbox");
+        }
+        if let Some(Hurtbox(rect)) = hurtbox {
+            *rect = sprite_manager.slice_rect(handle, size, "hurtbox");
+        }
+    }
+}
+
+/**
+ * A quad for the current frame of every sprite.
+ * Position is only advanced on ticks, it is extrapolated by velocity
+ * for the `alpha` of a tick since the last one.
+ */
+pub fn sprite_draws<'m>(
+    sprite_manager: &'m SpriteManager,
+    data: &EntityData,
+    (tint, render_layer, z_index): (
+        &ReadStorage<Tint>,
+        &ReadStorage<RenderLayer>,
+        &ReadStorage<ZIndex>,
+    ),
+    y_sort: &YSort,
+    alpha: f64,
+) -> Vec<(DrawKey, &'m Sprite, Draw)> {
+    let (_, position, velocity, size, sprite_handle, ..) = data;
+    let interpolate = |(x, y): (i32, i32), (vx, vy): (i32, i32)| {
+        (
+            x + (vx as f64 * alpha) as i32,
+            y + (vy as f64 * alpha) as i32,
+        )
+    };
+    let mut draws = Vec::new();
+    for (pos, velocity, size, handle, tint, layer, z) in (
+        position,
+        velocity.maybe(),
+        size,
+        sprite_handle,
+        tint.maybe(),
+        render_layer.maybe(),
+        z_index.maybe(),
+    )
+        .join()
+    {
+        let (mut x, mut y) = (pos.0, pos.1);
+        let frame_index = match sprite_manager.state(handle) {
+            Ok(state) => state.frame_i,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        if let Some(Velocity(vx, vy)) = velocity {
+            let (dx, dy) = interpolate((x, y), (*vx, *vy));
+            x = dx;
+            y = dy;
+        }
+        let (ox, oy) = sprite_manager.origin(handle, size);
+        let sprite = sprite_manager.sprite(handle).unwrap();
+        let instance = SpriteInstance {
+            offset: [(x + ox) as f32, (y + oy) as f32],
+            scale: [size.0 as f32, size.1 as f32],
+            frame: frame_index as i32,
+            tint: tint.map_or([1.0; 4], |t| t.0),
+        };
+        let layer = layer.map_or_else(Layer::default, |l| l.0);
+        let z = z.map_or(0, |z| z.0);
+        let row = if y_sort.0.contains(&layer) {
+            y + size.1
+        } else {
+            0
+        };
+        draws.push(((layer, z, row), sprite, Draw::Sprite(instance)));
+    }
+    draws
+}
+
+/**
+ * The chunks of every tilemap that overlap `view` and for which `drawn(entity, map, origin,
+ * chunk)` holds, which is where a backend can prepare them and skip empty ones.
+ */
+pub fn tilemap_draws<'m>(
+    sprite_manager: &'m SpriteManager,
+    (entities, tilemap, position): (&Entities, &ReadStorage<Tilemap>, &ReadStorage<Position>),
+    (render_layer, z_index): (&ReadStorage<RenderLayer>, &ReadStorage<ZIndex>),
+    view: Rect,
+    mut drawn: impl FnMut(Entity, &Tilemap, (i32, i32), usize) -> bool,
+) -> Vec<(DrawKey, &'m Sprite, Draw)> {
+    let mut draws = Vec::new();
+    for (entity, map, pos, layer, z) in (
+        entities,
+        tilemap,
+        position,
+        render_layer.maybe(),
+        z_index.maybe(),
+    )
+        .join()
+    {
+        // Collision only maps are never drawn.
+        if map.tileset.is_empty() {
+            continue;
+        }
+        let sprite = match sprite_manager.get(map.tileset) {
+            Some(sprite) => sprite,
+            None => {
+                warn_once(format!("Tilemap tileset \"{}\" is not loaded", map.tileset));
+                continue;
+            }
+        };
+        let layer = layer.map_or_else(Layer::default, |l| l.0);
+        let z = z.map_or(0, |z| z.0);
+        for chunk in 0..map.chunks() {
+            let mut rect = map.chunk_rect(chunk);
+            rect.offset(pos.0, pos.1);
+            // Chunks off camera are skipped, the UI layer is not moved by the camera.
+            if layer != Layer::UI && !rect.has_intersection(view) {
+                continue;
+            }
+            if drawn(entity, map, (pos.0, pos.1), chunk) {
+                draws.push(((layer, z, 0), sprite, Draw::Chunk(entity, chunk)));
+            }
+        }
+    }
+    draws
+}
+
+// Where a Text is drawn, extrapolated by velocity like sprites.
+pub fn text_position(pos: &Position, velocity: Option<&Velocity>, alpha: f64) -> (i32, i32) {
+    let (mut x, mut y) = (pos.0, pos.1);
+    if let Some(Velocity(vx, vy)) = velocity {
+        x += (*vx as f64 * alpha) as i32;
+        y += (*vy as f64 * alpha) as i32;
+    }
+    (x, y)
+}
+
+fn text_key(
+    (layer, z): (Option<&RenderLayer>, Option<&ZIndex>),
+    y_sort: &YSort,
+    y: i32,
+) -> DrawKey {
+    let layer = layer.map_or_else(Layer::default, |l| l.0);
+    let z = z.map_or(0, |z| z.0);
+    let row = if y_sort.0.contains(&layer) { y } else { 0 };
+    (layer, z, row)
+}
+
+/**
+ * A quad for every glyph of the Text drawn with BitmapFonts, as sprites of the font.
+ * Fonts that are not loaded yet are skipped.
+ */
+pub fn bitmap_text_draws<'m>(
+    sprite_manager: &'m SpriteManager,
+    fonts: &BitmapFonts,
+    data: &EntityData,
+    text: &ReadStorage<Text>,
+    (render_layer, z_index): (&ReadStorage<RenderLayer>, &ReadStorage<ZIndex>),
+    y_sort: &YSort,
+    alpha: f64,
+) -> Vec<(DrawKey, &'m Sprite, Draw)> {
+    let (_, position, velocity, ..) = data;
+    let mut draws = Vec::new();
+    for (text, pos, velocity, layer, z) in (
+        text,
+        position,
+        velocity.maybe(),
+        render_layer.maybe(),
+        z_index.maybe(),
+    )
+        .join()
+    {
+        let (font, sprite) = match fonts.get(text.font) {
+            Some(font) => match sprite_manager.get(font.name) {
+                Some(sprite) => (font, sprite),
+                None => continue,
+            },
+            None => continue,
+        };
+        let (x, y) = text_position(pos, velocity, alpha);
+        let key = text_key((layer, z), y_sort, y);
+        let scale = font.scale(text.size);
+        let (w, h) = font.canvas;
+        for (frame, (dx, dy)) in font.layout(text) {
+            let instance = SpriteInstance {
+                offset: [(x + dx) as f32, (y + dy) as f32],
+                scale: [(w as i32 * scale) as f32, (h as i32 * scale) as f32],
+                frame: frame as i32,
+                tint: text.color,
+            };
+            draws.push((key, sprite, Draw::Sprite(instance)));
+        }
+    }
+    draws
+}
+
+/**
+ * The glyphs of every Text drawn with a TTF font, in one list,
+ * and the range of each text with its draw key.
+ */
+fn text_draws(
+    fonts: &mut FontManager,
+    bitmap_fonts: &BitmapFonts,
+    data: &EntityData,
+    text: &ReadStorage<Text>,
+    (render_layer, z_index): (&ReadStorage<RenderLayer>, &ReadStorage<ZIndex>),
+    y_sort: &YSort,
+    alpha: f64,
+) -> (Vec<GlyphInstance>, Vec<(DrawKey, Range<usize>)>) {
+    let (_, position, velocity, ..) = data;
+    let mut glyphs = Vec::new();
+    let mut texts = Vec::new();
+    for (text, pos, velocity, layer, z) in (
+        text,
+        position,
+        velocity.maybe(),
+        render_layer.maybe(),
+        z_index.maybe(),
+    )
+        .join()
+    {
+        if bitmap_fonts.get(text.font).is_some() {
+            continue;
+        }
+        let (x, y) = text_position(pos, velocity, alpha);
+        let start = glyphs.len();
+        for quad in fonts.layout(text) {
+            let source = quad.source;
+            glyphs.push(GlyphInstance {
+                offset: [x as f32 + quad.offset.0, y as f32 + quad.offset.1],
+                scale: [quad.size.0, quad.size.1],
+                source: [
+                    source.x() as f32,
+                    source.y() as f32,
+                    source.width() as f32,
+                    source.height() as f32,
+                ],
+                color: text.color,
+            });
+        }
+        if glyphs.len() > start {
+            texts.push((text_key((layer, z), y_sort, y), start..glyphs.len()));
+        }
+    }
+    // Stable, so ties keep the join order.
+    texts.sort_by_key(|(key, _)| *key);
+    (glyphs, texts)
+}
+
 impl<'a, 's> System<'s> for Renderer<'a> {
     type SystemData = (
         EntityData<'s>,
+        ReadStorage<'s, Tint>,
+        ReadStorage<'s, RenderLayer>,
+        ReadStorage<'s, ZIndex>,
+        ReadStorage<'s, Tilemap>,
+        Read<'s, YSort>,
+        Read<'s, Game>,
+        Read<'s, Camera>,
+        Write<'s, Screen>,
+        Read<'s, PostProcess>,
+        Write<'s, Capture>,
+        Read<'s, DebugDraw>,
+        ReadStorage<'s, Collision>,
+        ReadStorage<'s, Text>,
+        Read<'s, BitmapFonts>,
+        ReadStorage<'s, ParticleEmitter>,
+        ReadStorage<'s, PointLight>,
+        ReadStorage<'s, AmbientLight>,
         Write<'s, egui::CtxRef>,
         Read<'s, egui::RawInput>,
     );
     fn run(
         &mut self,
         (
-            (position, velocity, size, sprite_handle, input_handler),
+            entity_data,
+            tint,
+            render_layer,
+            z_index,
+            tilemap,
+            y_sort,
+            game,
+            camera,
+            mut screen,
+            post_process,
+            mut capture,
+            debug_draw,
+            collision,
+            text,
+            bitmap_fonts,
+            particles,
+            point_light,
+            ambient_light,
             _egui_context,
             _egui_raw_input,
         ): Self::SystemData,
     ) {
-        let elapsed = self.now.elapsed();
-        // given Position and Velocity, calculate the "delta-frame"
-        let interpolate = |(x, y): (i32, i32), (vx, vy): (i32, i32)| {
-            (
-                x + (vx as f64 * elapsed.as_secs_f64()) as i32,
-                y + (vy as f64 * elapsed.as_secs_f64()) as i32,
-            )
-        };
+        for font in bitmap_fonts.0.values() {
+            self.load(&font.texture);
+        }
+        let alpha = game.interpolation();
+        let (entities, _, _, _, sprite_handle, ..) = &entity_data;
+        self.sprite_manager.release_deleted(entities, sprite_handle);
+        let (entities, position, ..) = &entity_data;
 
-        let mut target = self.window.draw();
+        // Picks up resizes and moves to displays with a different DPI.
+        screen.window = self.window.window_size();
+        screen.drawable = self.window.drawable_size();
+        let (vw, vh) = screen.resolution;
+        if self.offscreen.as_ref().map(|t| t.dimensions()) != Some((vw, vh)) {
+            self.offscreen = Some(Texture2d::empty(&self.window, vw, vh).unwrap());
+        }
+        let offscreen = self.offscreen.as_ref().unwrap();
+        let mut scene = offscreen.as_surface();
         // Clear Screen
-        target.clear_color(0.1, 0.1, 0.2, 1.0);
-
-        for (pos, velocity, input, size, handle) in (
-            &position,
-            velocity.maybe(),
-            input_handler.maybe(),
-            &size,
-            &sprite_handle,
-        )
-            .join()
-        {
-            let (mut x, mut y) = (pos.0, pos.1);
+        let [r, g, b, a] = CLEAR_COLOR;
+        scene.clear_color(r, g, b, a);
+
+        // The sort is stable, so ties keep the join order.
+        let mut draws = sprite_draws(
+            self.sprite_manager,
+            &entity_data,
+            (&tint, &render_layer, &z_index),
+            &y_sort,
+            alpha,
+        );
 
-            if let Some(signal) = input {
-                self.sprite_manager.signal(handle, signal.0.as_ref());
+        let render_set = self.render_set.as_mut().unwrap();
+        render_set.projection = camera.view_projection(&screen, alpha);
+        render_set
+            .chunk_buffers
+            .retain(|(e, _), _| entities.is_alive(*e) && tilemap.contains(*e));
+        let window = &self.window;
+        draws.extend(tilemap_draws(
+            self.sprite_manager,
+            (entities, &tilemap, position),
+            (&render_layer, &z_index),
+            camera.visible(&screen, alpha),
+            |entity, map, origin, chunk| {
+                Self::bake_chunk(window, render_set, entity, map, origin, chunk);
+                render_set.chunk_buffers.contains_key(&(entity, chunk))
+            },
+        ));
+        draws.extend(bitmap_text_draws(
+            self.sprite_manager,
+            &bitmap_fonts,
+            &entity_data,
+            &text,
+            (&render_layer, &z_index),
+            &y_sort,
+            alpha,
+        ));
+        draws.extend(particle_draws(
+            self.sprite_manager,
+            &particles,
+            (&render_layer, &z_index),
+            alpha,
+        ));
+        draws.sort_by_key(|(key, ..)| *key);
+
+        let mut debug_shapes = vec![];
+        if debug_draw.enabled {
+            debug_shapes.extend(debug::overlays(
+                &debug_draw,
+                &entity_data,
+                (&collision, &tilemap),
+                camera.visible(&screen, alpha),
+            ));
+            if debug_draw.sprite_bounds {
+                debug_shapes.extend(debug::sprite_bounds(&draws));
+            }
+        }
+
+        let (glyphs, texts) = text_draws(
+            &mut self.fonts,
+            &bitmap_fonts,
+            &entity_data,
+            &text,
+            (&render_layer, &z_index),
+            &y_sort,
+            alpha,
+        );
+        let mut texts = texts.into_iter().peekable();
+
+        // Consecutive sprites of the same sheet on the same layer share one instanced call.
+        // Text goes after the sprites it ties with.
+        let mut instances: Vec<SpriteInstance> = Vec::new();
+        let mut batches: Vec<Batch> = Vec::new();
+        for (key, sprite, draw) in draws {
+            while texts.peek().is_some_and(|(text_key, _)| *text_key < key) {
+                let ((layer, ..), range) = texts.next().unwrap();
+                batches.push(Batch::Text(layer, range));
             }
+            let (layer, ..) = key;
+            match draw {
+                Draw::Sprite(instance) => {
+                    instances.push(instance);
+                    let end = instances.len();
+                    match batches.last_mut() {
+                        Some(Batch::Sprites(last, last_layer, range))
+                            if last.info.name == sprite.info.name && *last_layer == layer =>
+                        {
+                            range.end = end
+                        }
+                        _ => batches.push(Batch::Sprites(sprite, layer, end - 1..end)),
+                    }
+                }
+                Draw::Chunk(entity, chunk) => {
+                    batches.push(Batch::Chunk(sprite, layer, entity, chunk))
+                }
+            }
+        }
+        batches.extend(texts.map(|((layer, ..), range)| Batch::Text(layer, range)));
 
-            // TODO -- fix timing?
-            let (sprite, frame_index) = self
-                .sprite_manager
-                .next_frame(handle, Duration::from_secs_f64(1.0 / 60.0));
-            if let Some(Velocity(vx, vy)) = velocity {
-                let (dx, dy) = interpolate((x, y), (*vx, *vy));
-                x = dx;
-                y = dy;
+        // The UI layer is placed in screen space, at the virtual resolution.
+        let (vw, vh) = (vw as f32, vh as f32);
+        let ui_projection = glm::ortho(0.0, vw, vh, 0.0, -1.0, 1.0);
+        if !instances.is_empty() {
+            Self::upload(&self.window, &mut render_set.instance_buffer, &instances);
+        }
+        if !glyphs.is_empty() {
+            Self::upload(&self.window, &mut render_set.glyph_buffer, &glyphs);
+            let version = self.fonts.version();
+            if render_set.glyph_atlas.as_ref().map(|(v, _)| *v) != Some(version) {
+                let atlas = self.fonts.atlas();
+                let raw = RawImage2d::from_raw_rgba(atlas.to_vec(), atlas.dimensions());
+                let texture = Texture2d::new(&self.window, raw).unwrap();
+                render_set.glyph_atlas = Some((version, texture));
             }
-            let info = ((x, y), (size.0 as f32, size.1 as f32), frame_index);
-            Self::draw_sprite(&mut target, sprite, self.render_set.as_ref().unwrap(), info);
         }
+        let (_, position, velocity, ..) = &entity_data;
+        let lights = lighting::lights((position, velocity), (&point_light, &ambient_light), alpha);
+        if let Some((_, lights)) = &lights {
+            render_set
+                .lighting
+                .prepare(&self.window, offscreen.dimensions(), lights);
+        }
+        let render_set = &*render_set;
+        // While lit, sprites and text also write their normals until the lights are applied
+        // under the UI.
+        let mut gbuffer = lights.as_ref().map(|_| {
+            let normals = render_set.lighting.normals.as_ref().unwrap();
+            let outputs = vec![("color", offscreen), ("normal", normals)];
+            MultiOutputFrameBuffer::new(window, outputs).unwrap()
+        });
+        let mut unlit = lights.map(|(ambient, lights)| (ambient, lights.len()));
+        let apply_lights = |ambient| {
+            render_set.lighting.apply(
+                (&render_set.vertex_buffer, &render_set.index_buffer),
+                offscreen,
+                &render_set.projection,
+                ambient,
+            )
+        };
+        for batch in batches {
+            let projection = match batch.layer() {
+                Layer::UI => &ui_projection,
+                _ => &render_set.projection,
+            };
+            if batch.layer() == Layer::UI {
+                if let Some(ambient) = unlit.take() {
+                    gbuffer = None;
+                    apply_lights(ambient);
+                }
+            }
+            let (sprite, slice) = match batch {
+                Batch::Sprites(sprite, _, range) => {
+                    let buffer = render_set.instance_buffer.as_ref().unwrap();
+                    (sprite, buffer.slice(range).unwrap())
+                }
+                Batch::Chunk(sprite, _, entity, chunk) => {
+                    let (_, buffer) = &render_set.chunk_buffers[&(entity, chunk)];
+                    (sprite, buffer.slice(..).unwrap())
+                }
+                Batch::Text(_, range) => {
+                    match &mut gbuffer {
+                        Some(gbuffer) => Self::draw_glyphs(gbuffer, render_set, range, projection),
+                        None => Self::draw_glyphs(&mut scene, render_set, range, projection),
+                    }
+                    continue;
+                }
+            };
+            match &mut gbuffer {
+                Some(gbuffer) => Self::draw_batch(gbuffer, sprite, render_set, slice, projection),
+                None => Self::draw_batch(&mut scene, sprite, render_set, slice, projection),
+            }
+        }
+        drop(gbuffer);
+        if let Some(ambient) = unlit {
+            apply_lights(ambient);
+        }
+        drop(scene);
+
+        let render_set = self.render_set.as_mut().unwrap();
+        let time = (game.time - game.start_system_time).as_secs_f32();
+        let output = render_set.post.apply(
+            &self.window,
+            (&render_set.vertex_buffer, &render_set.index_buffer),
+            offscreen,
+            &post_process.passes,
+            time,
+        );
+        if debug_draw.enabled {
+            let vertices = debug::tessellate(debug_draw.shapes().iter().chain(&debug_shapes));
+            debug::draw_lines(
+                &self.window,
+                &mut output.as_surface(),
+                &render_set.debug_program,
+                &vertices,
+                &render_set.projection,
+            );
+        }
+
+        let mut target = self.window.draw();
+        // Letterbox
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
+        let viewport = screen.viewport();
+        let bottom = screen.drawable.1 as i32 - viewport.y() - viewport.height() as i32;
+        output.as_surface().blit_whole_color_to(
+            &target,
+            &BlitTarget {
+                left: viewport.x() as u32,
+                bottom: bottom.max(0) as u32,
+                width: viewport.width() as i32,
+                height: viewport.height() as i32,
+            },
+            MagnifySamplerFilter::Nearest,
+        );
         target.finish().unwrap();
+        self.recorder.update(&self.window, &mut capture);
         // let ctx: &mut egui::CtxRef = &mut egui_context;
         // let raw: &egui::RawInput = &egui_raw_input;
         // ctx.begin_frame(raw.clone());
@@ -321,11 +1199,12 @@ impl<'a, 's> System<'s> for Renderer<'a> {
         //     let texture: &Texture = &egui_context.texture();
         // }
         // self.canvas.present();
-        self.now = Instant::now();
     }
 
     fn setup(&mut self, world: &mut World) {
         Self::SystemData::setup(world);
+        AnimationData::setup(world);
+        self.sprite_manager.track(world);
         self.init_render_data(world);
     }
 }
@@ -0,0 +1,132 @@
+use sdl2::rect::Rect;
+
+// How the virtual resolution is fitted into the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    // Largest whole multiple that fits, the rest is letterboxed. Keeps pixel art crisp.
+    Integer,
+    // Largest size that fits while keeping the aspect ratio.
+    Fit,
+    // Fill the whole window.
+    Stretch,
+}
+
+impl ScaleMode {
+    // The mode after this one, F11 cycles through them.
+    pub fn next(self) -> Self {
+        match self {
+            ScaleMode::Integer => ScaleMode::Fit,
+            ScaleMode::Fit => ScaleMode::Stretch,
+            ScaleMode::Stretch => ScaleMode::Integer,
+        }
+    }
+}
+
+/**
+ * The game is drawn at `resolution` to an offscreen target,
+ * which is then scaled up into the window according to `mode`.
+ * `window` is in points, `drawable` in pixels, they differ on high DPI displays.
+ */
+#[derive(Debug, Clone)]
+pub struct Screen {
+    pub resolution: (u32, u32),
+    pub mode: ScaleMode,
+    pub window: (u32, u32),
+    pub drawable: (u32, u32),
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self {
+            resolution: (800, 600),
+            mode: ScaleMode::Integer,
+            window: (800, 600),
+            drawable: (800, 600),
+        }
+    }
+}
+
+impl Screen {
+    // Pixels per point of the window.
+    pub fn dpi_scale(&self) -> f32 {
+        if self.window.0 == 0 {
+            return 1.0;
+        }
+        self.drawable.0 as f32 / self.window.0 as f32
+    }
+
+    // Area of the drawable the game is shown in, measured from the top left.
+    pub fn viewport(&self) -> Rect {
+        let (vw, vh) = (self.resolution.0.max(1), self.resolution.1.max(1));
+        let (dw, dh) = self.drawable;
+        let (w, h) = match self.mode {
+            ScaleMode::Stretch => (dw, dh),
+            ScaleMode::Integer if dw >= vw && dh >= vh => {
+                let k = (dw / vw).min(dh / vh);
+                (vw * k, vh * k)
+            }
+            // Windows smaller than the resolution fall back to fitting.
+            ScaleMode::Integer | ScaleMode::Fit => {
+                let s = (dw as f32 / vw as f32).min(dh as f32 / vh as f32);
+                ((vw as f32 * s) as u32, (vh as f32 * s) as u32)
+            }
+        };
+        Rect::new(
+            ((dw - w) / 2) as i32,
+            ((dh - h) / 2) as i32,
+            w.max(1),
+            h.max(1),
+        )
+    }
+
+    // Maps a point in window coordinates, ie. from mouse events, to the virtual resolution.
+    pub fn window_to_virtual(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let dpi = self.dpi_scale();
+        let viewport = self.viewport();
+        let sx = self.resolution.0 as f32 / viewport.width() as f32;
+        let sy = self.resolution.1 as f32 / viewport.height() as f32;
+        (
+            (x * dpi - viewport.x() as f32) * sx,
+            (y * dpi - viewport.y() as f32) * sy,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(mode: ScaleMode, window: (u32, u32), drawable: (u32, u32)) -> Screen {
+        Screen {
+            resolution: (320, 180),
+            mode,
+            window,
+            drawable,
+        }
+    }
+
+    #[test]
+    fn integer_scaling_letterboxes_the_rest() {
+        let large = screen(ScaleMode::Integer, (1000, 700), (1000, 700));
+        assert_eq!(large.viewport(), Rect::new(20, 80, 960, 540));
+        // Too small for a whole multiple, the resolution is fitted instead.
+        let small = screen(ScaleMode::Integer, (300, 200), (300, 200));
+        assert_eq!(small.viewport(), Rect::new(0, 16, 300, 168));
+    }
+
+    #[test]
+    fn fit_and_stretch_use_the_window() {
+        let fit = screen(ScaleMode::Fit, (1000, 700), (1000, 700));
+        assert_eq!(fit.viewport(), Rect::new(0, 69, 1000, 562));
+        let stretch = screen(ScaleMode::Stretch, (1000, 700), (1000, 700));
+        assert_eq!(stretch.viewport(), Rect::new(0, 0, 1000, 700));
+    }
+
+    #[test]
+    fn window_points_map_to_the_resolution() {
+        // A high DPI display, with two pixels per point.
+        let hidpi = screen(ScaleMode::Integer, (500, 350), (1000, 700));
+        assert_eq!(hidpi.window_to_virtual((250.0, 175.0)), (160.0, 90.0));
+        assert_eq!(hidpi.window_to_virtual((10.0, 40.0)), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::game::Game;
+use crate::lib::font::bitmap::BitmapFonts;
+use crate::lib::font::Text;
+use crate::lib::sprite::{Frames, Sprite, SpriteManager};
+use crate::lib::systems::camera::Camera;
+use crate::lib::systems::components::{Layer, Position, RenderLayer, Tint, ZIndex};
+use crate::lib::systems::particles::{particle_draws, ParticleEmitter};
+use crate::lib::systems::renderer::{
+    animate, bitmap_text_draws, chunk_instances, load_frames, sprite_draws, tilemap_draws,
+    AnimationData, Draw, EntityData, SpriteInstance, TextureInfo, YSort, CLEAR_COLOR, TEXTURES,
+};
+use crate::lib::systems::screen::Screen;
+use crate::lib::tilemap::Tilemap;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use image::{Rgba, RgbaImage};
+use specs::prelude::*;
+
+/**
+ * Draws the same scene as the Renderer on the CPU, so tests can check it against golden
+ * images on machines without a GPU.
+ * Quads go through the same projections, sample their frame with nearest filtering,
+ * are multiplied by their tint and alpha blended like the GL pipeline.
+ * Only bitmap fonts are drawn, not TTF ones. The debug overlay is not drawn,
+ * the scene is not lit and the post processing chain is not run.
+ */
+pub struct SoftwareRenderer<'a> {
+    pub sprite_manager: &'a mut SpriteManager,
+    // Game ticks the animations have been advanced through.
+    pub ticks: usize,
+    // The last frame drawn, at the screen's virtual resolution.
+    pub frame: RgbaImage,
+}
+
+impl<'a> SoftwareRenderer<'a> {
+    pub fn new(sprite_manager: &'a mut SpriteManager) -> Self {
+        Self {
+            sprite_manager,
+            ticks: 0,
+            frame: RgbaImage::new(0, 0),
+        }
+    }
+
+    // Loads a sprite after setup, ie. the tilesets of a map. Loaded sprites are kept as is.
+    pub fn load(&mut self, info: &TextureInfo) {
+        if self.sprite_manager.get(info.name).is_none() {
+            self.sprite_manager.add(load_frames(info));
+        }
+    }
+
+    // Advances the animations to the current game tick, like Renderer::animate.
+    pub fn animate(&mut self, world: &World) {
+        let (mut data, game, mut events) = world.system_data::<AnimationData>();
+        animate(
+            self.sprite_manager,
+            &mut self.ticks,
+            &game,
+            &mut data,
+            &mut events,
+        );
+    }
+
+    // Renders `world` and returns the frame.
+    pub fn render(&mut self, world: &World) -> &RgbaImage {
+        self.run_now(world);
+        &self.frame
+    }
+}
+
+// Pixel coordinates of a point of the world, measured from the top left.
+fn to_pixel(projection: &glm::Mat4x4, (w, h): (f32, f32), x: f32, y: f32) -> (f32, f32) {
+    let clip = projection * glm::vec4(x, y, 0.0, 1.0);
+    let (nx, ny) = (clip.x / clip.w, clip.y / clip.w);
+    ((nx + 1.0) * 0.5 * w, (1.0 - ny) * 0.5 * h)
+}
+
+/**
+ * Fills the pixels whose center falls inside the quad, ie. [0, 1) on both axes of the
+ * sprite, like the GL rasterizer. Any affine projection works, not just axis aligned ones.
+ */
+fn draw_quad(
+    target: &mut RgbaImage,
+    frames: &[RgbaImage],
+    instance: &SpriteInstance,
+    projection: &glm::Mat4x4,
+) {
+    let layers = frames.len();
+    if layers == 0 {
+        return;
+    }
+    // Out of range layers clamp, like sampling a texture array.
+    let frame = &frames[(instance.frame.max(0) as usize).min(layers - 1)];
+    let (fw, fh) = frame.dimensions();
+    let size = (target.width() as f32, target.height() as f32);
+    let [ox, oy] = instance.offset;
+    let [sx, sy] = instance.scale;
+    let p0 = to_pixel(projection, size, ox, oy);
+    let p1 = to_pixel(projection, size, ox + sx, oy);
+    let p2 = to_pixel(projection, size, ox, oy + sy);
+    let p3 = to_pixel(projection, size, ox + sx, oy + sy);
+    // Maps a pixel back to the quad's (u, v).
+    let (ax, ay) = (p1.0 - p0.0, p1.1 - p0.1);
+    let (bx, by) = (p2.0 - p0.0, p2.1 - p0.1);
+    let det = ax * by - bx * ay;
+    if det.abs() < f32::EPSILON {
+        return;
+    }
+    let xs = [p0.0, p1.0, p2.0, p3.0];
+    let ys = [p0.1, p1.1, p2.1, p3.1];
+    let min = |v: &[f32]| v.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = |v: &[f32]| v.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let x0 = min(&xs).floor().max(0.0) as u32;
+    let y0 = min(&ys).floor().max(0.0) as u32;
+    let x1 = (max(&xs).ceil().max(0.0) as u32).min(target.width());
+    let y1 = (max(&ys).ceil().max(0.0) as u32).min(target.height());
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let (dx, dy) = (x as f32 + 0.5 - p0.0, y as f32 + 0.5 - p0.1);
+            let u = (dx * by - bx * dy) / det;
+            let v = (ax * dy - dx * ay) / det;
+            if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+                continue;
+            }
+            let tx = ((u * fw as f32) as u32).min(fw - 1);
+            let ty = ((v * fh as f32) as u32).min(fh - 1);
+            let texel = frame.get_pixel(tx, ty);
+            let mut src = [0.0; 4];
+            for (i, c) in src.iter_mut().enumerate() {
+                *c = texel[i] as f32 / 255.0 * instance.tint[i];
+            }
+            blend(target.get_pixel_mut(x, y), src);
+        }
+    }
+}
+
+// Blend::alpha_blending, on color and alpha alike.
+fn blend(dst: &mut Rgba<u8>, src: [f32; 4]) {
+    let a = src[3];
+    for (i, c) in src.iter().enumerate() {
+        let d = dst[i] as f32 / 255.0;
+        let out = c * a + d * (1.0 - a);
+        dst[i] = (out.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+}
+
+fn cpu_frames(sprite: &Sprite) -> &[RgbaImage] {
+    match &sprite.texture {
+        Frames::Cpu(frames) => frames,
+        Frames::Gpu(_) => panic!(
+            "Sprite \"{}\" was loaded for the GPU, load it with load_frames",
+            sprite.info.name
+        ),
+    }
+}
+
+impl<'a, 's> System<'s> for SoftwareRenderer<'a> {
+    type SystemData = (
+        EntityData<'s>,
+        ReadStorage<'s, Tint>,
+        ReadStorage<'s, RenderLayer>,
+        ReadStorage<'s, ZIndex>,
+        ReadStorage<'s, Tilemap>,
+        Read<'s, YSort>,
+        Read<'s, Game>,
+        Read<'s, Camera>,
+        Read<'s, Screen>,
+        ReadStorage<'s, Text>,
+        Read<'s, BitmapFonts>,
+        ReadStorage<'s, ParticleEmitter>,
+    );
+    fn run(
+        &mut self,
+        (
+            entity_data,
+            tint,
+            render_layer,
+            z_index,
+            tilemap,
+            y_sort,
+            game,
+            camera,
+            screen,
+            text,
+            bitmap_fonts,
+            particles,
+        ): Self::SystemData,
+    ) {
+        for font in bitmap_fonts.0.values() {
+            self.load(&font.texture);
+        }
+        let alpha = game.interpolation();
+        let (entities, _, _, _, sprite_handle, ..) = &entity_data;
+        self.sprite_manager.release_deleted(entities, sprite_handle);
+        let (entities, position, ..) = &entity_data;
+
+        let (vw, vh) = screen.resolution;
+        let [r, g, b, a] = CLEAR_COLOR;
+        let byte = |c: f32| (c * 255.0).round() as u8;
+        self.frame = RgbaImage::from_pixel(vw, vh, Rgba([byte(r), byte(g), byte(b), byte(a)]));
+
+        let mut draws = sprite_draws(
+            self.sprite_manager,
+            &entity_data,
+            (&tint, &render_layer, &z_index),
+            &y_sort,
+            alpha,
+        );
+        draws.extend(tilemap_draws(
+            self.sprite_manager,
+            (entities, &tilemap, position),
+            (&render_layer, &z_index),
+            camera.visible(&screen, alpha),
+            |_, map, _, chunk| map.chunk_tiles(chunk).next().is_some(),
+        ));
+        draws.extend(bitmap_text_draws(
+            self.sprite_manager,
+            &bitmap_fonts,
+            &entity_data,
+            &text,
+            (&render_layer, &z_index),
+            &y_sort,
+            alpha,
+        ));
+        draws.extend(particle_draws(
+            self.sprite_manager,
+            &particles,
+            (&render_layer, &z_index),
+            alpha,
+        ));
+        draws.sort_by_key(|(key, ..)| *key);
+
+        let projection = camera.view_projection(&screen, alpha);
+        let ui_projection = glm::ortho(0.0, vw as f32, vh as f32, 0.0, -1.0, 1.0);
+        for ((layer, ..), sprite, draw) in draws {
+            let projection = match layer {
+                Layer::UI => &ui_projection,
+                _ => &projection,
+            };
+            let frames = cpu_frames(sprite);
+            match draw {
+                Draw::Sprite(instance) => draw_quad(&mut self.frame, frames, &instance, projection),
+                Draw::Chunk(entity, chunk) => {
+                    let (map, Position(x, y)) = match (tilemap.get(entity), position.get(entity)) {
+                        (Some(map), Some(pos)) => (map, pos),
+                        _ => continue,
+                    };
+                    for instance in chunk_instances(map, (*x, *y), chunk) {
+                        draw_quad(&mut self.frame, frames, &instance, projection);
+                    }
+                }
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        AnimationData::setup(world);
+        self.sprite_manager.track(world);
+        for texture in TEXTURES.iter() {
+            self.load(texture);
+        }
+    }
+}
+
+/**
+ * Compares `image` against the golden PNG at `path`, allowing every channel to be off by
+ * up to `tolerance`. On a mismatch the image is saved next to it as `<path>.actual.png`.
+ * With BLESS set in the environment the golden is (re)written from `image` instead.
+ */
+pub fn compare_golden(image: &RgbaImage, path: &str, tolerance: u8) -> Result<()> {
+    if std::env::var_os("BLESS").is_some() {
+        image.save(path)?;
+        return Ok(());
+    }
+    let golden = image::open(path)
+        .map_err(|e| {
+            eyre!(
+                "Cannot open golden {}: {}, run with BLESS=1 to create it",
+                path,
+                e
+            )
+        })?
+        .to_rgba8();
+    let fail = |message: String| {
+        let actual = format!("{}.actual.png", path);
+        match image.save(&actual) {
+            Ok(()) => eyre!("{}, saved the frame to {}", message, actual),
+            Err(e) => eyre!("{}, could not save the frame: {}", message, e),
+        }
+    };
+    if golden.dimensions() != image.dimensions() {
+        return Err(fail(format!(
+            "{} is {:?}, the frame is {:?}",
+            path,
+            golden.dimensions(),
+            image.dimensions()
+        )));
+    }
+    let mut differing = 0;
+    let mut worst = (0, 0, 0);
+    for ((x, y, a), b) in image.enumerate_pixels().zip(golden.pixels()) {
+        let diff = (0..4)
+            .map(|i| (a[i] as i16 - b[i] as i16).abs())
+            .max()
+            .unwrap() as u8;
+        if diff > tolerance {
+            differing += 1;
+            if diff > worst.2 {
+                worst = (x, y, diff);
+            }
+        }
+    }
+    if differing > 0 {
+        let (x, y, diff) = worst;
+        return Err(fail(format!(
+            "{} pixels differ from {} by more than {}, the worst by {} at ({}, {})",
+            differing, path, tolerance, diff, x, y
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::systems::components::Size;
+
+    #[test]
+    fn draws_the_golden_scene() {
+        let mut sprite_manager = SpriteManager::new();
+        let mut renderer = SoftwareRenderer::new(&mut sprite_manager);
+        let mut world = World::new();
+        RunNow::setup(&mut renderer, &mut world);
+        world.insert(Screen {
+            resolution: (160, 120),
+            ..Default::default()
+        });
+        world.insert(Camera {
+            position: (80.0, 60.0),
+            previous: (80.0, 60.0),
+            ..Default::default()
+        });
+
+        // Nothing moves, so the frame does not depend on when it is drawn.
+        let mut ground = Tilemap::new("tile", (10, 1), (16, 16));
+        for x in 0..10 {
+            ground.set(x, 0, Some(0));
+        }
+        world
+            .create_entity()
+            .with(Position(0, 96))
+            .with(ground)
+            .build();
+        world
+            .create_entity()
+            .with(Position(16, 48))
+            .with(Size(48, 48))
+            .with(renderer.sprite_manager.init("chicken"))
+            .with(RenderLayer(Layer::Actors))
+            .build();
+        world
+            .create_entity()
+            .with(Position(96, 64))
+            .with(Size(32, 32))
+            .with(renderer.sprite_manager.init("mushroom"))
+            .with(Tint([1.0, 0.5, 0.5, 0.75]))
+            .with(RenderLayer(Layer::Actors))
+            .build();
+        // A few ticks into the chicken's animation.
+        world.write_resource::<Game>().ticks = 4;
+        renderer.animate(&world);
+
+        let frame = renderer.render(&world);
+        compare_golden(frame, "fixtures/golden/software_scene.png", 0).unwrap();
+    }
+}
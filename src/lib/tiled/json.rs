@@ -0,0 +1,274 @@
+use super::{decode_gids, MapLayer, Object, Properties, Property, TiledMap, Tileset};
+use crate::lib::importer::{read_json, relative, visible};
+use crate::lib::sprite::SpriteManager;
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+struct RawProperty {
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: String,
+    value: Value,
+}
+
+fn properties(raw: Vec<RawProperty>) -> Properties {
+    raw.into_iter()
+        .map(|p| {
+            let value = match (p.kind.as_str(), p.value) {
+                (_, Value::Bool(b)) => Property::Bool(b),
+                ("float", Value::Number(n)) => Property::Float(n.as_f64().unwrap_or(0.0)),
+                (_, Value::Number(n)) => match n.as_i64() {
+                    Some(i) => Property::Int(i),
+                    None => Property::Float(n.as_f64().unwrap_or(0.0)),
+                },
+                (_, Value::String(s)) => Property::String(s),
+                (_, other) => Property::String(other.to_string()),
+            };
+            (p.name, value)
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct RawObject {
+    #[serde(default)]
+    id: u32,
+    #[serde(default)]
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    class: String,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    gid: Option<u32>,
+    #[serde(default)]
+    properties: Vec<RawProperty>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawData {
+    Gids(Vec<u32>),
+    Encoded(String),
+}
+
+#[derive(Deserialize)]
+struct RawLayer {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    width: usize,
+    #[serde(default)]
+    height: usize,
+    #[serde(default)]
+    offsetx: f32,
+    #[serde(default)]
+    offsety: f32,
+    #[serde(default = "visible")]
+    visible: bool,
+    data: Option<RawData>,
+    encoding: Option<String>,
+    compression: Option<String>,
+    chunks: Option<Value>,
+    #[serde(default)]
+    objects: Vec<RawObject>,
+    #[serde(default)]
+    layers: Vec<RawLayer>,
+    #[serde(default)]
+    properties: Vec<RawProperty>,
+}
+
+#[derive(Deserialize)]
+struct RawTile {
+    id: u32,
+    #[serde(default)]
+    properties: Vec<RawProperty>,
+}
+
+#[derive(Deserialize)]
+struct RawTileset {
+    #[serde(default)]
+    firstgid: u32,
+    source: Option<String>,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    tilewidth: u32,
+    #[serde(default)]
+    tileheight: u32,
+    #[serde(default)]
+    columns: u32,
+    #[serde(default)]
+    tilecount: u32,
+    #[serde(default)]
+    margin: u32,
+    #[serde(default)]
+    spacing: u32,
+    image: Option<String>,
+    #[serde(default)]
+    tiles: Vec<RawTile>,
+}
+
+#[derive(Deserialize)]
+struct RawMap {
+    #[serde(default)]
+    orientation: String,
+    #[serde(default)]
+    infinite: bool,
+    width: usize,
+    height: usize,
+    tilewidth: u32,
+    tileheight: u32,
+    #[serde(default)]
+    layers: Vec<RawLayer>,
+    #[serde(default)]
+    tilesets: Vec<RawTileset>,
+    #[serde(default)]
+    properties: Vec<RawProperty>,
+}
+
+fn tileset(raw: RawTileset, path: &str) -> Result<Tileset> {
+    // External tilesets (.tsj) only keep their firstgid in the map.
+    if let Some(source) = &raw.source {
+        let source = relative(path, source);
+        let mut set = tileset(read_json(&source)?, &source)?;
+        set.first_gid = raw.firstgid;
+        return Ok(set);
+    }
+    let image = match raw.image {
+        Some(image) => relative(path, &image),
+        None => bail!(
+            "Tileset \"{}\" is an image collection, only single image tilesets are supported",
+            raw.name
+        ),
+    };
+    Ok(Tileset {
+        first_gid: raw.firstgid,
+        name: raw.name,
+        tile_size: (raw.tilewidth, raw.tileheight),
+        columns: raw.columns,
+        tile_count: raw.tilecount,
+        margin: raw.margin,
+        spacing: raw.spacing,
+        image: SpriteManager::intern(image),
+        tiles: raw
+            .tiles
+            .into_iter()
+            .map(|tile| (tile.id, properties(tile.properties)))
+            .collect(),
+    })
+}
+
+// Children of a group are only visible when the group is.
+fn layers(
+    raw: Vec<RawLayer>,
+    (ox, oy): (f32, f32),
+    parent_visible: bool,
+    inherited: &Properties,
+    out: &mut Vec<MapLayer>,
+) -> Result<()> {
+    for layer in raw {
+        let mut props = inherited.clone();
+        props.extend(properties(layer.properties));
+        let offset = (ox + layer.offsetx, oy + layer.offsety);
+        let visible = parent_visible && layer.visible;
+        match layer.kind.as_str() {
+            "tilelayer" => {
+                if layer.chunks.is_some() {
+                    bail!("Layer \"{}\" is from an infinite map", layer.name);
+                }
+                let count = layer.width * layer.height;
+                let gids = match layer.data {
+                    Some(RawData::Gids(gids)) => gids,
+                    Some(RawData::Encoded(data)) if layer.encoding.as_deref() == Some("base64") => {
+                        let name = &layer.name;
+                        decode_gids(&data, layer.compression.as_deref(), count)
+                            .map_err(|e| eyre!("Layer \"{}\": {}", name, e))?
+                    }
+                    _ => bail!("Layer \"{}\" has no readable data", layer.name),
+                };
+                if gids.len() != count {
+                    bail!(
+                        "Layer \"{}\" has {} tiles, expected {}",
+                        layer.name,
+                        gids.len(),
+                        count
+                    );
+                }
+                out.push(MapLayer::Tiles {
+                    name: layer.name,
+                    width: layer.width,
+                    height: layer.height,
+                    offset,
+                    visible,
+                    gids,
+                    properties: props,
+                });
+            }
+            "objectgroup" => out.push(MapLayer::Objects {
+                name: layer.name,
+                offset,
+                objects: layer
+                    .objects
+                    .into_iter()
+                    .map(|o| Object {
+                        id: o.id,
+                        name: o.name,
+                        kind: if o.kind.is_empty() { o.class } else { o.kind },
+                        x: o.x,
+                        y: o.y,
+                        width: o.width,
+                        height: o.height,
+                        gid: o.gid,
+                        properties: properties(o.properties),
+                    })
+                    .collect(),
+                properties: props,
+            }),
+            "group" => layers(layer.layers, offset, visible, &props, out)?,
+            // Image layers are not supported.
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn open(path: &str) -> Result<TiledMap> {
+    let raw: RawMap = read_json(path)?;
+    if raw.infinite {
+        bail!("Infinite maps are not supported");
+    }
+    if !raw.orientation.is_empty() && raw.orientation != "orthogonal" {
+        bail!(
+            "Only orthogonal maps are supported, found {}",
+            raw.orientation
+        );
+    }
+    let tilesets = raw
+        .tilesets
+        .into_iter()
+        .map(|set| tileset(set, path))
+        .collect::<Result<Vec<_>>>()?;
+    let mut out = vec![];
+    layers(raw.layers, (0.0, 0.0), true, &HashMap::new(), &mut out)?;
+    Ok(TiledMap {
+        width: raw.width,
+        height: raw.height,
+        tile_size: (raw.tilewidth, raw.tileheight),
+        tilesets,
+        layers: out,
+        properties: properties(raw.properties),
+    })
+}
@@ -0,0 +1,550 @@
+use crate::aseprite::AsepriteJSON;
+use crate::lib::importer::{self, flag, named_sprite, tile_sprite, CustomValue};
+use crate::lib::sprite::SpriteManager;
+use crate::systems::components::CollisionType::Static;
+use crate::systems::components::{Collision, Layer, Position, RenderLayer, Size, ZIndex};
+use crate::systems::renderer::TextureInfo;
+use crate::tilemap::Tilemap;
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use specs::prelude::*;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+pub mod json;
+pub mod tmx;
+
+// Tiled stores tile flips in the top bits of a gid, flips are not supported and ignored.
+const GID_MASK: u32 = 0x1FFF_FFFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Property {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl CustomValue for Property {
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Property::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Property::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Property {
+    // Parses a property given as the (type, value) strings of a .tmx file.
+    fn parse(kind: &str, value: &str) -> Result<Self> {
+        Ok(match kind {
+            "bool" => Property::Bool(value == "true"),
+            "int" | "object" => Property::Int(value.parse()?),
+            "float" => Property::Float(value.parse()?),
+            _ => Property::String(value.to_string()),
+        })
+    }
+}
+
+pub type Properties = HashMap<String, Property>;
+
+#[derive(Debug, Clone)]
+pub struct Tileset {
+    pub first_gid: u32,
+    pub name: String,
+    pub tile_size: (u32, u32),
+    pub columns: u32,
+    pub tile_count: u32,
+    pub margin: u32,
+    pub spacing: u32,
+    // Path of the tileset image, also the name its sprite is loaded under.
+    pub image: &'static str,
+    // Properties of individual tiles, by local id.
+    pub tiles: HashMap<u32, Properties>,
+}
+
+impl Tileset {
+    // The sprite whose frames are the tiles of this set.
+    pub fn texture(&self) -> TextureInfo {
+        TextureInfo {
+            name: self.image,
+            path: self.image,
+            json: Some(AsepriteJSON::grid(
+                &self.name,
+                self.tile_size,
+                self.columns,
+                self.tile_count,
+                self.margin,
+                self.spacing,
+            )),
+            states: None,
+        }
+    }
+
+    fn is_solid(&self, tile: u32) -> bool {
+        self.tiles.get(&tile).is_some_and(|p| flag(p, "solid"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub id: u32,
+    pub name: String,
+    // The object's type, or class since Tiled 1.9.
+    pub kind: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    // Set on tile objects.
+    pub gid: Option<u32>,
+    pub properties: Properties,
+}
+
+#[derive(Debug, Clone)]
+pub enum MapLayer {
+    Tiles {
+        name: String,
+        width: usize,
+        height: usize,
+        offset: (f32, f32),
+        visible: bool,
+        // Row major, 0 is an empty cell.
+        gids: Vec<u32>,
+        properties: Properties,
+    },
+    Objects {
+        name: String,
+        offset: (f32, f32),
+        objects: Vec<Object>,
+        properties: Properties,
+    },
+}
+
+/**
+ * A map made in Tiled, read from either the JSON (.tmj / .json) or XML (.tmx) format.
+ * Group layers are flattened, their offsets and properties applied to their children.
+ * Only finite orthogonal maps are supported.
+ */
+#[derive(Debug, Clone)]
+pub struct TiledMap {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: (u32, u32),
+    pub tilesets: Vec<Tileset>,
+    // In draw order, bottom first.
+    pub layers: Vec<MapLayer>,
+    pub properties: Properties,
+}
+
+// Adds components to entities spawned from objects of a given type, see importer::Templates.
+pub type Templates = importer::Templates<Object>;
+
+impl TiledMap {
+    pub fn open(path: &str) -> Result<Self> {
+        let map = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("tmx") => tmx::open(path),
+            Some("tmj") | Some("json") => json::open(path),
+            _ => bail!(
+                "{} is not a Tiled map, expected a .tmx, .tmj or .json file",
+                path
+            ),
+        };
+        map.and_then(Self::check_tile_sizes)
+            .map_err(|e| eyre!("Cannot load map {}: {}", path, e))
+    }
+
+    // Tile layers are drawn on the map's grid, tilesets of another tile size would not fit it.
+    fn check_tile_sizes(self) -> Result<Self> {
+        for layer in &self.layers {
+            let (name, gids) = match layer {
+                MapLayer::Tiles { name, gids, .. } => (name, gids),
+                MapLayer::Objects { .. } => continue,
+            };
+            for &gid in gids {
+                let set = match self.tileset_of(gid) {
+                    Some((set, _)) => &self.tilesets[set],
+                    None => continue,
+                };
+                if set.tile_size != self.tile_size {
+                    bail!(
+                        "Layer \"{}\" uses tileset \"{}\" of {}x{} tiles on a grid of {}x{} tiles",
+                        name,
+                        set.name,
+                        set.tile_size.0,
+                        set.tile_size.1,
+                        self.tile_size.0,
+                        self.tile_size.1
+                    );
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    // The tileset `gid` belongs to and the tile's id within it.
+    pub fn tileset_of(&self, gid: u32) -> Option<(usize, u32)> {
+        let gid = gid & GID_MASK;
+        if gid == 0 {
+            return None;
+        }
+        self.tilesets
+            .iter()
+            .enumerate()
+            .filter(|(_, set)| set.first_gid <= gid)
+            .max_by_key(|(_, set)| set.first_gid)
+            .map(|(i, set)| (i, gid - set.first_gid))
+    }
+
+    // Sprites of every tileset, to be loaded by the renderer before spawning.
+    pub fn textures(&self) -> Vec<TextureInfo> {
+        self.tilesets.iter().map(Tileset::texture).collect()
+    }
+
+    /**
+     * Creates the entities of the map in `world`.
+     * Each tile layer becomes one Tilemap per tileset it uses; cells are solid when
+     * their tile or their layer has a `solid` property set to true.
+     * Each object becomes an entity with Position and Size, `Collision(Some(Static))`
+     * when `solid` is set, a sprite when it is a tile object or has a `sprite` property,
+     * and then whatever the template registered for its type adds.
+     * Layers are ZIndex'ed in map order, on the RenderLayer named by their
+     * `render_layer` property (World by default).
+     */
+    pub fn spawn(
+        &self,
+        world: &mut World,
+        sprites: &mut SpriteManager,
+        templates: &Templates,
+    ) -> Vec<Entity> {
+        let mut spawned = vec![];
+        for (z, layer) in self.layers.iter().enumerate() {
+            match layer {
+                MapLayer::Tiles {
+                    width,
+                    height,
+                    offset,
+                    visible,
+                    gids,
+                    properties,
+                    ..
+                } => {
+                    let solid = flag(properties, "solid");
+                    let mut maps: HashMap<usize, Tilemap> = HashMap::new();
+                    for (i, &gid) in gids.iter().enumerate() {
+                        let (set, tile) = match self.tileset_of(gid) {
+                            Some(found) => found,
+                            None => continue,
+                        };
+                        let tileset = &self.tilesets[set];
+                        let map = maps.entry(set).or_insert_with(|| {
+                            Tilemap::new(tileset.image, (*width, *height), self.tile_size)
+                        });
+                        let (x, y) = (i % width, i / width);
+                        // Hidden layers still collide, ie. a dedicated collision layer.
+                        if *visible {
+                            map.set(x, y, Some(tile));
+                        }
+                        map.set_solid(x, y, solid || tileset.is_solid(tile));
+                    }
+                    let mut maps: Vec<_> = maps.into_iter().collect();
+                    maps.sort_by_key(|(set, _)| *set);
+                    for (_, map) in maps {
+                        let entity = world
+                            .create_entity()
+                            .with(Position(offset.0 as i32, offset.1 as i32))
+                            .with(map)
+                            .with(RenderLayer(render_layer(properties)))
+                            .with(ZIndex(z as i32))
+                            .build();
+                        spawned.push(entity);
+                    }
+                }
+                MapLayer::Objects {
+                    offset,
+                    objects,
+                    properties,
+                    ..
+                } => {
+                    for object in objects {
+                        let entity = self.spawn_object(
+                            world,
+                            sprites,
+                            templates,
+                            object,
+                            *offset,
+                            (render_layer(properties), z as i32),
+                        );
+                        spawned.push(entity);
+                    }
+                }
+            }
+        }
+        spawned
+    }
+
+    fn spawn_object(
+        &self,
+        world: &mut World,
+        sprites: &mut SpriteManager,
+        templates: &Templates,
+        object: &Object,
+        (ox, oy): (f32, f32),
+        (layer, z): (Layer, i32),
+    ) -> Entity {
+        let mut y = object.y + oy;
+        // Tile objects are positioned by their bottom left corner.
+        if object.gid.is_some() {
+            y -= object.height;
+        }
+        let mut builder = world
+            .create_entity()
+            .with(Position((object.x + ox) as i32, y as i32))
+            .with(Size(object.width as i32, object.height as i32))
+            .with(RenderLayer(layer))
+            .with(ZIndex(z));
+        if flag(&object.properties, "solid") {
+            builder = builder.with(Collision(Some(Static)));
+        }
+        if let Some((set, tile)) = object.gid.and_then(|gid| self.tileset_of(gid)) {
+            builder = builder.with(tile_sprite(sprites, self.tilesets[set].image, tile));
+        } else if let Some(name) = object.properties.get("sprite").and_then(Property::as_str) {
+            match named_sprite(sprites, name) {
+                Some(handle) => builder = builder.with(handle),
+                None => eprintln!("Object {} uses unknown sprite \"{}\"", object.id, name),
+            }
+        }
+        templates
+            .apply(&object.kind, object, sprites, builder)
+            .build()
+    }
+}
+
+fn render_layer(properties: &Properties) -> Layer {
+    match properties.get("render_layer").and_then(Property::as_str) {
+        Some("background") => Layer::Background,
+        Some("actors") => Layer::Actors,
+        Some("foreground") => Layer::Foreground,
+        Some("ui") => Layer::UI,
+        _ => Layer::World,
+    }
+}
+
+/**
+ * Layer data stored as base64, optionally compressed,
+ * as little endian u32 gids.
+ */
+fn decode_gids(data: &str, compression: Option<&str>, count: usize) -> Result<Vec<u32>> {
+    // Tiled indents the data like the rest of the XML.
+    let data: String = data.split_whitespace().collect();
+    let bytes = base64::decode(data)?;
+    let bytes = match compression {
+        None | Some("") => bytes,
+        Some("zlib") => {
+            let mut out = vec![];
+            ZlibDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+            out
+        }
+        Some("gzip") => {
+            let mut out = vec![];
+            GzDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+            out
+        }
+        Some(other) => bail!("Unsupported layer compression \"{}\"", other),
+    };
+    if bytes.len() != count * 4 {
+        bail!(
+            "Layer data has {} bytes, expected {} for {} tiles",
+            bytes.len(),
+            count * 4,
+            count
+        );
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::components::Velocity;
+
+    // fixtures/tiled has the same map saved as .tmx and as .tmj.
+    fn assert_fixture(map: &TiledMap) {
+        assert_eq!((map.width, map.height, map.tile_size), (3, 2, (16, 16)));
+        assert_eq!(map.properties["music"].as_str(), Some("field"));
+
+        assert_eq!(map.tilesets.len(), 1);
+        let set = &map.tilesets[0];
+        assert_eq!((set.first_gid, set.name.as_str()), (1, "tiles"));
+        assert_eq!(
+            Path::new(set.image),
+            Path::new("fixtures/tiled/../../sprites/tile.png")
+        );
+        assert!(set.is_solid(0));
+        assert_eq!(map.tileset_of(1), Some((0, 0)));
+        assert_eq!(map.tileset_of(0), None);
+
+        assert_eq!(map.layers.len(), 4);
+        match &map.layers[0] {
+            MapLayer::Tiles {
+                name,
+                visible,
+                gids,
+                ..
+            } => {
+                assert_eq!((name.as_str(), *visible), ("ground", true));
+                assert_eq!(gids, &[1, 0, 1, 0, 1, 1]);
+            }
+            other => panic!("Expected the ground tiles, found {:?}", other),
+        }
+        match &map.layers[1] {
+            MapLayer::Tiles {
+                visible,
+                gids,
+                properties,
+                ..
+            } => {
+                assert!(!visible);
+                assert_eq!(gids, &[0, 0, 0, 1, 0, 1]);
+                assert_eq!(render_layer(properties), Layer::Foreground);
+            }
+            other => panic!("Expected the wall tiles, found {:?}", other),
+        }
+        // The group is flattened into its object layer.
+        match &map.layers[2] {
+            MapLayer::Objects {
+                name,
+                offset,
+                objects,
+                properties,
+            } => {
+                assert_eq!((name.as_str(), *offset), ("spawns", (8.0, 6.0)));
+                assert_eq!(render_layer(properties), Layer::Actors);
+                let kinds: Vec<_> = objects.iter().map(|o| o.kind.as_str()).collect();
+                assert_eq!(kinds, ["chicken", "crate"]);
+                assert_eq!(objects[0].properties["speed"], Property::Float(1.5));
+                assert_eq!((objects[0].x, objects[0].y), (16.0, 8.0));
+                assert_eq!((objects[0].gid, objects[1].gid), (None, Some(1)));
+            }
+            other => panic!("Expected the spawns, found {:?}", other),
+        }
+        // Layers of a hidden group are hidden too.
+        match &map.layers[3] {
+            MapLayer::Tiles {
+                name,
+                visible,
+                properties,
+                ..
+            } => {
+                assert_eq!((name.as_str(), *visible), ("cave walls", false));
+                assert_eq!(render_layer(properties), Layer::Background);
+            }
+            other => panic!("Expected the cave walls, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_tmx() {
+        assert_fixture(&TiledMap::open("fixtures/tiled/map.tmx").unwrap());
+    }
+
+    #[test]
+    fn reads_json() {
+        assert_fixture(&TiledMap::open("fixtures/tiled/map.tmj").unwrap());
+    }
+
+    #[test]
+    fn reloading_reuses_the_tileset_name() {
+        let first = TiledMap::open("fixtures/tiled/map.tmx").unwrap();
+        let second = TiledMap::open("fixtures/tiled/map.tmx").unwrap();
+        assert!(std::ptr::eq(
+            first.tilesets[0].image,
+            second.tilesets[0].image
+        ));
+    }
+
+    #[test]
+    fn spawns_layers_and_objects() {
+        let mut map = TiledMap::open("fixtures/tiled/map.tmx").unwrap();
+        // Tile objects need their tileset loaded as a sprite, which takes a GL context.
+        if let MapLayer::Objects { objects, .. } = &mut map.layers[2] {
+            objects.retain(|o| o.gid.is_none());
+        }
+        let mut world = World::new();
+        world.register::<Position>();
+        world.register::<Size>();
+        world.register::<RenderLayer>();
+        world.register::<ZIndex>();
+        world.register::<Collision>();
+        world.register::<Tilemap>();
+        world.register::<Velocity>();
+        let mut templates = Templates::new();
+        templates.insert("chicken", |_, _, e| e.with(Velocity(1, 0)));
+        let spawned = map.spawn(&mut world, &mut SpriteManager::new(), &templates);
+        assert_eq!(spawned.len(), 4);
+
+        let maps = world.read_storage::<Tilemap>();
+        let layers = world.read_storage::<RenderLayer>();
+        let ground = maps.get(spawned[0]).unwrap();
+        assert_eq!((ground.get(0, 0), ground.get(1, 0)), (Some(0), None));
+        // Every tile of the set is solid.
+        assert!(ground.is_solid(0, 0) && !ground.is_solid(1, 0));
+        // The walls are hidden but still collide.
+        let walls = maps.get(spawned[1]).unwrap();
+        assert_eq!(walls.get(0, 1), None);
+        assert!(walls.is_solid(0, 1) && walls.is_solid(2, 1) && !walls.is_solid(1, 1));
+        assert_eq!(layers.get(spawned[1]).unwrap().0, Layer::Foreground);
+
+        let chicken = spawned[2];
+        assert_eq!(world.read_storage::<Position>().get(chicken).unwrap().0, 24);
+        assert_eq!(world.read_storage::<Velocity>().get(chicken).unwrap().0, 1);
+        assert!(world.read_storage::<Collision>().get(chicken).is_none());
+        assert_eq!(layers.get(chicken).unwrap().0, Layer::Actors);
+
+        let cave = maps.get(spawned[3]).unwrap();
+        assert_eq!(cave.get(1, 0), None);
+        assert_eq!(layers.get(spawned[3]).unwrap().0, Layer::Background);
+        let z = world.read_storage::<ZIndex>();
+        assert!(z.get(spawned[3]).unwrap().0 > z.get(spawned[0]).unwrap().0);
+    }
+
+    #[test]
+    fn rejects_tilesets_off_the_grid() {
+        let mut map = TiledMap::open("fixtures/tiled/map.tmj").unwrap();
+        map.tilesets[0].tile_size = (32, 32);
+        let error = map.check_tile_sizes().unwrap_err();
+        assert!(error.to_string().contains("32x32 tiles"), "{}", error);
+    }
+
+    #[test]
+    fn decodes_padded_and_indented_gids() {
+        // One gid leaves two bytes of padding, two gids leave one.
+        assert_eq!(decode_gids("BQAAAA==", None, 1).unwrap(), [5]);
+        assert_eq!(decode_gids("BQAAAAYAAAA=", None, 2).unwrap(), [5, 6]);
+        assert_eq!(
+            decode_gids("\n   BQAA\n   AAYAAAA=\n  ", Some(""), 2).unwrap(),
+            [5, 6]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_gids() {
+        assert!(decode_gids("BQ*AAA==", None, 1).is_err());
+        let error = decode_gids("BQAAAA==", None, 2).unwrap_err();
+        assert!(error.to_string().contains("expected 8"), "{}", error);
+        assert!(decode_gids("BQAAAA==", Some("zstd"), 1).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use super::{decode_gids, MapLayer, Object, Properties, Property, TiledMap, Tileset};
+use crate::lib::importer::relative;
+use crate::lib::sprite::SpriteManager;
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+use xml::reader::{EventReader, XmlEvent};
+
+// Just enough of a DOM to walk a .tmx / .tsx file.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: HashMap<String, String>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+
+    // Attribute `name` parsed, or `default` when it is missing.
+    fn parse<T: FromStr>(&self, name: &str, default: T) -> Result<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        match self.attr(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|e| eyre!("<{} {}=\"{}\">: {}", self.name, name, value, e)),
+            None => Ok(default),
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn properties(&self) -> Result<Properties> {
+        let mut properties = Properties::new();
+        if let Some(list) = self.child("properties") {
+            for p in list.children.iter().filter(|c| c.name == "property") {
+                let name = p.attr("name").unwrap_or_default().to_string();
+                // Multiline strings are stored as text instead of in `value`.
+                let value = p.attr("value").unwrap_or(&p.text);
+                properties.insert(name, Property::parse(p.attr("type").unwrap_or(""), value)?);
+            }
+        }
+        Ok(properties)
+    }
+}
+
+fn read(path: &str) -> Result<Element> {
+    let file = File::open(path).map_err(|e| eyre!("Cannot open {}: {}", path, e))?;
+    let mut stack: Vec<Element> = vec![Element::default()];
+    for event in EventReader::new(BufReader::new(file)) {
+        match event.map_err(|e| eyre!("{}: {}", path, e))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => stack.push(Element {
+                name: name.local_name,
+                attributes: attributes
+                    .into_iter()
+                    .map(|a| (a.name.local_name, a.value))
+                    .collect(),
+                ..Default::default()
+            }),
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(element);
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                stack.last_mut().unwrap().text.push_str(&text);
+            }
+            _ => {}
+        }
+    }
+    stack
+        .pop()
+        .and_then(|document| document.children.into_iter().next())
+        .ok_or_else(|| eyre!("{} is empty", path))
+}
+
+fn tileset(element: &Element, path: &str) -> Result<Tileset> {
+    let first_gid = element.parse("firstgid", 0)?;
+    // External tilesets (.tsx) only keep their firstgid in the map.
+    if let Some(source) = element.attr("source") {
+        let source = relative(path, source);
+        let mut set = tileset(&read(&source)?, &source)?;
+        set.first_gid = first_gid;
+        return Ok(set);
+    }
+    let name = element.attr("name").unwrap_or_default().to_string();
+    let image = match element.child("image").and_then(|i| i.attr("source")) {
+        Some(image) => relative(path, image),
+        None => bail!(
+            "Tileset \"{}\" is an image collection, only single image tilesets are supported",
+            name
+        ),
+    };
+    let mut tiles = HashMap::new();
+    for tile in element.children.iter().filter(|c| c.name == "tile") {
+        tiles.insert(tile.parse("id", 0)?, tile.properties()?);
+    }
+    Ok(Tileset {
+        first_gid,
+        name,
+        tile_size: (
+            element.parse("tilewidth", 0)?,
+            element.parse("tileheight", 0)?,
+        ),
+        columns: element.parse("columns", 0)?,
+        tile_count: element.parse("tilecount", 0)?,
+        margin: element.parse("margin", 0)?,
+        spacing: element.parse("spacing", 0)?,
+        image: SpriteManager::intern(image),
+        tiles,
+    })
+}
+
+fn gids(layer: &Element, count: usize) -> Result<Vec<u32>> {
+    let data = layer.child("data").ok_or_else(|| eyre!("Missing <data>"))?;
+    if data.child("chunk").is_some() {
+        bail!("Infinite maps are not supported");
+    }
+    match data.attr("encoding") {
+        Some("csv") => data
+            .text
+            .split(',')
+            .map(|gid| Ok(gid.trim().parse()?))
+            .collect(),
+        Some("base64") => decode_gids(&data.text, data.attr("compression"), count),
+        // Plain XML, one <tile> per cell.
+        None => data
+            .children
+            .iter()
+            .filter(|c| c.name == "tile")
+            .map(|tile| tile.parse("gid", 0))
+            .collect(),
+        Some(other) => bail!("Unsupported layer encoding \"{}\"", other),
+    }
+}
+
+// Children of a group are only visible when the group is.
+fn layers(
+    parent: &Element,
+    (ox, oy): (f32, f32),
+    parent_visible: bool,
+    inherited: &Properties,
+    out: &mut Vec<MapLayer>,
+) -> Result<()> {
+    for layer in &parent.children {
+        let mut props = inherited.clone();
+        props.extend(layer.properties()?);
+        let name = layer.attr("name").unwrap_or_default().to_string();
+        let offset = (
+            ox + layer.parse::<f32>("offsetx", 0.0)?,
+            oy + layer.parse::<f32>("offsety", 0.0)?,
+        );
+        let visible = parent_visible && layer.attr("visible") != Some("0");
+        match layer.name.as_str() {
+            "layer" => {
+                let (width, height): (usize, usize) =
+                    (layer.parse("width", 0)?, layer.parse("height", 0)?);
+                let gids =
+                    gids(layer, width * height).map_err(|e| eyre!("Layer \"{}\": {}", name, e))?;
+                if gids.len() != width * height {
+                    bail!(
+                        "Layer \"{}\" has {} tiles, expected {}",
+                        name,
+                        gids.len(),
+                        width * height
+                    );
+                }
+                out.push(MapLayer::Tiles {
+                    name,
+                    width,
+                    height,
+                    offset,
+                    visible,
+                    gids,
+                    properties: props,
+                });
+            }
+            "objectgroup" => {
+                let mut objects = vec![];
+                for o in layer.children.iter().filter(|c| c.name == "object") {
+                    let kind = o.attr("type").or_else(|| o.attr("class"));
+                    objects.push(Object {
+                        id: o.parse("id", 0)?,
+                        name: o.attr("name").unwrap_or_default().to_string(),
+                        kind: kind.unwrap_or_default().to_string(),
+                        x: o.parse("x", 0.0)?,
+                        y: o.parse("y", 0.0)?,
+                        width: o.parse("width", 0.0)?,
+                        height: o.parse("height", 0.0)?,
+                        gid: o.attr("gid").map(|_| o.parse("gid", 0)).transpose()?,
+                        properties: o.properties()?,
+                    });
+                }
+                out.push(MapLayer::Objects {
+                    name,
+                    offset,
+                    objects,
+                    properties: props,
+                });
+            }
+            "group" => layers(layer, offset, visible, &props, out)?,
+            // Image layers are not supported.
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn open(path: &str) -> Result<TiledMap> {
+    let map = read(path)?;
+    if map.name != "map" {
+        bail!("Expected a <map> element, found <{}>", map.name);
+    }
+    if map.attr("infinite") == Some("1") {
+        bail!("Infinite maps are not supported");
+    }
+    match map.attr("orientation") {
+        None | Some("orthogonal") => {}
+        Some(other) => bail!("Only orthogonal maps are supported, found {}", other),
+    }
+    let tilesets = map
+        .children
+        .iter()
+        .filter(|c| c.name == "tileset")
+        .map(|set| tileset(set, path))
+        .collect::<Result<Vec<_>>>()?;
+    let mut out = vec![];
+    layers(&map, (0.0, 0.0), true, &HashMap::new(), &mut out)?;
+    Ok(TiledMap {
+        width: map.parse("width", 0)?,
+        height: map.parse("height", 0)?,
+        tile_size: (map.parse("tilewidth", 0)?, map.parse("tileheight", 0)?),
+        tilesets,
+        layers: out,
+        properties: map.properties()?,
+    })
+}
@@ -0,0 +1,196 @@
+use sdl2::rect::Rect;
+use specs::{Component, DenseVecStorage};
+
+// Width and height of a chunk, in tiles.
+pub const CHUNK_SIZE: usize = 16;
+
+pub type TileId = u32;
+
+/**
+ * A grid of tiles drawn from the frames of the `tileset` sprite,
+ * placed with its top left corner at the entity's Position.
+ * The renderer bakes each CHUNK_SIZE x CHUNK_SIZE chunk into a static buffer
+ * and only rebuilds it when `set` changes one of its tiles.
+ * A Tilemap with an empty tileset is not drawn, it only collides.
+ */
+#[derive(Component, Debug, Clone)]
+pub struct Tilemap {
+    pub tileset: &'static str,
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: (u32, u32),
+    tiles: Vec<Option<TileId>>,
+    solid: Vec<bool>,
+    // Bumped whenever a tile of the chunk changes.
+    versions: Vec<u32>,
+}
+
+impl Tilemap {
+    pub fn new(
+        tileset: &'static str,
+        (width, height): (usize, usize),
+        tile_size: (u32, u32),
+    ) -> Self {
+        let chunks = Self::chunk_count(width) * Self::chunk_count(height);
+        Self {
+            tileset,
+            width,
+            height,
+            tile_size,
+            tiles: vec![None; width * height],
+            solid: vec![false; width * height],
+            versions: vec![0; chunks],
+        }
+    }
+
+    fn chunk_count(tiles: usize) -> usize {
+        tiles.div_ceil(CHUNK_SIZE)
+    }
+
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some(y * self.width + x)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<TileId> {
+        self.index(x, y).and_then(|i| self.tiles[i])
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, tile: Option<TileId>) {
+        if let Some(i) = self.index(x, y) {
+            if self.tiles[i] != tile {
+                self.tiles[i] = tile;
+                let chunk = self.chunk_of(x, y);
+                self.versions[chunk] = self.versions[chunk].wrapping_add(1);
+            }
+        }
+    }
+
+    pub fn is_solid(&self, x: usize, y: usize) -> bool {
+        self.index(x, y).is_some_and(|i| self.solid[i])
+    }
+
+    // Solid cells are what physics collides with, independent of what is drawn.
+    pub fn set_solid(&mut self, x: usize, y: usize, solid: bool) {
+        if let Some(i) = self.index(x, y) {
+            self.solid[i] = solid;
+        }
+    }
+
+    pub fn chunks_x(&self) -> usize {
+        Self::chunk_count(self.width)
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.versions.len()
+    }
+
+    fn chunk_of(&self, x: usize, y: usize) -> usize {
+        (y / CHUNK_SIZE) * self.chunks_x() + x / CHUNK_SIZE
+    }
+
+    pub fn chunk_version(&self, chunk: usize) -> u32 {
+        self.versions[chunk]
+    }
+
+    // Area covered by `chunk`, relative to the map's origin.
+    pub fn chunk_rect(&self, chunk: usize) -> Rect {
+        let (tw, th) = self.tile_size;
+        let (cx, cy) = (chunk % self.chunks_x(), chunk / self.chunks_x());
+        Rect::new(
+            (cx * CHUNK_SIZE) as i32 * tw as i32,
+            (cy * CHUNK_SIZE) as i32 * th as i32,
+            CHUNK_SIZE as u32 * tw,
+            CHUNK_SIZE as u32 * th,
+        )
+    }
+
+    // Non empty tiles of `chunk` as (x, y, tile), in tiles.
+    pub fn chunk_tiles(&self, chunk: usize) -> impl Iterator<Item = (usize, usize, TileId)> + '_ {
+        let (cx, cy) = (chunk % self.chunks_x(), chunk / self.chunks_x());
+        let xs = cx * CHUNK_SIZE..((cx + 1) * CHUNK_SIZE).min(self.width);
+        let ys = cy * CHUNK_SIZE..((cy + 1) * CHUNK_SIZE).min(self.height);
+        ys.flat_map(move |y| xs.clone().map(move |x| (x, y)))
+            .filter_map(move |(x, y)| self.get(x, y).map(|tile| (x, y, tile)))
+    }
+
+    /**
+     * Rects of the solid cells overlapping `rect`.
+     * Both are in world coordinates, the map's top left corner being at `origin`.
+     */
+    pub fn solid_cells(&self, origin: (i32, i32), rect: Rect) -> Vec<Rect> {
+        let (tw, th) = (self.tile_size.0 as i32, self.tile_size.1 as i32);
+        let cell = |v: i32, o: i32, size: i32| ((v - o) as f32 / size as f32).floor() as i32;
+        let x0 = cell(rect.left(), origin.0, tw).max(0);
+        let y0 = cell(rect.top(), origin.1, th).max(0);
+        let x1 = cell(rect.right() - 1, origin.0, tw).min(self.width as i32 - 1);
+        let y1 = cell(rect.bottom() - 1, origin.1, th).min(self.height as i32 - 1);
+        let mut cells = vec![];
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                if self.is_solid(x as usize, y as usize) {
+                    cells.push(Rect::new(
+                        origin.0 + x * tw,
+                        origin.1 + y * th,
+                        tw as u32,
+                        th as u32,
+                    ));
+                }
+            }
+        }
+        cells
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_chunks_get_a_new_version() {
+        let mut map = Tilemap::new("tile", (CHUNK_SIZE + 1, 2), (16, 16));
+        assert_eq!((map.chunks_x(), map.chunks()), (2, 2));
+        map.set(CHUNK_SIZE, 1, Some(3));
+        assert_eq!((map.chunk_version(0), map.chunk_version(1)), (0, 1));
+        // Setting the same tile again or out of the map changes nothing.
+        map.set(CHUNK_SIZE, 1, Some(3));
+        map.set(CHUNK_SIZE + 1, 0, Some(3));
+        assert_eq!((map.chunk_version(0), map.chunk_version(1)), (0, 1));
+        // Solidity is not drawn.
+        map.set_solid(0, 0, true);
+        assert_eq!(map.chunk_version(0), 0);
+
+        assert_eq!(map.chunk_rect(1), Rect::new(256, 0, 256, 256));
+        let tiles: Vec<_> = map.chunk_tiles(1).collect();
+        assert_eq!(tiles, [(CHUNK_SIZE, 1, 3)]);
+    }
+
+    #[test]
+    fn solid_cells_overlap_the_rect() {
+        let mut map = Tilemap::new("tile", (4, 3), (16, 16));
+        for x in 0..4 {
+            map.set_solid(x, 2, true);
+        }
+        map.set_solid(3, 1, true);
+        // The map starts at (100, 50), the rect covers cells 1 to 3 of the last two rows.
+        let cells = map.solid_cells((100, 50), Rect::new(120, 70, 40, 20));
+        assert_eq!(
+            cells,
+            [
+                Rect::new(148, 66, 16, 16),
+                Rect::new(116, 82, 16, 16),
+                Rect::new(132, 82, 16, 16),
+                Rect::new(148, 82, 16, 16),
+            ]
+        );
+        // Partly outside the map, only the cells inside count.
+        let cells = map.solid_cells((100, 50), Rect::new(0, 90, 110, 100));
+        assert_eq!(cells, [Rect::new(100, 82, 16, 16)]);
+        assert!(map
+            .solid_cells((100, 50), Rect::new(0, 0, 50, 50))
+            .is_empty());
+    }
+}
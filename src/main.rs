@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::time::Duration;
 use std::time::Instant;
-use systems::components::CollisionType::Static;
 use systems::components::Size;
 use winit::window::WindowBuilder;
 
@@ -11,16 +10,28 @@ use game::Game;
 
 use lib::logging::DisplayError;
 use lib::*;
+use systems::camera::{Camera, CameraSystem};
 use systems::components::InputHandler;
 use systems::components::Position;
 use systems::components::Velocity;
+use systems::debug::DebugDraw;
 use systems::input::InputSystem;
+use systems::lighting::{AmbientLight, PointLight};
+use systems::particles::{ParticleEmitter, ParticleSystem};
 use systems::physics::Physics;
+use systems::postprocess::{Pass, PostProcess};
 use systems::renderer::Renderer;
+use systems::screen::{ScaleMode, Screen};
 
+use aseprite::AsepriteJSON;
+use font::bitmap::{BitmapFont, BitmapFonts};
+use font::{Align, FontManager, Text};
+use ldtk::LdtkProject;
 use opengl::DisplayBuild;
-use sprite::{SpriteConfig, SpriteManager};
+use sprite::{OnFinish, SpriteConfig, SpriteManager};
 use systems::components::Collision;
+use systems::components::{Hitbox, Hurtbox, Layer, RenderLayer};
+use tiled::TiledMap;
 
 mod game;
 mod lib;
@@ -33,8 +44,16 @@ extern crate sdl2;
 extern crate prettytable;
 extern crate nalgebra_glm as glm;
 
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 
+// Map objects of the chicken kind, in Tiled or LDtk, join the flock.
+fn chicken<'a, T>(_: &T, sprites: &mut SpriteManager, e: EntityBuilder<'a>) -> EntityBuilder<'a> {
+    e.with(sprites.init("chicken"))
+        .with(Velocity(0, 1))
+        .with(Collision(None))
+}
+
 pub fn main() -> Result<()> {
     color_eyre::install()?;
     // winit_main()
@@ -59,7 +78,7 @@ pub fn sdl_main() -> Result<()> {
     let sdl_context = sdl2::init().map_err(DisplayError::from)?;
     let video_subsystem = sdl_context.video().map_err(DisplayError::from)?;
 
-    let _ttf_context = sdl2::ttf::init()?;
+    let ttf_context = sdl2::ttf::init()?;
 
     let gl_attr = video_subsystem.gl_attr();
 
@@ -68,10 +87,9 @@ pub fn sdl_main() -> Result<()> {
     // gl_attr.set_context_flags().forward_compatible().set();
     gl_attr.set_context_version(3, 3);
 
-    let window = video_subsystem
-        .window("rust-sdl2 demo: Video", 800, 600)
-        .build_glium()
-        .unwrap();
+    let mut window = video_subsystem.window("rust-sdl2 demo: Video", 800, 600);
+    window.resizable().allow_highdpi();
+    let window = window.build_glium().unwrap();
 
     // let raw = egui::RawInput {
     //     screen_rect: Some(Rect::from_two_pos(
@@ -111,12 +129,22 @@ pub fn sdl_main() -> Result<()> {
     let mut physics: Physics = Default::default();
     RunNow::setup(&mut physics, &mut world);
 
+    let mut particles: ParticleSystem = Default::default();
+    RunNow::setup(&mut particles, &mut world);
+
+    let mut camera: CameraSystem = Default::default();
+    RunNow::setup(&mut camera, &mut world);
+
     let mut renderer = Renderer {
         sprite_manager: &mut sprite_manager,
         window,
         render_set: None,
-        now: start_system_time,
+        ticks: 0,
+        offscreen: None,
+        recorder: Default::default(),
+        fonts: FontManager::new(&ttf_context),
     };
+    renderer.fonts.add("joystix", "joystix monospace.ttf");
     RunNow::setup(&mut renderer, &mut world);
     // renderer.prep();
 
@@ -124,7 +152,7 @@ pub fn sdl_main() -> Result<()> {
     world.insert(egui);
     // world.insert(raw);
 
-    world
+    let player = world
         .create_entity()
         .with(Velocity(0, 0))
         .with(Position(50, 50))
@@ -132,29 +160,138 @@ pub fn sdl_main() -> Result<()> {
         .with(renderer.sprite_manager.init("chicken"))
         .with(InputHandler(None))
         .with(Collision(None))
+        .with(Hitbox(None))
+        .with(Hurtbox(None))
+        .with(RenderLayer(Layer::Actors))
+        .with(ParticleEmitter::dust("particle", (18.0 * 3.0, 18.0 * 3.0)))
         .build();
+    // The resolution the game is drawn at before being scaled into the window.
+    let screen = Screen {
+        resolution: (800, 600),
+        mode: ScaleMode::Integer,
+        ..Default::default()
+    };
+    world.insert(Camera {
+        target: Some(player),
+        smoothing: 0.2,
+        dead_zone: (48.0, 32.0),
+        ..Default::default()
+    });
+    world.insert(screen);
+    world.insert(PostProcess {
+        passes: vec![
+            Pass::bloom(),
+            Pass::color_lut("sprites/lut_warm.png"),
+            Pass::crt(),
+            Pass::vignette(),
+        ],
+    });
 
     for x in 1..10 {
         for y in 1..10 {
+            // Each chicken pecks once, out of step with its neighbours, then goes back to idling.
+            let chicken = renderer.sprite_manager.init("chicken");
+            let speed = 0.75 + ((x + y) % 4) as f64 / 8.0;
+            renderer.sprite_manager.set_speed(&chicken, speed)?;
+            renderer
+                .sprite_manager
+                .play_once(&chicken, "peck", OnFinish::Return)?;
             world
                 .create_entity()
                 .with(Velocity(0, 1))
                 .with(Position(x * 18, y * 18))
                 .with(Size(18, 18))
                 .with(Collision(None))
-                .with(renderer.sprite_manager.init("chicken"))
+                .with(chicken)
+                .with(RenderLayer(Layer::Actors))
                 .build();
         }
     }
 
-    for x in 0..20 {
+    // Pixel fonts, a BMFont file and a grid sheet of outlined capitals.
+    let mut bitmap_fonts = BitmapFonts::default();
+    bitmap_fonts.add(BitmapFont::open("pixel", "sprites/pixel_font.fnt")?);
+    bitmap_fonts.add(BitmapFont::from_sheet(
+        "title",
+        "sprites/title_font.png",
+        AsepriteJSON::grid("title", (5, 7), 16, 46, 0, 0),
+        " ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.,!?-:'/+",
+    ));
+    world.insert(bitmap_fonts);
+
+    world
+        .create_entity()
+        .with(Position(8, 8))
+        .with(Text::new("BLOBNTREE", "title", 21))
+        .with(RenderLayer(Layer::UI))
+        .build();
+    world
+        .create_entity()
+        .with(Position(792, 8))
+        .with(Text {
+            align: Align::Right,
+            ..Text::new("F3 hitboxes\nF5-F8 effects\nF11 scaling", "pixel", 10)
+        })
+        .with(RenderLayer(Layer::UI))
+        .build();
+    world
+        .create_entity()
+        .with(Position(400, 560))
+        .with(Text {
+            align: Align::Center,
+            wrap: Some(360),
+            ..Text::new(
+                "Use the arrow keys to run and jump with the flock",
+                "joystix",
+                12,
+            )
+        })
+        .with(RenderLayer(Layer::UI))
+        .build();
+
+    // The level to play, a Tiled map or an LDtk project, ie. `cargo run -- maps/rooms.ldtk`.
+    // Chickens of either join the flock.
+    let path = std::env::args().nth(1);
+    let path = path.as_deref().unwrap_or("maps/field.tmx");
+    let project = match path.ends_with(".ldtk") {
+        true => Some(LdtkProject::open(path)?),
+        false => None,
+    };
+    let mut ldtk_templates = ldtk::Templates::new();
+    ldtk_templates.insert("Chicken", chicken);
+    // The LDtk level the player is in, and what was spawned for it.
+    let mut room = None;
+    if let Some(project) = &project {
+        for texture in project.textures() {
+            renderer.load(&texture);
+        }
+        let level = project
+            .level_at((50, 50))
+            .ok_or_else(|| eyre!("{} has no level at the player's start", path))?;
+        let spawned =
+            project.spawn_level(&mut world, renderer.sprite_manager, &ldtk_templates, level);
+        world.write_resource::<Camera>().bounds = Some(level.bounds());
+        room = Some((level, spawned));
+        // The rooms are at night, the player carries a lantern.
         world
             .create_entity()
-            .with(Position(x * 32, 400))
-            .with(Size(32, 32))
-            .with(renderer.sprite_manager.init("tile"))
-            .with(Collision(Some(Static)))
+            .with(AmbientLight([0.25, 0.25, 0.4]))
             .build();
+        let lantern = PointLight {
+            offset: (27.0, 27.0),
+            ..PointLight::new([1.0, 0.8, 0.5], 160.0)
+        };
+        world
+            .write_storage::<PointLight>()
+            .insert(player, lantern)?;
+    } else {
+        let mut templates = tiled::Templates::new();
+        templates.insert("chicken", chicken);
+        let map = TiledMap::open(path)?;
+        for texture in map.textures() {
+            renderer.load(&texture);
+        }
+        map.spawn(&mut world, renderer.sprite_manager, &templates);
     }
     // render a surface, and convert it to a texture bound to the canvas
     let mut now = Instant::now();
@@ -167,19 +304,40 @@ pub fn sdl_main() -> Result<()> {
 
         //https://gafferongames.com/post/fix_your_timestep/
         //https://dewitters.com/dewitters-gameloop/
-        const TICKS_PER_SECOND: u64 = 30;
         const MAX_FRAMESKIP: u64 = 5;
-        let skip_ticks: Duration = Duration::from_millis(1000 / TICKS_PER_SECOND);
+        let skip_ticks: Duration = world.read_resource::<Game>().tick_length();
         let mut loops = 0;
         while Instant::now() > next_tick && loops < MAX_FRAMESKIP {
+            // Debug shapes are pushed during a tick and drawn until the next one.
+            world.write_resource::<DebugDraw>().clear();
+            renderer.animate(&world);
             physics.run_now(&world);
+            particles.run_now(&world);
+            camera.run_now(&world);
             //tick counter
-            next_tick += skip_ticks;
-            loops += 1;
             let mut game = world.write_resource::<Game>();
             game.ticks += 1;
+            game.time = next_tick;
+            next_tick += skip_ticks;
+            loops += 1;
+        }
+        // Walking out of the LDtk level swaps it for the one the player went into.
+        if let (Some(project), Some((level, spawned))) = (&project, &mut room) {
+            let position = world
+                .read_storage::<Position>()
+                .get(player)
+                .map(|p| (p.0, p.1));
+            if let Some(next) = position.and_then(|p| project.leaving(level, p)) {
+                world.delete_entities(spawned)?;
+                *spawned =
+                    project.spawn_level(&mut world, renderer.sprite_manager, &ldtk_templates, next);
+                world.write_resource::<Camera>().bounds = Some(next.bounds());
+                *level = next;
+            }
         }
         renderer.run_now(&world);
+        // Apply entity deletions, the renderer releases their sprites next frame.
+        world.maintain();
 
         let mut game = world.write_resource::<Game>();
         game.render_ticks += 1;